@@ -0,0 +1,44 @@
+/*
+ * Lists the attached probes and switches the first one to CMSIS-DAP 2.0.
+ *
+ *     cargo rustc --lib --release --features ffi --crate-type cdylib
+ *     cc -o example ffi/example.c -Iffi -Ltarget/release -lturbo_110
+ *     LD_LIBRARY_PATH=target/release ./example
+ *
+ * This talks to real hardware, so a probe must be attached. ffi/test.c
+ * runs the same calls against scripted probes instead.
+ */
+
+#include <stdio.h>
+
+#include "turbo110.h"
+
+#define MODE_CMSIS_DAP2 4
+
+int main(void) {
+    Turbo110Probe probes[8];
+    size_t count = 0;
+    Turbo110Status status = turbo110_list(probes, 8, &count);
+    if (status != TURBO110_STATUS_OK) {
+        fprintf(stderr, "turbo110_list failed (%d): %s\n", status, turbo110_last_error());
+        return 1;
+    }
+    if (count == 0) {
+        fprintf(stderr, "no probe attached\n");
+        return 1;
+    }
+    for (size_t i = 0; i < count && i < 8; i++) {
+        printf("%04x:%04x bus %u address %u serial \"%s\"%s\n", probes[i].vid, probes[i].pid,
+               probes[i].bus, probes[i].address, probes[i].serial, probes[i].dfu ? " (DFU)" : "");
+    }
+
+    char error[256];
+    const char *serial = probes[0].serial[0] ? probes[0].serial : NULL;
+    status = turbo110_switch_mode(serial, MODE_CMSIS_DAP2, error, sizeof(error));
+    if (status != TURBO110_STATUS_OK) {
+        fprintf(stderr, "turbo110_switch_mode failed (%d): %s\n", status, error);
+        return 1;
+    }
+    printf("switched to mode %d\n", MODE_CMSIS_DAP2);
+    return 0;
+}
@@ -0,0 +1,64 @@
+/*
+ * Runs the happy path of the C API against scripted probes in place of USB:
+ * lists them, switches one to CMSIS-DAP 2.0 and checks its configuration
+ * was rewritten, and checks the failures a probe with old firmware and a
+ * missing probe are reported with. Needs the library built with the
+ * ffi-mock feature, which the makefile does:
+ *
+ *     make -C ffi test
+ */
+
+#include <stdio.h>
+#include <string.h>
+
+#define TURBO110_MOCK
+#include "turbo110.h"
+
+#define MODE_CMSIS_DAP2 4
+#define CMSIS_DAP_2_MINIMUM 0x03000008
+
+static int failures = 0;
+
+#define CHECK(condition) \
+    do { \
+        if (!(condition)) { \
+            fprintf(stderr, "%s:%d: %s\n", __FILE__, __LINE__, #condition); \
+            failures++; \
+        } \
+    } while (0)
+
+int main(void) {
+    CHECK(turbo110_mock_plug_in("MOCK0001", CMSIS_DAP_2_MINIMUM) == TURBO110_STATUS_OK);
+    CHECK(turbo110_mock_plug_in("MOCK0002", CMSIS_DAP_2_MINIMUM - 1) == TURBO110_STATUS_OK);
+
+    Turbo110Probe probes[8];
+    size_t count = 0;
+    CHECK(turbo110_list(probes, 8, &count) == TURBO110_STATUS_OK);
+    CHECK(count == 2);
+    if (count == 2) {
+        CHECK(strcmp(probes[0].serial, "MOCK0001") == 0);
+        CHECK(strcmp(probes[1].serial, "MOCK0002") == 0);
+        CHECK(!probes[0].dfu && !probes[1].dfu);
+    }
+
+    char error[256] = "";
+    CHECK(turbo110_switch_mode("MOCK0001", MODE_CMSIS_DAP2, error, sizeof(error)) ==
+          TURBO110_STATUS_OK);
+    CHECK(turbo110_mock_mode("MOCK0001") == MODE_CMSIS_DAP2);
+
+    CHECK(turbo110_switch_mode("MOCK0002", MODE_CMSIS_DAP2, error, sizeof(error)) ==
+          TURBO110_STATUS_FIRMWARE_TOO_OLD);
+    CHECK(error[0] != '\0');
+    CHECK(turbo110_last_error() != NULL && strcmp(turbo110_last_error(), error) == 0);
+    CHECK(turbo110_mock_mode("MOCK0002") == 0);
+
+    CHECK(turbo110_switch_mode("MOCK0003", MODE_CMSIS_DAP2, NULL, 0) ==
+          TURBO110_STATUS_NOT_FOUND);
+
+    if (failures) {
+        fprintf(stderr, "%d checks failed\n", failures);
+        return 1;
+    }
+    printf("ffi test passed\n");
+    return 0;
+}
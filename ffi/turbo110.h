@@ -0,0 +1,124 @@
+#ifndef TURBO110_H
+#define TURBO110_H
+
+/* Generated by cbindgen from src/ffi.rs. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result codes. These values are part of the ABI and never change.
+ */
+typedef enum Turbo110Status {
+  TURBO110_STATUS_OK = 0,
+  /**
+   * No matching probe is attached
+   */
+  TURBO110_STATUS_NOT_FOUND = 1,
+  /**
+   * A pointer was NULL or a string was not valid UTF-8
+   */
+  TURBO110_STATUS_INVALID_ARGUMENT = 2,
+  /**
+   * Opening or talking to the probe failed
+   */
+  TURBO110_STATUS_USB = 3,
+  /**
+   * A control transfer to the bootloader failed
+   */
+  TURBO110_STATUS_TRANSFER = 4,
+  /**
+   * The firmware is too old for the requested mode
+   */
+  TURBO110_STATUS_FIRMWARE_TOO_OLD = 5,
+  /**
+   * The probe stopped responding or did not re-enumerate
+   */
+  TURBO110_STATUS_TIMEOUT = 6,
+  /**
+   * The probe sent back something unexpected
+   */
+  TURBO110_STATUS_PROTOCOL = 7,
+  TURBO110_STATUS_OTHER = 255,
+} Turbo110Status;
+
+/**
+ * An attached probe, as reported by [`turbo110_list`]
+ */
+typedef struct Turbo110Probe {
+  uint16_t vid;
+  uint16_t pid;
+  uint8_t bus;
+  uint8_t address;
+  /**
+   * Nonzero if the probe is in the DFU bootloader
+   */
+  uint8_t dfu;
+  /**
+   * NUL-terminated, empty if the probe has no serial number. Serial
+   * numbers longer than 63 bytes are truncated.
+   */
+  char serial[64];
+} Turbo110Probe;
+
+/**
+ * List attached probes. Up to `capacity` entries are written to `out`, and
+ * the number of probes found is stored in `out_count`, which may exceed
+ * `capacity`.
+ *
+ * # Safety
+ * `out` must point to `capacity` writable entries, or may be NULL if
+ * `capacity` is 0. `out_count` must be a valid pointer.
+ */
+enum Turbo110Status turbo110_list(struct Turbo110Probe *out, size_t capacity, size_t *out_count);
+
+/**
+ * Switch the probe with serial number `serial` to configuration mode
+ * `mode`, returning once it has re-enumerated. If `serial` is NULL, the
+ * only attached probe is used. On failure, the error message is also
+ * copied into `out_error_buf`, which may be NULL.
+ *
+ * # Safety
+ * `serial` must be NULL or a NUL-terminated string. `out_error_buf` must
+ * be NULL or point to `out_error_len` writable bytes.
+ */
+enum Turbo110Status turbo110_switch_mode(const char *serial,
+                                         uint16_t mode,
+                                         char *out_error_buf,
+                                         size_t out_error_len);
+
+/**
+ * The message for the last failure on this thread, or NULL if nothing has
+ * failed yet. The string stays valid until the next failing call on the
+ * same thread.
+ */
+const char *turbo110_last_error(void);
+
+#if defined(TURBO110_MOCK)
+/**
+ * Plug a scripted probe with serial number `serial` in, running firmware
+ * `firmware_version`, where this thread looks for probes from now on. USB
+ * is then no longer looked at. Only in builds with the `ffi-mock` feature.
+ *
+ * # Safety
+ * `serial` must be a NUL-terminated string.
+ */
+enum Turbo110Status turbo110_mock_plug_in(const char *serial, uint32_t firmware_version);
+#endif
+
+#if defined(TURBO110_MOCK)
+/**
+ * The mode the configuration of the scripted probe with serial number
+ * `serial` is set to, or -1 if no such probe was plugged in. Only in builds
+ * with the `ffi-mock` feature.
+ *
+ * # Safety
+ * `serial` must be a NUL-terminated string.
+ */
+int32_t turbo110_mock_mode(const char *serial);
+#endif
+
+#endif  /* TURBO110_H */
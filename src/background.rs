@@ -0,0 +1,147 @@
+//! Running long bootloader operations on a thread of their own.
+
+use std::sync::{Arc, mpsc};
+use std::thread::JoinHandle;
+
+use crate::{
+    CancelToken, Observer, Phase, Warning, Xds110Configuration, Xds110DfuDevice, Xds110Error,
+};
+
+/// What an [`OperationHandle`] reports while its operation runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OperationEvent {
+    Phase(Phase),
+    Warning(Warning),
+}
+
+/// Forwards to the channel of a handle, and to whatever observer the device
+/// had before
+struct ChannelObserver {
+    sender: mpsc::Sender<OperationEvent>,
+    inner: Option<Arc<dyn Observer>>,
+}
+
+impl Observer for ChannelObserver {
+    fn phase(&self, phase: Phase) {
+        // Nobody may be listening any more, which is fine
+        let _ = self.sender.send(OperationEvent::Phase(phase));
+        if let Some(inner) = &self.inner {
+            inner.phase(phase);
+        }
+    }
+
+    fn warning(&self, warning: &Warning) {
+        let _ = self.sender.send(OperationEvent::Warning(warning.clone()));
+        if let Some(inner) = &self.inner {
+            inner.warning(warning);
+        }
+    }
+}
+
+/// An operation running on a thread of its own, see
+/// [`Xds110DfuDevice::write_configuration_background`]
+///
+/// Dropping the handle without joining cancels the operation and blocks until
+/// the bootloader has been brought back to idle.
+pub struct OperationHandle<T> {
+    events: mpsc::Receiver<OperationEvent>,
+    cancel: CancelToken,
+    thread: Option<JoinHandle<Result<T, Xds110Error>>>,
+}
+
+impl<T> OperationHandle<T> {
+    /// Progress and warnings, in the order they happened. The channel closes
+    /// once the operation is over.
+    pub fn progress(&self) -> &mpsc::Receiver<OperationEvent> {
+        &self.events
+    }
+
+    /// Ask the operation to stop at the next transfer. It then fails with
+    /// [`Xds110Error::Cancelled`].
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    /// Wait for the operation to end. On failure the device is dropped with
+    /// the probe still in the bootloader; reopen it with
+    /// [`open_dfu`](crate::open_dfu).
+    pub fn join(mut self) -> Result<T, Xds110Error> {
+        let thread = self.thread.take().expect("joined twice");
+        match thread.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+impl<T> Drop for OperationHandle<T> {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.cancel.cancel();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Xds110DfuDevice {
+    /// Move the device to a new thread and run `f` on it there. The device
+    /// keeps reporting to its own observer as well as to the handle, but its
+    /// cancel token is replaced by the handle's.
+    fn spawn<T: Send + 'static>(
+        mut self,
+        f: impl FnOnce(Self) -> Result<T, Xds110Error> + Send + 'static,
+    ) -> OperationHandle<T> {
+        let (sender, events) = mpsc::channel();
+        let cancel = CancelToken::new();
+        self.observer = Some(Arc::new(ChannelObserver {
+            sender,
+            inner: self.observer.take(),
+        }));
+        self.cancel = Some(cancel.clone());
+        OperationHandle {
+            events,
+            cancel,
+            thread: Some(std::thread::spawn(move || f(self))),
+        }
+    }
+
+    /// [`write_configuration`](Self::write_configuration) on a thread of its
+    /// own. Joining the handle gives the device back.
+    pub fn write_configuration_background(
+        self,
+        configuration: Xds110Configuration,
+    ) -> OperationHandle<Xds110DfuDevice> {
+        self.spawn(move |mut dfu| {
+            dfu.write_configuration(&configuration)?;
+            Ok(dfu)
+        })
+    }
+
+    /// [`flash_firmware`](Self::flash_firmware) on a thread of its own.
+    /// Joining the handle gives the device back.
+    pub fn flash_firmware_background(
+        self,
+        image: Vec<u8>,
+        expected_sha256: Option<[u8; 32]>,
+    ) -> OperationHandle<Xds110DfuDevice> {
+        self.spawn(move |mut dfu| {
+            dfu.flash_firmware(&image, expected_sha256)?;
+            Ok(dfu)
+        })
+    }
+
+    /// [`upload_firmware`](Self::upload_firmware) into memory on a thread of
+    /// its own. Joining the handle gives the device back along with the
+    /// image.
+    pub fn upload_firmware_background(self) -> OperationHandle<(Xds110DfuDevice, Vec<u8>)> {
+        self.spawn(|mut dfu| {
+            let mut image = Vec::new();
+            dfu.upload_firmware(&mut image)?;
+            Ok((dfu, image))
+        })
+    }
+}
@@ -0,0 +1,46 @@
+//! Where probes are looked for.
+//!
+//! A probe that switches state drops off the bus and comes back, so waiting
+//! for it means listing the attached probes and opening the one that is the
+//! same probe again. That is all [`Bus`] asks for. [`UsbBus`] is the real
+//! one; the tests put a scripted bus in its place.
+
+use std::io;
+
+use crate::{
+    ProbeInfo, Xds110DfuDevice, Xds110UsbDevice, list_probes, open_dfu_matching,
+    open_xds110_matching,
+};
+
+/// Lists the attached probes and opens them
+pub(crate) trait Bus: Send + Sync {
+    /// Every attached XDS110, in either state
+    fn list(&self) -> io::Result<Vec<ProbeInfo>>;
+
+    /// Open the bootloader that `matches`
+    fn open_dfu(&self, matches: &dyn Fn(&ProbeInfo) -> bool) -> io::Result<Xds110DfuDevice>;
+
+    /// Open the probe running its normal firmware that `matches`
+    fn open_xds110(&self, matches: &dyn Fn(&ProbeInfo) -> bool) -> io::Result<Xds110UsbDevice>;
+}
+
+/// The USB bus, through nusb
+pub(crate) struct UsbBus;
+
+impl Bus for UsbBus {
+    fn list(&self) -> io::Result<Vec<ProbeInfo>> {
+        list_probes()
+    }
+
+    fn open_dfu(&self, matches: &dyn Fn(&ProbeInfo) -> bool) -> io::Result<Xds110DfuDevice> {
+        open_dfu_matching(|device| {
+            ProbeInfo::from_device(device).is_some_and(|info| matches(&info))
+        })
+    }
+
+    fn open_xds110(&self, matches: &dyn Fn(&ProbeInfo) -> bool) -> io::Result<Xds110UsbDevice> {
+        open_xds110_matching(|device| {
+            ProbeInfo::from_device(device).is_some_and(|info| matches(&info))
+        })
+    }
+}
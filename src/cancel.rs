@@ -0,0 +1,26 @@
+//! Stopping long-running bootloader operations from another thread.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A shared flag checked between transfers. Clones refer to the same flag,
+/// so one can be handed to the device while another sits behind a cancel
+/// button.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the operation in progress to stop. This cannot be undone; use a
+    /// fresh token for the next operation.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
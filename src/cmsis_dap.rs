@@ -0,0 +1,305 @@
+//! Commands sent over the CMSIS-DAP 2.0 bulk interface.
+//!
+//! This interface only exists once the probe has been switched to mode 4. It
+//! is separate from the TI proprietary interface that the rest of
+//! [`Xds110UsbDevice`] talks to.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use nusb::transfer::{Direction, EndpointType};
+
+use crate::{Transport, Xds110Error, Xds110UsbDevice};
+
+const CMSIS_DAP_TIMEOUT: Duration = Duration::from_secs(1);
+
+const DAP_CONNECT: u8 = 0x02;
+const DAP_DISCONNECT: u8 = 0x03;
+const DAP_SWJ_CLOCK: u8 = 0x11;
+const DAP_SWJ_SEQUENCE: u8 = 0x12;
+
+pub(crate) const DAP_OK: u8 = 0x00;
+
+/// The debug port to select with `DAP_Connect`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmsisDapPort {
+    /// Let the probe pick its configured default port
+    Default = 0,
+    Swd = 1,
+    Jtag = 2,
+}
+
+impl TryFrom<u8> for CmsisDapPort {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(CmsisDapPort::Default),
+            1 => Ok(CmsisDapPort::Swd),
+            2 => Ok(CmsisDapPort::Jtag),
+            other => Err(other),
+        }
+    }
+}
+
+/// Finds and claims the CMSIS-DAP interface, or `None` if there is none
+type Claim = Box<dyn Fn() -> Result<Option<CmsisDapInterface>, std::io::Error> + Send + Sync>;
+
+/// The CMSIS-DAP interface of a probe, claimed the first time a command
+/// needs it. Opening the probe for anything else leaves the interface to
+/// other tools, such as OpenOCD or pyOCD.
+pub(crate) struct LazyCmsisDap {
+    claim: Claim,
+    /// Kept as the claim error if the interface exists but is held
+    /// elsewhere, so that only CMSIS-DAP commands fail
+    claimed: OnceLock<Result<Option<CmsisDapInterface>, std::io::Error>>,
+}
+
+impl LazyCmsisDap {
+    pub(crate) fn new(
+        claim: impl Fn() -> Result<Option<CmsisDapInterface>, std::io::Error> + Send + Sync + 'static,
+    ) -> Self {
+        LazyCmsisDap {
+            claim: Box::new(claim),
+            claimed: OnceLock::new(),
+        }
+    }
+
+    /// For a probe known to have no CMSIS-DAP interface
+    pub(crate) fn none() -> Self {
+        Self::new(|| Ok(None))
+    }
+
+    /// The interface of `device`, once it is needed
+    pub(crate) fn of(info: nusb::DeviceInfo, device: nusb::Device) -> Self {
+        Self::new(move || CmsisDapInterface::find(&info, &device))
+    }
+
+    fn get(&self) -> &Result<Option<CmsisDapInterface>, std::io::Error> {
+        self.claimed.get_or_init(&self.claim)
+    }
+}
+
+pub(crate) struct CmsisDapInterface {
+    handle: Box<dyn Transport>,
+    epin: u8,
+    epout: u8,
+}
+
+impl CmsisDapInterface {
+    /// Locate and claim the CMSIS-DAP 2.0 interface. The specification
+    /// requires its interface string to contain "CMSIS-DAP". `None` if the
+    /// probe has no such interface; an error if it has one that could not
+    /// be claimed, usually because another program holds it.
+    pub(crate) fn find(
+        info: &nusb::DeviceInfo,
+        device: &nusb::Device,
+    ) -> Result<Option<Self>, std::io::Error> {
+        let Some(iface) = info.interfaces().find(|x| {
+            x.class() == 0xff
+                && x.interface_string()
+                    .is_some_and(|name| name.contains("CMSIS-DAP"))
+        }) else {
+            return Ok(None);
+        };
+        let Some(config) = device.configurations().next() else {
+            return Ok(None);
+        };
+        let Some(interface) = config
+            .interfaces()
+            .find(|x| x.interface_number() == iface.interface_number())
+        else {
+            return Ok(None);
+        };
+
+        let mut epin = None;
+        let mut epout = None;
+        for alt_setting in interface.alt_settings() {
+            for endpoint in alt_setting.endpoints() {
+                if endpoint.transfer_type() != EndpointType::Bulk {
+                    continue;
+                }
+                match endpoint.direction() {
+                    Direction::In => epin = epin.or(Some(endpoint.address())),
+                    Direction::Out => epout = epout.or(Some(endpoint.address())),
+                }
+            }
+        }
+
+        let (Some(epin), Some(epout)) = (epin, epout) else {
+            return Ok(None);
+        };
+        let handle = device.claim_interface(iface.interface_number())?;
+        Ok(Some(CmsisDapInterface::new(Box::new(handle), epin, epout)))
+    }
+
+    /// Drive the interface through `handle`, whose bulk endpoints are
+    /// `epin` and `epout`
+    pub(crate) fn new(handle: Box<dyn Transport>, epin: u8, epout: u8) -> Self {
+        CmsisDapInterface {
+            handle,
+            epin,
+            epout,
+        }
+    }
+
+    /// Send a command and read back its response, checking that the response
+    /// echoes the command ID.
+    pub(crate) fn command(
+        &self,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, Xds110Error> {
+        self.handle
+            .write_bulk(self.epout, request, CMSIS_DAP_TIMEOUT)?;
+        let len = self
+            .handle
+            .read_bulk(self.epin, response, CMSIS_DAP_TIMEOUT)?;
+        if len < 1 || response[0] != request[0] {
+            return Err(Xds110Error::CmsisDapInvalidResponse {
+                command: request[0],
+            });
+        }
+        Ok(len)
+    }
+
+    /// Send a command whose response is just a status byte, and check that
+    /// the status is `DAP_OK`
+    pub(crate) fn status_command(&self, request: &[u8]) -> Result<(), Xds110Error> {
+        let mut response = [0u8; 2];
+        let len = self.command(request, &mut response)?;
+        if len < 2 {
+            return Err(Xds110Error::CmsisDapInvalidResponse {
+                command: request[0],
+            });
+        }
+        if response[1] != DAP_OK {
+            return Err(Xds110Error::CmsisDapCommandFailed {
+                command: request[0],
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Xds110UsbDevice {
+    /// The CMSIS-DAP interface, claimed now if it was not yet
+    pub(crate) fn cmsis_dap(&self) -> Result<&CmsisDapInterface, Xds110Error> {
+        match self.cmsis_dap.get() {
+            Ok(Some(dap)) => Ok(dap),
+            Ok(None) => Err(Xds110Error::CmsisDapUnavailable),
+            Err(e) => Err(Xds110Error::CmsisDapClaimFailed(std::io::Error::new(
+                e.kind(),
+                e.to_string(),
+            ))),
+        }
+    }
+
+    /// Issue `DAP_Connect` and return the port the probe actually selected.
+    pub fn cmsis_dap_connect(&self, port: CmsisDapPort) -> Result<CmsisDapPort, Xds110Error> {
+        let mut response = [0u8; 2];
+        let len = self
+            .cmsis_dap()?
+            .command(&[DAP_CONNECT, port as u8], &mut response)?;
+        if len < 2 {
+            return Err(Xds110Error::CmsisDapInvalidResponse {
+                command: DAP_CONNECT,
+            });
+        }
+        match CmsisDapPort::try_from(response[1]) {
+            // A port of 0 in the response means initialization failed
+            Ok(CmsisDapPort::Default) => Err(Xds110Error::CmsisDapCommandFailed {
+                command: DAP_CONNECT,
+            }),
+            Ok(selected) => Ok(selected),
+            Err(_) => Err(Xds110Error::CmsisDapInvalidResponse {
+                command: DAP_CONNECT,
+            }),
+        }
+    }
+
+    /// Issue `DAP_Disconnect`, releasing the debug port selected by
+    /// [`cmsis_dap_connect`](Self::cmsis_dap_connect).
+    pub fn cmsis_dap_disconnect(&self) -> Result<(), Xds110Error> {
+        self.cmsis_dap()?.status_command(&[DAP_DISCONNECT])
+    }
+
+    /// Issue `DAP_SWJ_Clock`, setting the SWD and JTAG clock to `hz`. Targets
+    /// that only cope with a slow clock while they start up need this before
+    /// [`cmsis_dap_connect`](Self::cmsis_dap_connect).
+    pub fn set_swj_clock(&self, hz: u32) -> Result<(), Xds110Error> {
+        let mut request = vec![DAP_SWJ_CLOCK];
+        request.extend(hz.to_le_bytes());
+        self.cmsis_dap()?.status_command(&request)
+    }
+
+    /// Issue `DAP_SWJ_Sequence`, clocking `bit_count` bits of `data` out on
+    /// SWDIO/TMS, least significant bit of the first byte first. A count of
+    /// 0 stands for 256 bits. `data` must hold exactly the bytes the count
+    /// needs.
+    pub fn swj_sequence(&self, bit_count: u8, data: &[u8]) -> Result<(), Xds110Error> {
+        let bits = match bit_count {
+            0 => 256,
+            bits => bits as usize,
+        };
+        if data.len() != bits.div_ceil(8) {
+            return Err(Xds110Error::SwjSequenceLength {
+                bits,
+                len: data.len(),
+            });
+        }
+        let mut request = vec![DAP_SWJ_SEQUENCE, bit_count];
+        request.extend(data);
+        self.cmsis_dap()?.status_command(&request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::CMSIS_DAP_2_MINIMUM;
+    use crate::mock::MockFirmware;
+
+    /// A claim that finds no interface, counting how often it was tried
+    fn counted(claims: &Arc<AtomicUsize>) -> LazyCmsisDap {
+        let claims = claims.clone();
+        LazyCmsisDap::new(move || {
+            claims.fetch_add(1, Ordering::SeqCst);
+            Ok(None)
+        })
+    }
+
+    #[test]
+    fn interface_is_claimed_on_first_use() {
+        let claims = Arc::new(AtomicUsize::new(0));
+        let mut probe = MockFirmware::xds110(CMSIS_DAP_2_MINIMUM, 0).open();
+        probe.cmsis_dap = counted(&claims);
+
+        // The TI interface alone does not need it
+        probe.supported_commands().unwrap();
+        assert_eq!(claims.load(Ordering::SeqCst), 0);
+
+        for _ in 0..2 {
+            assert!(matches!(
+                probe.cmsis_dap_disconnect(),
+                Err(Xds110Error::CmsisDapUnavailable)
+            ));
+        }
+        assert_eq!(claims.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn failed_claim_only_fails_cmsis_dap() {
+        let mut probe = MockFirmware::xds110(CMSIS_DAP_2_MINIMUM, 0).open();
+        probe.cmsis_dap = LazyCmsisDap::new(|| Err(std::io::ErrorKind::ResourceBusy.into()));
+
+        assert!(matches!(
+            probe.cmsis_dap_connect(CmsisDapPort::Swd),
+            Err(Xds110Error::CmsisDapClaimFailed(e)) if e.kind() == std::io::ErrorKind::ResourceBusy
+        ));
+        probe.supported_commands().unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+//! Vendor commands on the TI proprietary bulk interface.
+//!
+//! Requests are framed as `'*'`, a 16-bit little-endian length, the command
+//! byte and its parameters. Responses carry the same header followed by a
+//! 32-bit error code and any response data. The protocol has no capability
+//! query, so [`SupportedCommands`] is inferred from the firmware version and
+//! hardware ID reported by `XDS_VERSION`.
+
+use std::time::Duration;
+
+use crate::{
+    CMSIS_DAP_2_MINIMUM, FIRMWARE_VERSION_TIMEOUT, FirmwareVersion, Xds110Error, Xds110UsbDevice,
+};
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+
+const XDS_VERSION: u8 = 0x03;
+const XDS_SET_SRST: u8 = 0x0e;
+const XDS_SET_SUPPLY: u8 = 0x32;
+
+/// The oldest firmware with the command set OpenOCD's XDS110 driver uses
+const OCD_FIRMWARE_VERSION: u32 = 0x02_03_00_11;
+/// Hardware ID of the standalone XDS110, as opposed to one built into a
+/// LaunchPad
+const STANDALONE_HARDWARE_ID: u16 = 0x21;
+/// What [`power_target`](Xds110UsbDevice::power_target) supplies, in mV
+const TARGET_SUPPLY_VOLTAGE: u32 = 3300;
+
+/// The vendor commands a probe's firmware and hardware support
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SupportedCommands {
+    pub firmware: FirmwareVersion,
+    pub hardware_id: u16,
+    /// Driving the target's reset line, see
+    /// [`reset_target`](Xds110UsbDevice::reset_target)
+    pub has_target_reset: bool,
+    /// Supplying power to the target, only wired up on the standalone probe
+    pub has_supply_control: bool,
+    /// Mode 4, see [`CMSIS_DAP_2_MINIMUM`]
+    pub has_cmsis_dap2: bool,
+    /// Setting the probe's LEDs. No XDS110 firmware has a command for it,
+    /// the firmware drives them itself, so this is always false.
+    pub has_led_control: bool,
+    /// Reading the serial number with a vendor command. No XDS110 firmware
+    /// has one, so this is always false; the serial number is in the USB
+    /// string descriptor, see [`serial`](Xds110UsbDevice::serial).
+    pub has_serial_query: bool,
+}
+
+impl Xds110UsbDevice {
+    /// Send `command` and return the response data after the error code
+    fn execute(
+        &self,
+        command: u8,
+        params: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Xds110Error> {
+        let len = (params.len() + 1) as u16;
+        let mut request = vec![0x2a];
+        request.extend_from_slice(&len.to_le_bytes());
+        request.push(command);
+        request.extend_from_slice(params);
+        self.device_handle
+            .write_bulk(self.epout, &request, timeout)?;
+
+        let mut response = [0u8; 64];
+        let n = self
+            .device_handle
+            .read_bulk(self.epin, &mut response, timeout)?;
+        if n < 7 || response[0] != 0x2a {
+            return Err(Xds110Error::InvalidResponse { command });
+        }
+        let code = i32::from_le_bytes(response[3..7].try_into().unwrap());
+        if code != 0 {
+            return Err(Xds110Error::CommandFailed { command, code });
+        }
+        Ok(response[7..n].to_vec())
+    }
+
+    pub fn supported_commands(&self) -> Result<SupportedCommands, Xds110Error> {
+        let data = self.execute(XDS_VERSION, &[], FIRMWARE_VERSION_TIMEOUT)?;
+        if data.len() < 6 {
+            return Err(Xds110Error::InvalidResponse {
+                command: XDS_VERSION,
+            });
+        }
+        let firmware = FirmwareVersion(u32::from_le_bytes(data[0..4].try_into().unwrap()));
+        let hardware_id = u16::from_le_bytes([data[4], data[5]]);
+        Ok(SupportedCommands {
+            firmware,
+            hardware_id,
+            has_target_reset: firmware >= FirmwareVersion(OCD_FIRMWARE_VERSION),
+            has_supply_control: hardware_id == STANDALONE_HARDWARE_ID,
+            has_cmsis_dap2: firmware >= FirmwareVersion(CMSIS_DAP_2_MINIMUM),
+            has_led_control: false,
+            has_serial_query: false,
+        })
+    }
+
+    /// Drive the target's reset line, holding the target in reset while
+    /// `asserted`
+    pub fn reset_target(&self, asserted: bool) -> Result<(), Xds110Error> {
+        if !self.supported_commands()?.has_target_reset {
+            return Err(Xds110Error::Unsupported {
+                command: XDS_SET_SRST,
+            });
+        }
+        // The line is active low
+        self.execute(XDS_SET_SRST, &[!asserted as u8], COMMAND_TIMEOUT)?;
+        Ok(())
+    }
+
+    /// Switch the 3.3V supply to the target on or off. Probes that cannot
+    /// power the target fail with [`Xds110Error::Unsupported`].
+    pub fn power_target(&self, on: bool) -> Result<(), Xds110Error> {
+        if !self.supported_commands()?.has_supply_control {
+            return Err(Xds110Error::Unsupported {
+                command: XDS_SET_SUPPLY,
+            });
+        }
+        // The voltage, then whether to drive it at all
+        let voltage = if on { TARGET_SUPPLY_VOLTAGE } else { 0 };
+        let mut params = voltage.to_le_bytes().to_vec();
+        params.push(on as u8);
+        self.execute(XDS_SET_SUPPLY, &params, COMMAND_TIMEOUT)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockFirmware;
+
+    #[test]
+    fn commands_follow_the_firmware_and_hardware() {
+        let probe = MockFirmware::xds110(CMSIS_DAP_2_MINIMUM, STANDALONE_HARDWARE_ID).open();
+        let supported = probe.supported_commands().unwrap();
+        assert_eq!(supported.firmware, FirmwareVersion(CMSIS_DAP_2_MINIMUM));
+        assert!(supported.has_target_reset);
+        assert!(supported.has_supply_control);
+        assert!(supported.has_cmsis_dap2);
+        // Not on any firmware
+        assert!(!supported.has_led_control);
+        assert!(!supported.has_serial_query);
+
+        let probe = MockFirmware::xds110(OCD_FIRMWARE_VERSION - 1, 0).open();
+        let supported = probe.supported_commands().unwrap();
+        assert!(!supported.has_target_reset);
+        assert!(!supported.has_supply_control);
+        assert!(!supported.has_cmsis_dap2);
+        assert!(matches!(
+            probe.reset_target(true),
+            Err(Xds110Error::Unsupported {
+                command: XDS_SET_SRST
+            })
+        ));
+        assert!(matches!(
+            probe.power_target(true),
+            Err(Xds110Error::Unsupported {
+                command: XDS_SET_SUPPLY
+            })
+        ));
+    }
+}
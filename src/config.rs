@@ -0,0 +1,157 @@
+//! Layout of the configuration block.
+
+use std::fmt;
+
+use crate::{CONFIGURATION_SIZE, FirmwareVersion, ProbeMode, Xds110Error};
+
+/// Offset of the mode in the configuration block, a little-endian `u16`
+pub const MODE_OFFSET: usize = 16;
+/// Offset of [`MAGIC_BYTES`] in the configuration block, right after the mode
+pub const MAGIC_OFFSET: usize = 18;
+/// What a valid configuration block holds at [`MAGIC_OFFSET`]
+pub const MAGIC_BYTES: [u8; 2] = [0x55, 0xaa];
+
+/// The fields of the configuration block that this tool understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConfigurationSummary {
+    pub mode: ProbeMode,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::serde_util::hex_bytes")
+    )]
+    pub magic: [u8; 2],
+    pub magic_valid: bool,
+}
+
+impl ConfigurationSummary {
+    /// Summarize a configuration block. Panics if `configuration` is too
+    /// short to hold the mode and magic.
+    pub fn parse(configuration: &[u8]) -> Self {
+        let magic = [configuration[MAGIC_OFFSET], configuration[MAGIC_OFFSET + 1]];
+        ConfigurationSummary {
+            mode: ProbeMode::from(u16::from_le_bytes([
+                configuration[MODE_OFFSET],
+                configuration[MODE_OFFSET + 1],
+            ])),
+            magic,
+            magic_valid: magic == MAGIC_BYTES,
+        }
+    }
+
+    /// Whether the block looks like a layout other than the one this crate
+    /// knows. It has no version field, so this goes by the magic: a block
+    /// that is erased or zeroed where the magic belongs is taken as damaged
+    /// rather than foreign, and can be repaired.
+    pub fn unrecognized_layout(&self) -> bool {
+        !self.magic_valid && self.magic != [0xff, 0xff] && self.magic != [0x00, 0x00]
+    }
+}
+
+/// Bring `cfg` up to the layout that `fw_version` reads, returning whether
+/// anything had to be changed. `fw_version` is `None` when it is not known,
+/// as for a probe found in its bootloader.
+///
+/// Every firmware so far reads the one layout this crate knows, so there is
+/// nothing to migrate: a configuration in that layout, or a damaged one that
+/// can be repaired, gives `Ok(false)`. One whose layout is
+/// [unrecognized](ConfigurationSummary::unrecognized_layout) fails with
+/// [`Xds110Error::UnrecognizedConfigLayout`], as writing it back to any
+/// firmware could corrupt it.
+pub fn ensure_configuration_version_is_current(
+    cfg: &mut Xds110Configuration,
+    fw_version: Option<FirmwareVersion>,
+) -> Result<bool, Xds110Error> {
+    let _ = fw_version;
+    let summary = cfg.summary();
+    if summary.unrecognized_layout() {
+        return Err(Xds110Error::UnrecognizedConfigLayout {
+            magic: summary.magic,
+        });
+    }
+    Ok(false)
+}
+
+/// A complete configuration block, as read from and written to the probe
+#[derive(Clone, PartialEq, Eq)]
+pub struct Xds110Configuration(Box<[u8; CONFIGURATION_SIZE as usize]>);
+
+impl Xds110Configuration {
+    pub fn from_bytes(raw: [u8; CONFIGURATION_SIZE as usize]) -> Self {
+        Xds110Configuration(Box::new(raw))
+    }
+
+    pub fn to_bytes(&self) -> &[u8; CONFIGURATION_SIZE as usize] {
+        &self.0
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8; CONFIGURATION_SIZE as usize] {
+        &mut self.0
+    }
+
+    pub fn summary(&self) -> ConfigurationSummary {
+        ConfigurationSummary::parse(&self.0[..])
+    }
+
+    pub fn set_mode(&mut self, mode: ProbeMode) {
+        self.0[MODE_OFFSET..MODE_OFFSET + 2].copy_from_slice(&mode.number().to_le_bytes());
+    }
+
+    /// Restore the magic
+    pub fn set_magic(&mut self) {
+        self.0[MAGIC_OFFSET..MAGIC_OFFSET + MAGIC_BYTES.len()].copy_from_slice(&MAGIC_BYTES);
+    }
+}
+
+impl TryFrom<&[u8]> for Xds110Configuration {
+    type Error = std::array::TryFromSliceError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Ok(Xds110Configuration::from_bytes(value.try_into()?))
+    }
+}
+
+/// Shows the summary rather than all 16 KiB
+impl fmt::Debug for Xds110Configuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Xds110Configuration")
+            .field(&self.summary())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_magic(magic: [u8; 2]) -> Xds110Configuration {
+        let mut bytes = [0; CONFIGURATION_SIZE as usize];
+        bytes[MAGIC_OFFSET..MAGIC_OFFSET + 2].copy_from_slice(&magic);
+        Xds110Configuration::from_bytes(bytes)
+    }
+
+    #[test]
+    fn known_layout_needs_no_migration() {
+        for magic in [MAGIC_BYTES, [0xff, 0xff], [0x00, 0x00]] {
+            let mut configuration = with_magic(magic);
+            let before = configuration.clone();
+            for firmware in [None, Some(FirmwareVersion(crate::CMSIS_DAP_2_MINIMUM))] {
+                assert!(
+                    !ensure_configuration_version_is_current(&mut configuration, firmware).unwrap()
+                );
+            }
+            assert!(configuration == before);
+        }
+    }
+
+    #[test]
+    fn unrecognized_layout_is_refused() {
+        let mut configuration = with_magic([0x12, 0x34]);
+        assert!(matches!(
+            ensure_configuration_version_is_current(&mut configuration, None),
+            Err(Xds110Error::UnrecognizedConfigLayout {
+                magic: [0x12, 0x34]
+            })
+        ));
+    }
+}
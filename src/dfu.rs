@@ -0,0 +1,490 @@
+//! Raw Tiva DFU bootloader primitives.
+//!
+//! The bootloader speaks standard USB DFU 1.1, with Tiva-specific commands
+//! sent as the payload of a `DFU_DNLOAD` request. See the Tiva USB DFU Class
+//! reference (spma054) for the command layouts.
+
+use nusb::transfer::TransferError;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::error::TransferContext;
+use crate::{Transport, Xds110Error};
+
+pub const DFU_DETACH: u8 = 0;
+pub const DFU_DNLOAD: u8 = 1;
+pub const DFU_UPLOAD: u8 = 2;
+pub const DFU_GETSTATUS: u8 = 3;
+pub const DFU_CLRSTATUS: u8 = 4;
+pub const DFU_ABORT: u8 = 6;
+
+/// `bInterfaceClass` and `bInterfaceSubClass` of a DFU interface
+const DFU_INTERFACE_CLASS: u8 = 0xfe;
+const DFU_INTERFACE_SUBCLASS: u8 = 0x01;
+/// `bDescriptorType` of the DFU functional descriptor
+const DFU_FUNCTIONAL_DESCRIPTOR: u8 = 0x21;
+
+/// Tiva vendor request used to query the binary protocol
+const USBD_DFU_REQUEST_TIVA: u8 = 0x42;
+const REQUEST_TIVA_VALUE: u16 = 0x23;
+/// "LM", which a Tiva bootloader answers the protocol query with, followed
+/// by the protocol version
+const TIVA_PROTOCOL_MARKER: u16 = 0x4c4d;
+
+pub const DFU_CMD_WRITE: u8 = 1;
+pub const DFU_CMD_READ: u8 = 2;
+pub const DFU_CMD_ERASE: u8 = 4;
+pub const DFU_CMD_INFO: u8 = 5;
+pub const DFU_CMD_BIN: u8 = 6;
+pub const DFU_CMD_RESET: u8 = 7;
+
+/// A Tiva bootloader command. Blocks are 1024 bytes long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TivaCommand {
+    /// Start a download of `len` bytes to flash starting at `block`
+    Write { block: u16, len: u32 },
+    /// Start an upload of `len` bytes from flash starting at `block`
+    Read { block: u16, len: u32 },
+    /// Erase `num_blocks` blocks of flash starting at `block`
+    Erase { block: u16, num_blocks: u16 },
+    /// Prepare a [`FlashGeometry`] for the next upload
+    Info,
+    /// Enable or disable the 8-byte header prefixed to uploaded data
+    Bin { no_header: bool },
+    /// Leave the bootloader, jumping to `addr`. The address follows the
+    /// command byte, least significant byte first, so 0x0100df20 is sent as
+    /// `20 df 00 01`.
+    Reset { addr: u32 },
+}
+
+/// The name of the Tiva command with the first byte `command`, for error
+/// messages
+pub fn command_name(command: u8) -> &'static str {
+    match command {
+        DFU_CMD_WRITE => "DFU_CMD_WRITE",
+        DFU_CMD_READ => "DFU_CMD_READ",
+        DFU_CMD_ERASE => "DFU_CMD_ERASE",
+        DFU_CMD_INFO => "DFU_CMD_INFO",
+        DFU_CMD_BIN => "DFU_CMD_BIN",
+        DFU_CMD_RESET => "DFU_CMD_RESET",
+        _ => "Tiva command",
+    }
+}
+
+impl TivaCommand {
+    /// The name of the command, for error messages
+    pub fn name(&self) -> &'static str {
+        command_name(self.to_bytes()[0])
+    }
+
+    /// The `DFU_DNLOAD` payload for this command
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            TivaCommand::Write { block, len } => transfer_header(DFU_CMD_WRITE, block, len),
+            TivaCommand::Read { block, len } => transfer_header(DFU_CMD_READ, block, len),
+            TivaCommand::Erase { block, num_blocks } => {
+                let mut bytes = vec![DFU_CMD_ERASE, 0];
+                bytes.extend_from_slice(&block.to_le_bytes());
+                bytes.extend_from_slice(&num_blocks.to_le_bytes());
+                bytes.resize(8, 0);
+                bytes
+            }
+            TivaCommand::Info => vec![DFU_CMD_INFO, 0, 0, 0, 0, 0, 0, 0],
+            TivaCommand::Bin { no_header } => {
+                // Padded to the length the XDS110 bootloader has always been sent
+                let mut bytes = vec![0; 11];
+                bytes[0] = DFU_CMD_BIN;
+                bytes[1] = no_header as u8;
+                bytes
+            }
+            TivaCommand::Reset { addr } => {
+                let mut bytes = vec![0; 8];
+                bytes[0] = DFU_CMD_RESET;
+                bytes[1..5].copy_from_slice(&addr.to_le_bytes());
+                bytes
+            }
+        }
+    }
+}
+
+/// Command byte, a reserved byte, the 16-bit start block and a 32-bit length
+fn transfer_header(command: u8, block: u16, len: u32) -> Vec<u8> {
+    let mut bytes = vec![command, 0];
+    bytes.extend_from_slice(&block.to_le_bytes());
+    bytes.extend_from_slice(&len.to_le_bytes());
+    bytes
+}
+
+/// Split the `DFU_CMD_PROG` header that Tiva tools such as `dfuwrap` put in
+/// front of an image off `file`. Returns the start block it names and the
+/// image, or `None` if `file` does not start with a header for exactly the
+/// rest of the file.
+pub fn split_prog_prefix(file: &[u8]) -> Option<(u16, &[u8])> {
+    let (header, image) = file.split_at_checked(8)?;
+    let block = u16::from_le_bytes([header[2], header[3]]);
+    let len = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    (header[..2] == [DFU_CMD_WRITE, 0] && len as usize == image.len()).then_some((block, image))
+}
+
+/// The flash layout reported in response to [`TivaCommand::Info`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FlashGeometry {
+    /// Size of an erasable flash block, in bytes
+    pub block_size: u16,
+    pub num_blocks: u16,
+    /// Contents of the device's DID1 register
+    pub part_info: u32,
+    /// Contents of the device's DID0 register
+    pub class_info: u32,
+    /// One past the last address of flash, which is also its size
+    pub flash_top: u32,
+    /// Where the application image starts; everything below belongs to the
+    /// bootloader
+    pub app_start: u32,
+}
+
+impl FlashGeometry {
+    /// Length of the upload carrying the geometry
+    pub const SIZE: u16 = 20;
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..Self::SIZE as usize)?;
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Some(FlashGeometry {
+            block_size: u16_at(0),
+            num_blocks: u16_at(2),
+            part_info: u32_at(4),
+            class_info: u32_at(8),
+            flash_top: u32_at(12),
+            app_start: u32_at(16),
+        })
+    }
+}
+
+/// The name the DFU 1.1 specification gives `bStatus`
+pub fn status_name(status: u8) -> &'static str {
+    match status {
+        0x00 => "OK",
+        0x01 => "errTARGET",
+        0x02 => "errFILE",
+        0x03 => "errWRITE",
+        0x04 => "errERASE",
+        0x05 => "errCHECK_ERASED",
+        0x06 => "errPROG",
+        0x07 => "errVERIFY",
+        0x08 => "errADDRESS",
+        0x09 => "errNOTDONE",
+        0x0a => "errFIRMWARE",
+        0x0b => "errVENDOR",
+        0x0c => "errUSBR",
+        0x0d => "errPOR",
+        0x0e => "errUNKNOWN",
+        0x0f => "errSTALLEDPKT",
+        _ => "unknown status",
+    }
+}
+
+/// `bState` from a DFU status response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum DfuState {
+    AppIdle,
+    AppDetach,
+    DfuIdle,
+    DnloadSync,
+    DnBusy,
+    DnloadIdle,
+    ManifestSync,
+    Manifest,
+    ManifestWaitReset,
+    UploadIdle,
+    Error,
+    Unknown(u8),
+}
+
+impl From<u8> for DfuState {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => DfuState::AppIdle,
+            1 => DfuState::AppDetach,
+            2 => DfuState::DfuIdle,
+            3 => DfuState::DnloadSync,
+            4 => DfuState::DnBusy,
+            5 => DfuState::DnloadIdle,
+            6 => DfuState::ManifestSync,
+            7 => DfuState::Manifest,
+            8 => DfuState::ManifestWaitReset,
+            9 => DfuState::UploadIdle,
+            10 => DfuState::Error,
+            other => DfuState::Unknown(other),
+        }
+    }
+}
+
+/// A parsed `DFU_GETSTATUS` response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DfuStatus {
+    /// `bStatus`, 0 means OK
+    pub status: u8,
+    /// `bwPollTimeout`, how long the host should wait before polling again
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            rename = "poll_timeout_ms",
+            serialize_with = "crate::serde_util::millis"
+        )
+    )]
+    pub poll_timeout: Duration,
+    pub state: DfuState,
+    /// `iString`, a string descriptor index describing the status
+    pub string_index: u8,
+}
+
+impl DfuStatus {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; 6] = bytes.get(..6)?.try_into().ok()?;
+        Some(DfuStatus {
+            status: bytes[0],
+            poll_timeout: Duration::from_millis(u32::from_le_bytes([
+                bytes[1], bytes[2], bytes[3], 0,
+            ]) as u64),
+            state: DfuState::from(bytes[4]),
+            string_index: bytes[5],
+        })
+    }
+
+    /// How long to wait before polling again: the poll timeout, capped so a
+    /// bogus value cannot stall the host
+    pub fn poll_delay(&self) -> Duration {
+        self.poll_timeout.min(MAX_POLL_DELAY)
+    }
+}
+
+/// Shows the state and `bStatus`. The string behind `iString` has to be
+/// fetched from the device, see
+/// [`Xds110DfuDevice::get_status_string`](crate::Xds110DfuDevice::get_status_string).
+impl fmt::Display for DfuStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}, status {}", self.state, self.status)
+    }
+}
+
+/// The longest [`DfuStatus::poll_delay`] will wait
+const MAX_POLL_DELAY: Duration = Duration::from_millis(500);
+
+/// Where the DFU interface sits in the configuration of a device. Its number
+/// is the `wIndex` of the class requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuInterface {
+    pub number: u8,
+    pub alt_setting: u8,
+    /// The functional descriptor following the interface descriptor, if
+    /// there is one
+    pub functional: Option<DfuFunctional>,
+}
+
+/// The DFU functional descriptor, with what the bootloader can do and how
+/// much it takes at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuFunctional {
+    /// `bmAttributes`: bit 0 for download, bit 1 for upload, bit 2 for
+    /// manifestation tolerant and bit 3 for detaching by itself
+    pub attributes: u8,
+    pub detach_timeout_ms: u16,
+    /// The most bytes one `DFU_DNLOAD` or `DFU_UPLOAD` may carry
+    pub transfer_size: u16,
+    /// `bcdDFUVersion`, which DFU 1.0 descriptors lack
+    pub dfu_version: Option<u16>,
+}
+
+impl DfuFunctional {
+    pub fn parse(descriptor: &[u8]) -> Option<Self> {
+        if descriptor.len() < 7 || descriptor[1] != DFU_FUNCTIONAL_DESCRIPTOR {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([descriptor[i], descriptor[i + 1]]);
+        Some(DfuFunctional {
+            attributes: descriptor[2],
+            detach_timeout_ms: u16_at(3),
+            transfer_size: u16_at(5),
+            dfu_version: (descriptor.len() >= 9).then(|| u16_at(7)),
+        })
+    }
+}
+
+/// Find the first DFU interface in the configuration of `device`
+pub fn find_interface(device: &nusb::Device) -> Option<DfuInterface> {
+    let config = device.configurations().next()?;
+    config
+        .interface_alt_settings()
+        .find(|alt| alt.class() == DFU_INTERFACE_CLASS && alt.subclass() == DFU_INTERFACE_SUBCLASS)
+        .map(|alt| DfuInterface {
+            number: alt.interface_number(),
+            alt_setting: alt.alternate_setting(),
+            functional: alt
+                .descriptors()
+                .find_map(|descriptor| DfuFunctional::parse(&descriptor)),
+        })
+}
+
+/// Issue `DFU_GETSTATUS`. The bootloader expects this after every operation.
+pub fn get_status(device: &dyn Transport, interface: u8) -> Result<DfuStatus, TransferError> {
+    let bytes = get_status_bytes(device, interface)?;
+    DfuStatus::parse(&bytes).ok_or(TransferError::Fault)
+}
+
+/// Issue `DFU_GETSTATUS` and return the response unparsed, however long it
+/// turned out to be
+pub fn get_status_bytes(device: &dyn Transport, interface: u8) -> Result<Vec<u8>, TransferError> {
+    device.class_in(DFU_GETSTATUS, 0, interface as u16, 6)
+}
+
+/// Poll the status until the bootloader reports `state`, giving up after
+/// `timeout`
+pub fn wait_for_state(
+    device: &dyn Transport,
+    interface: u8,
+    state: DfuState,
+    timeout: Duration,
+) -> Result<DfuStatus, Xds110Error> {
+    poll_for_state(
+        || get_status(device, interface).context("DFU_GETSTATUS"),
+        state,
+        timeout,
+    )
+}
+
+/// Call `poll` until it returns a status in `state`, waiting the poll
+/// timeout in between. Fails once the same state has lasted suspiciously
+/// long, or when `timeout` is up.
+pub(crate) fn poll_for_state(
+    mut poll: impl FnMut() -> Result<DfuStatus, Xds110Error>,
+    state: DfuState,
+    timeout: Duration,
+) -> Result<DfuStatus, Xds110Error> {
+    let deadline = Instant::now() + timeout;
+    let mut hang = HangDetector::default();
+    loop {
+        let status = poll()?;
+        if status.state == state {
+            return Ok(status);
+        }
+        hang.check(&status)?;
+        if Instant::now() >= deadline {
+            return Err(Xds110Error::WaitTimeout {
+                expected: state,
+                state: status.state,
+                status: status.status,
+            });
+        }
+        std::thread::sleep(status.poll_delay());
+    }
+}
+
+/// How long a state must last, at the least, before it counts as hung. A
+/// poll timeout of 0 would otherwise make any repeated state look hung.
+const HANG_TIMEOUT_FLOOR: Duration = Duration::from_secs(1);
+
+/// Notices a bootloader that has stayed in the same state for ten times the
+/// poll timeout it asked for
+#[derive(Default)]
+pub(crate) struct HangDetector {
+    /// The state last seen, when it was first seen and its poll timeout
+    current: Option<(DfuState, Instant, Duration)>,
+}
+
+impl HangDetector {
+    pub(crate) fn check(&mut self, status: &DfuStatus) -> Result<(), Xds110Error> {
+        match self.current {
+            Some((state, since, poll_timeout)) if state == status.state => {
+                let elapsed = since.elapsed();
+                if elapsed > (poll_timeout * 10).max(HANG_TIMEOUT_FLOOR) {
+                    return Err(Xds110Error::DeviceHung { state, elapsed });
+                }
+            }
+            _ => self.current = Some((status.state, Instant::now(), status.poll_timeout)),
+        }
+        Ok(())
+    }
+}
+
+/// Issue a request that carries no data, such as `DFU_ABORT`
+fn request(
+    device: &dyn Transport,
+    interface: u8,
+    request: u8,
+    value: u16,
+) -> Result<(), TransferError> {
+    device.class_out(request, value, interface as u16, &[])
+}
+
+/// Issue `DFU_CLRSTATUS`, leaving `dfuERROR` for `dfuIDLE`
+pub fn clear_status(device: &dyn Transport, interface: u8) -> Result<(), TransferError> {
+    request(device, interface, DFU_CLRSTATUS, 0)
+}
+
+/// Issue `DFU_DETACH`, asking the device to leave DFU mode at the next bus
+/// reset if one comes within `timeout_ms`
+pub fn detach(device: &dyn Transport, interface: u8, timeout_ms: u16) -> Result<(), TransferError> {
+    request(device, interface, DFU_DETACH, timeout_ms)
+}
+
+/// Issue `DFU_ABORT`, returning from an idle transfer state to `dfuIDLE`
+pub fn abort(device: &dyn Transport, interface: u8) -> Result<(), TransferError> {
+    request(device, interface, DFU_ABORT, 0)
+}
+
+/// Issue a raw `DFU_DNLOAD` with `block_num` as its wValue
+pub fn download(
+    device: &dyn Transport,
+    interface: u8,
+    block_num: u16,
+    data: &[u8],
+) -> Result<(), TransferError> {
+    device.class_out(DFU_DNLOAD, block_num, interface as u16, data)
+}
+
+/// Issue a raw `DFU_UPLOAD` with `block_num` as its wValue. Where the data
+/// comes from was set up by the [`TivaCommand::Read`] before it.
+pub fn upload(
+    device: &dyn Transport,
+    interface: u8,
+    block_num: u16,
+    length: u16,
+) -> Result<Vec<u8>, TransferError> {
+    device.class_in(DFU_UPLOAD, block_num, interface as u16, length)
+}
+
+/// Send a Tiva command and fetch the status that must follow it
+pub fn send_command(
+    device: &dyn Transport,
+    interface: u8,
+    block_num: u16,
+    command: TivaCommand,
+) -> Result<DfuStatus, TransferError> {
+    download(device, interface, block_num, &command.to_bytes())?;
+    get_status(device, interface)
+}
+
+/// Whether `response` to the Tiva protocol query carries the marker of a
+/// bootloader speaking the binary protocol
+pub fn is_tiva_protocol(response: &[u8]) -> bool {
+    response.len() >= 4 && u16::from_le_bytes([response[0], response[1]]) == TIVA_PROTOCOL_MARKER
+}
+
+/// Issue the Tiva protocol query, returning the raw response
+pub fn query_tiva_protocol(
+    device: &dyn Transport,
+    interface: u8,
+) -> Result<Vec<u8>, TransferError> {
+    device.class_in(
+        USBD_DFU_REQUEST_TIVA,
+        REQUEST_TIVA_VALUE,
+        interface as u16,
+        4,
+    )
+}
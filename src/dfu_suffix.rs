@@ -0,0 +1,115 @@
+//! The 16-byte suffix DFU 1.1 appends to firmware files.
+//!
+//! The suffix names the device the image is for and ends with a CRC-32 of
+//! everything before the CRC itself. `dfu-util` and TI's tools produce and
+//! check the same layout.
+
+use crate::{XDS110_DFU_DEVICES, XDS110_USB_DEVICES, Xds110Error};
+
+/// Length of the suffix, which is also its `bLength` field
+pub const DFU_SUFFIX_LEN: usize = 16;
+/// `ucDfuSignature`, as it appears in the file
+const SIGNATURE: &[u8; 3] = b"UFD";
+/// The `bcdDFU` of suffixes this crate writes
+const DFU_VERSION: u16 = 0x0100;
+/// Stands for any ID in the vendor, product and device fields
+const ANY_ID: u16 = 0xffff;
+
+/// The identifying fields of a DFU suffix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuSuffix {
+    /// `bcdDevice` of the intended device, or `0xffff` for any
+    pub device: u16,
+    /// `idProduct` of the intended device, or `0xffff` for any
+    pub product: u16,
+    /// `idVendor` of the intended device, or `0xffff` for any
+    pub vendor: u16,
+    /// `bcdDFU`, the DFU version the suffix follows
+    pub dfu_version: u16,
+}
+
+impl DfuSuffix {
+    /// A suffix for any XDS110 in DFU mode
+    pub fn xds110() -> Self {
+        DfuSuffix {
+            device: ANY_ID,
+            product: XDS110_DFU_DEVICES[0].pid,
+            vendor: XDS110_DFU_DEVICES[0].vid,
+            dfu_version: DFU_VERSION,
+        }
+    }
+
+    /// Split `file` into the image and its suffix. A file without the
+    /// suffix signature is all image. A suffix with a bad CRC fails with
+    /// [`Xds110Error::DfuSuffixCrcMismatch`].
+    pub fn split(file: &[u8]) -> Result<(&[u8], Option<DfuSuffix>), Xds110Error> {
+        let Some(split) = file.len().checked_sub(DFU_SUFFIX_LEN) else {
+            return Ok((file, None));
+        };
+        let (image, suffix) = file.split_at(split);
+        if &suffix[8..11] != SIGNATURE || suffix[11] as usize != DFU_SUFFIX_LEN {
+            return Ok((file, None));
+        }
+
+        let expected = u32::from_le_bytes(suffix[12..16].try_into().unwrap());
+        let actual = crc32(&file[..file.len() - 4]);
+        if actual != expected {
+            return Err(Xds110Error::DfuSuffixCrcMismatch { expected, actual });
+        }
+        let field = |i: usize| u16::from_le_bytes([suffix[i], suffix[i + 1]]);
+        Ok((
+            image,
+            Some(DfuSuffix {
+                device: field(0),
+                product: field(2),
+                vendor: field(4),
+                dfu_version: field(6),
+            }),
+        ))
+    }
+
+    /// Whether the suffix allows a device with these IDs
+    pub fn matches(&self, vendor: u16, product: u16) -> bool {
+        (self.vendor == ANY_ID || self.vendor == vendor)
+            && (self.product == ANY_ID || self.product == product)
+    }
+
+    /// Whether the suffix allows an XDS110, in either of its states
+    pub fn matches_xds110(&self) -> bool {
+        XDS110_USB_DEVICES
+            .iter()
+            .map(|x| (x.vid, x.pid))
+            .chain(XDS110_DFU_DEVICES.iter().map(|x| (x.vid, x.pid)))
+            .any(|(vid, pid)| self.matches(vid, pid))
+    }
+
+    /// Append this suffix and its CRC to `image`, for instance to make a
+    /// dump from [`upload_firmware`](crate::Xds110DfuDevice::upload_firmware)
+    /// loadable with other DFU tools
+    pub fn append_to(&self, image: &mut Vec<u8>) {
+        for field in [self.device, self.product, self.vendor, self.dfu_version] {
+            image.extend(field.to_le_bytes());
+        }
+        image.extend(SIGNATURE);
+        image.push(DFU_SUFFIX_LEN as u8);
+        let crc = crc32(image);
+        image.extend(crc.to_le_bytes());
+    }
+}
+
+/// The CRC-32 of DFU suffixes, which is the usual one without the final
+/// inversion
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,416 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::dfu::{DfuState, FlashGeometry};
+use crate::{CONFIGURATION_SIZE, FirmwareVersion, FirmwareVersionError, SwdAck};
+
+#[derive(Debug)]
+pub enum Xds110Error {
+    /// Enumerating, opening or talking to the probe over bulk endpoints failed
+    Io(std::io::Error),
+
+    /// A control transfer to the DFU bootloader failed while doing
+    /// `operation`
+    Transfer {
+        operation: &'static str,
+        source: nusb::transfer::TransferError,
+    },
+
+    /// The bootloader sent back something that could not be parsed
+    InvalidDfuResponse(&'static str),
+
+    /// The address cannot be reached through the bootloader's commands
+    UnreachableAddress(u32),
+
+    /// The bootloader reported a flash layout without the configuration
+    /// block where this crate expects it, so it is not touched
+    UnexpectedFlashLayout(FlashGeometry),
+
+    /// A single word cannot be written at this address, as it lies in flash,
+    /// which is only erased a page at a time
+    FlashWordWrite(u32),
+
+    /// A raw write starting at `address` would overwrite the bootloader,
+    /// which ends at `app_start`
+    BootloaderOverlap { address: u32, app_start: u32 },
+
+    /// A firmware image did not hash to the expected SHA-256
+    ChecksumMismatch {
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+
+    /// A firmware image does not fit in the application region
+    ImageTooLarge { size: usize, capacity: usize },
+
+    /// A configuration patch lies beyond the end of the block
+    PatchOutOfRange { offset: u16 },
+
+    /// A configuration patch touches a byte the tool maintains itself
+    PatchReserved { offset: u16 },
+
+    /// The configuration holds `magic` where the magic belongs, so it may be
+    /// in a layout this crate does not know and is not written
+    UnrecognizedConfigLayout { magic: [u8; 2] },
+
+    /// The configuration read back differs from what was written
+    VerifyFailed { offset: u16 },
+
+    /// The firmware read back differs from the image at `address`
+    FirmwareVerifyFailed { address: u32 },
+
+    /// The bootloader stayed in `state` for far longer than it said it
+    /// would need
+    DeviceHung { state: DfuState, elapsed: Duration },
+
+    /// The bootloader went into `dfuERROR` with `bStatus` `status`.
+    /// `description` is the string it attached, if any.
+    BootloaderError {
+        status: u8,
+        description: Option<String>,
+    },
+
+    /// The bootloader did not reach `expected` in time. `state` and
+    /// `status` are from the last status it reported.
+    WaitTimeout {
+        expected: DfuState,
+        state: DfuState,
+        status: u8,
+    },
+
+    /// The bootloader did not settle in `dfuIDLE` around the reset that
+    /// leaves it. `state` and `status` are from the last status it
+    /// reported. Whatever was written last may not have been committed.
+    ResetTimeout { state: DfuState, status: u8 },
+
+    /// The operation was stopped through its
+    /// [`CancelToken`](crate::CancelToken). The bootloader has been returned
+    /// to `dfuIDLE`, but a write may have been left half done.
+    Cancelled,
+
+    /// The probe did not show up again after being asked to switch states
+    ReenumerationTimeout,
+
+    /// A vendor command response was truncated or malformed
+    InvalidResponse { command: u8 },
+
+    /// The probe reported an error code for a vendor command
+    CommandFailed { command: u8, code: i32 },
+
+    /// The probe's firmware or hardware does not support a vendor command
+    Unsupported { command: u8 },
+
+    /// The probe firmware is too old for the requested mode
+    FirmwareTooOld {
+        found: FirmwareVersion,
+        minimum: FirmwareVersion,
+    },
+
+    /// The probe does not expose a CMSIS-DAP 2.0 interface, for example
+    /// because it has not been switched to mode 4 yet
+    CmsisDapUnavailable,
+
+    /// The probe has a CMSIS-DAP 2.0 interface but claiming it failed,
+    /// usually because another program is using it
+    CmsisDapClaimFailed(std::io::Error),
+
+    /// A CMSIS-DAP response was truncated or did not match its command
+    CmsisDapInvalidResponse { command: u8 },
+
+    /// The probe reported that a CMSIS-DAP command failed
+    CmsisDapCommandFailed { command: u8 },
+
+    /// The JTAG scan chain gave back no data, or only ever the same level
+    /// on TDO
+    JtagChainBroken,
+
+    /// The instruction registers of a JTAG chain could not be told apart
+    JtagChainUnresolved { devices: usize, ir_length: usize },
+
+    /// An SWJ sequence of `bits` bits was given `len` bytes of data
+    SwjSequenceLength { bits: usize, len: usize },
+
+    /// Not one of the four register addresses a DAP transfer can name
+    InvalidDapRegister(u8),
+
+    /// An SWD transfer was not acknowledged with OK
+    SwdTransferFailed(SwdAck),
+
+    /// A firmware file carries a DFU suffix whose CRC is wrong
+    DfuSuffixCrcMismatch { expected: u32, actual: u32 },
+
+    /// A firmware file's DFU suffix names a device other than the XDS110
+    DfuSuffixMismatch { vendor: u16, product: u16 },
+
+    /// A streamed flash read stopped part way. Everything before `address`
+    /// has been written out.
+    FlashReadFailed {
+        address: u32,
+        source: Box<Xds110Error>,
+    },
+
+    /// The bootloader stalled the request for `operation`. `status` is the
+    /// `bStatus` it gave as the reason; the error has been cleared since.
+    Stalled { operation: &'static str, status: u8 },
+
+    /// The bootloader stalled the Tiva protocol query, so it does not know
+    /// the Tiva commands
+    TivaQueryUnsupported,
+
+    /// The bootloader answered a ping in `state` with `bStatus` `status`,
+    /// rather than idle and OK
+    UnexpectedState { state: DfuState, status: u8 },
+
+    /// An upload came back empty after `received` of the `expected` bytes
+    UploadEnded { received: u32, expected: u32 },
+
+    /// The bootloader answered the Tiva protocol query without the marker,
+    /// with these bytes
+    NotTivaProtocol(Vec<u8>),
+}
+
+impl fmt::Display for Xds110Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Xds110Error::Io(e) => write!(f, "USB error: {}", e),
+            Xds110Error::Transfer { operation, source } => {
+                write!(f, "DFU transfer failed during {}: {}", operation, source)
+            }
+            Xds110Error::InvalidDfuResponse(request) => {
+                write!(f, "invalid response from the bootloader to {}", request)
+            }
+            Xds110Error::UnexpectedFlashLayout(geometry) => write!(
+                f,
+                "unexpected flash layout (application at {:#x}, top of flash at {:#x}, \
+                 {}-byte blocks), refusing to touch the configuration",
+                geometry.app_start, geometry.flash_top, geometry.block_size
+            ),
+            Xds110Error::FlashWordWrite(address) => write!(
+                f,
+                "address {:#010x} is in flash, which cannot be written a word at a time",
+                address
+            ),
+            Xds110Error::BootloaderOverlap { address, app_start } => write!(
+                f,
+                "writing at {:#010x} would overwrite the bootloader, which ends at {:#010x}",
+                address, app_start
+            ),
+            Xds110Error::UnreachableAddress(address) => write!(
+                f,
+                "address {:#010x} cannot be reached through the bootloader",
+                address
+            ),
+            Xds110Error::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "image SHA-256 is {} but {} was expected",
+                hex(actual),
+                hex(expected)
+            ),
+            Xds110Error::ImageTooLarge { size, capacity } => write!(
+                f,
+                "image is {} bytes but only {} bytes are available",
+                size, capacity
+            ),
+            Xds110Error::PatchOutOfRange { offset } => write!(
+                f,
+                "offset {:#06x} is outside the {}-byte configuration",
+                offset, CONFIGURATION_SIZE
+            ),
+            Xds110Error::PatchReserved { offset } => {
+                write!(f, "offset {:#06x} is reserved for the magic", offset)
+            }
+            Xds110Error::UnrecognizedConfigLayout { magic } => write!(
+                f,
+                "the configuration holds {:02x?} instead of the magic, it may be a newer \
+                 layout than this tool understands, refusing to write it",
+                magic
+            ),
+            Xds110Error::VerifyFailed { offset } => write!(
+                f,
+                "configuration did not verify, offset {:#06x} reads back differently",
+                offset
+            ),
+            Xds110Error::FirmwareVerifyFailed { address } => write!(
+                f,
+                "firmware did not verify, {:#010x} reads back differently",
+                address
+            ),
+            Xds110Error::DeviceHung { state, elapsed } => write!(
+                f,
+                "the bootloader appears hung, it has been in {:?} for {} ms",
+                state,
+                elapsed.as_millis()
+            ),
+            Xds110Error::BootloaderError {
+                status,
+                description,
+            } => {
+                write!(f, "the bootloader reported an error, status {}", status)?;
+                if let Some(description) = description {
+                    write!(f, ": {}", description)?;
+                }
+                Ok(())
+            }
+            Xds110Error::WaitTimeout {
+                expected,
+                state,
+                status,
+            } => write!(
+                f,
+                "timed out waiting for the bootloader to reach {:?}, it is in {:?} with status {}",
+                expected, state, status
+            ),
+            Xds110Error::ResetTimeout { state, status } => write!(
+                f,
+                "timed out resetting the bootloader, it is in {:?} with status {}; \
+                 the last write may not have been committed",
+                state, status
+            ),
+            Xds110Error::Cancelled => write!(f, "the operation was cancelled"),
+            Xds110Error::ReenumerationTimeout => {
+                write!(f, "timed out waiting for the probe to re-enumerate")
+            }
+            Xds110Error::InvalidResponse { command } => {
+                write!(f, "invalid response to command {:#04x}", command)
+            }
+            Xds110Error::CommandFailed { command, code } => {
+                write!(f, "command {:#04x} failed with error {}", command, code)
+            }
+            Xds110Error::Unsupported { command } => {
+                write!(f, "command {:#04x} is not supported by this probe", command)
+            }
+            Xds110Error::FirmwareTooOld { found, minimum } => write!(
+                f,
+                "CMSIS-DAP 2.0 is only supported on firmware versions >= {} -- Your firmware is {}",
+                minimum, found
+            ),
+            Xds110Error::CmsisDapUnavailable => {
+                write!(f, "the probe has no CMSIS-DAP 2.0 interface")
+            }
+            Xds110Error::CmsisDapClaimFailed(e) => write!(
+                f,
+                "could not claim the CMSIS-DAP 2.0 interface, is another program using it? ({})",
+                e
+            ),
+            Xds110Error::CmsisDapInvalidResponse { command } => {
+                write!(f, "invalid response to CMSIS-DAP command {:#04x}", command)
+            }
+            Xds110Error::CmsisDapCommandFailed { command } => {
+                write!(f, "CMSIS-DAP command {:#04x} failed", command)
+            }
+            Xds110Error::JtagChainBroken => write!(
+                f,
+                "the JTAG chain does not pass data through, is the target connected and powered?"
+            ),
+            Xds110Error::JtagChainUnresolved { devices, ir_length } => write!(
+                f,
+                "cannot split {} instruction register bits between {} JTAG devices",
+                ir_length, devices
+            ),
+            Xds110Error::SwjSequenceLength { bits, len } => write!(
+                f,
+                "an SWJ sequence of {} bits needs {} bytes of data, not {}",
+                bits,
+                bits.div_ceil(8),
+                len
+            ),
+            Xds110Error::InvalidDapRegister(register) => write!(
+                f,
+                "{:#04x} is not a DAP register address, which are 0x0, 0x4, 0x8 and 0xc",
+                register
+            ),
+            Xds110Error::SwdTransferFailed(ack) => write!(f, "SWD transfer failed: {}", ack),
+            Xds110Error::DfuSuffixCrcMismatch { expected, actual } => write!(
+                f,
+                "the DFU suffix CRC is {:#010x} but the file has {:#010x}",
+                expected, actual
+            ),
+            Xds110Error::DfuSuffixMismatch { vendor, product } => write!(
+                f,
+                "the firmware file is for device {:04x}:{:04x}, not an XDS110",
+                vendor, product
+            ),
+            Xds110Error::FlashReadFailed { address, source } => {
+                write!(f, "reading flash stopped at {:#010x}: {}", address, source)
+            }
+            Xds110Error::Stalled { operation, status } => write!(
+                f,
+                "the bootloader refused {} ({}, status {})",
+                operation,
+                crate::dfu::status_name(*status),
+                status
+            ),
+            Xds110Error::TivaQueryUnsupported => {
+                write!(f, "this bootloader doesn't support the Tiva protocol query")
+            }
+            Xds110Error::UnexpectedState { state, status } => write!(
+                f,
+                "the bootloader is in {:?} with status {}, expected an idle bootloader",
+                state, status
+            ),
+            Xds110Error::UploadEnded { received, expected } => write!(
+                f,
+                "the bootloader stopped sending after {} of {} bytes",
+                received, expected
+            ),
+            Xds110Error::NotTivaProtocol(response) => write!(
+                f,
+                "device does not speak the TI binary DFU protocol (it answered `{}`)",
+                hex(response)
+            ),
+        }
+    }
+}
+
+impl core::error::Error for Xds110Error {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Xds110Error::Io(e) => Some(e),
+            Xds110Error::Transfer { source, .. } => Some(source),
+            Xds110Error::FlashReadFailed { source, .. } => Some(source),
+            Xds110Error::CmsisDapClaimFailed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl From<std::io::Error> for Xds110Error {
+    fn from(value: std::io::Error) -> Self {
+        Xds110Error::Io(value)
+    }
+}
+
+impl From<FirmwareVersionError> for Xds110Error {
+    fn from(value: FirmwareVersionError) -> Self {
+        match value {
+            FirmwareVersionError::TooOld { found, minimum } => {
+                Xds110Error::FirmwareTooOld { found, minimum }
+            }
+            FirmwareVersionError::Io(e) => Xds110Error::Io(e),
+        }
+    }
+}
+
+/// Attaches the operation a failed transfer was part of
+pub(crate) trait TransferContext<T> {
+    fn context(self, operation: &'static str) -> Result<T, Xds110Error>;
+}
+
+impl<T> TransferContext<T> for Result<T, nusb::transfer::TransferError> {
+    fn context(self, operation: &'static str) -> Result<T, Xds110Error> {
+        self.map_err(|source| Xds110Error::Transfer { operation, source })
+    }
+}
+
+impl From<Xds110Error> for std::io::Error {
+    fn from(value: Xds110Error) -> Self {
+        match value {
+            Xds110Error::Io(e) => e,
+            other => std::io::Error::other(other),
+        }
+    }
+}
@@ -0,0 +1,370 @@
+//! C bindings for the mode switch, built into the cdylib with the `ffi`
+//! feature. See `ffi/turbo110.h` for the matching header.
+//!
+//! Only plain C types cross the boundary. Every function returns a
+//! [`Turbo110Status`], and the message describing the last failure on the
+//! calling thread is available from [`turbo110_last_error`].
+//!
+//! The `ffi-mock` feature adds functions that plug scripted probes in, for
+//! the C test in `ffi/test.c`. A library built with it finds only those.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char};
+use std::sync::Arc;
+
+use crate::bus::{Bus, UsbBus};
+#[cfg(any(test, feature = "ffi-mock"))]
+use crate::mock::{MockBus, MockFirmware, MockProbe};
+use crate::{
+    ProbeInfo, ProbeMode, ProbeState, SwitchOptions, Xds110Error, Xds110Probe, switch_mode,
+};
+
+/// Result codes. These values are part of the ABI and never change.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Turbo110Status {
+    Ok = 0,
+    /// No matching probe is attached
+    NotFound = 1,
+    /// A pointer was NULL or a string was not valid UTF-8
+    InvalidArgument = 2,
+    /// Opening or talking to the probe failed
+    Usb = 3,
+    /// A control transfer to the bootloader failed
+    Transfer = 4,
+    /// The firmware is too old for the requested mode
+    FirmwareTooOld = 5,
+    /// The probe stopped responding or did not re-enumerate
+    Timeout = 6,
+    /// The probe sent back something unexpected
+    Protocol = 7,
+    Other = 255,
+}
+
+/// An attached probe, as reported by [`turbo110_list`]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Turbo110Probe {
+    pub vid: u16,
+    pub pid: u16,
+    pub bus: u8,
+    pub address: u8,
+    /// Nonzero if the probe is in the DFU bootloader
+    pub dfu: u8,
+    /// NUL-terminated, empty if the probe has no serial number. Serial
+    /// numbers longer than 63 bytes are truncated.
+    pub serial: [c_char; 64],
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+    /// Where probes are looked for in place of the USB bus, once one has
+    /// been plugged in
+    #[cfg(any(test, feature = "ffi-mock"))]
+    static MOCK_BUS: RefCell<Option<MockBus>> = const { RefCell::new(None) };
+}
+
+/// The bus probes are looked for on
+fn bus() -> Arc<dyn Bus> {
+    #[cfg(any(test, feature = "ffi-mock"))]
+    if let Some(bus) = MOCK_BUS.with(|bus| bus.borrow().clone()) {
+        return Arc::new(bus);
+    }
+    Arc::new(UsbBus)
+}
+
+/// Remember `message` for [`turbo110_last_error`] and return `status`
+fn fail(status: Turbo110Status, message: String) -> Turbo110Status {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    status
+}
+
+fn status_of(error: &Xds110Error) -> Turbo110Status {
+    match error {
+        Xds110Error::Io(e) => match e.kind() {
+            std::io::ErrorKind::NotFound => Turbo110Status::NotFound,
+            std::io::ErrorKind::TimedOut => Turbo110Status::Timeout,
+            _ => Turbo110Status::Usb,
+        },
+        Xds110Error::CmsisDapClaimFailed(_) => Turbo110Status::Usb,
+        Xds110Error::Transfer { .. } => Turbo110Status::Transfer,
+        Xds110Error::FirmwareTooOld { .. } => Turbo110Status::FirmwareTooOld,
+        Xds110Error::FlashReadFailed { source, .. } => status_of(source),
+        Xds110Error::ReenumerationTimeout
+        | Xds110Error::DeviceHung { .. }
+        | Xds110Error::WaitTimeout { .. }
+        | Xds110Error::ResetTimeout { .. } => Turbo110Status::Timeout,
+        Xds110Error::InvalidDfuResponse(_)
+        | Xds110Error::BootloaderError { .. }
+        | Xds110Error::InvalidResponse { .. }
+        | Xds110Error::CommandFailed { .. }
+        | Xds110Error::CmsisDapInvalidResponse { .. }
+        | Xds110Error::CmsisDapCommandFailed { .. }
+        | Xds110Error::SwdTransferFailed(_) => Turbo110Status::Protocol,
+        _ => Turbo110Status::Other,
+    }
+}
+
+/// `s` as a string, or `None` if it is NULL or not valid UTF-8
+///
+/// # Safety
+/// `s` must be NULL or a NUL-terminated string.
+#[cfg(feature = "ffi-mock")]
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    match s.is_null() {
+        true => None,
+        false => unsafe { CStr::from_ptr(s) }.to_str().ok(),
+    }
+}
+
+/// Copy `s` into `buf` as a NUL-terminated string, truncating if needed
+///
+/// # Safety
+/// `buf` must be NULL or point to `len` writable bytes.
+unsafe fn copy_string(s: &str, buf: *mut c_char, len: usize) {
+    if buf.is_null() || len == 0 {
+        return;
+    }
+    let n = s.len().min(len - 1);
+    unsafe {
+        std::ptr::copy_nonoverlapping(s.as_ptr().cast(), buf, n);
+        *buf.add(n) = 0;
+    }
+}
+
+/// Open the probe on `bus` with serial number `serial`, or any probe if it
+/// is `None`
+fn open(bus: &dyn Bus, serial: Option<&str>) -> Result<Xds110Probe, Xds110Error> {
+    let matches = |device: &ProbeInfo| serial.is_none_or(|s| device.serial.as_deref() == Some(s));
+    match bus.open_dfu(&matches) {
+        Ok(dfu) => Ok(Xds110Probe::Dfu(dfu)),
+        Err(_) => Ok(Xds110Probe::Runtime(bus.open_xds110(&matches)?)),
+    }
+}
+
+/// List attached probes. Up to `capacity` entries are written to `out`, and
+/// the number of probes found is stored in `out_count`, which may exceed
+/// `capacity`.
+///
+/// # Safety
+/// `out` must point to `capacity` writable entries, or may be NULL if
+/// `capacity` is 0. `out_count` must be a valid pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn turbo110_list(
+    out: *mut Turbo110Probe,
+    capacity: usize,
+    out_count: *mut usize,
+) -> Turbo110Status {
+    if out_count.is_null() || (out.is_null() && capacity != 0) {
+        return fail(Turbo110Status::InvalidArgument, "NULL argument".into());
+    }
+    let probes = match bus().list() {
+        Ok(probes) => probes,
+        Err(e) => {
+            let e = Xds110Error::from(e);
+            return fail(status_of(&e), e.to_string());
+        }
+    };
+    for (i, info) in probes.iter().take(capacity).enumerate() {
+        let mut probe = Turbo110Probe {
+            vid: info.vid,
+            pid: info.pid,
+            bus: info.bus,
+            address: info.address,
+            dfu: (info.state == ProbeState::Dfu) as u8,
+            serial: [0; 64],
+        };
+        unsafe {
+            copy_string(
+                info.serial.as_deref().unwrap_or(""),
+                probe.serial.as_mut_ptr(),
+                probe.serial.len(),
+            );
+            out.add(i).write(probe);
+        }
+    }
+    unsafe { out_count.write(probes.len()) };
+    Turbo110Status::Ok
+}
+
+/// Switch the probe with serial number `serial` to configuration mode
+/// `mode`, returning once it has re-enumerated. If `serial` is NULL, the
+/// only attached probe is used. On failure, the error message is also
+/// copied into `out_error_buf`, which may be NULL.
+///
+/// # Safety
+/// `serial` must be NULL or a NUL-terminated string. `out_error_buf` must
+/// be NULL or point to `out_error_len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn turbo110_switch_mode(
+    serial: *const c_char,
+    mode: u16,
+    out_error_buf: *mut c_char,
+    out_error_len: usize,
+) -> Turbo110Status {
+    let serial = match serial.is_null() {
+        true => None,
+        false => match unsafe { CStr::from_ptr(serial) }.to_str() {
+            Ok(serial) => Some(serial),
+            Err(_) => {
+                let message = "serial number is not valid UTF-8";
+                unsafe { copy_string(message, out_error_buf, out_error_len) };
+                return fail(Turbo110Status::InvalidArgument, message.into());
+            }
+        },
+    };
+
+    let result = open(&*bus(), serial)
+        .and_then(|probe| switch_mode(probe, ProbeMode::from(mode), &SwitchOptions::default()));
+    match result {
+        Ok(_) => Turbo110Status::Ok,
+        Err(e) => {
+            let message = e.to_string();
+            unsafe { copy_string(&message, out_error_buf, out_error_len) };
+            fail(status_of(&e), message)
+        }
+    }
+}
+
+/// The message for the last failure on this thread, or NULL if nothing has
+/// failed yet. The string stays valid until the next failing call on the
+/// same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn turbo110_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Plug a scripted probe with serial number `serial` in, running firmware
+/// `firmware_version`, where this thread looks for probes from now on. USB
+/// is then no longer looked at. Only in builds with the `ffi-mock` feature.
+///
+/// # Safety
+/// `serial` must be a NUL-terminated string.
+#[cfg(feature = "ffi-mock")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn turbo110_mock_plug_in(
+    serial: *const c_char,
+    firmware_version: u32,
+) -> Turbo110Status {
+    let Some(serial) = (unsafe { to_str(serial) }) else {
+        return fail(
+            Turbo110Status::InvalidArgument,
+            "invalid serial number".into(),
+        );
+    };
+    let probe = MockProbe {
+        firmware: MockFirmware::xds110(firmware_version, 0),
+        ..MockProbe::new(serial, ProbeState::Runtime)
+    };
+    MOCK_BUS.with(|bus| bus.borrow_mut().get_or_insert_default().plug_in(probe));
+    Turbo110Status::Ok
+}
+
+/// The mode the configuration of the scripted probe with serial number
+/// `serial` is set to, or -1 if no such probe was plugged in. Only in builds
+/// with the `ffi-mock` feature.
+///
+/// # Safety
+/// `serial` must be a NUL-terminated string.
+#[cfg(feature = "ffi-mock")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn turbo110_mock_mode(serial: *const c_char) -> i32 {
+    let probe = unsafe { to_str(serial) }
+        .and_then(|serial| MOCK_BUS.with(|bus| bus.borrow().as_ref()?.probe(serial)));
+    match probe {
+        Some(probe) => {
+            let configuration = probe.bootloader.configuration();
+            crate::ConfigurationSummary::parse(&configuration)
+                .mode
+                .number() as i32
+        }
+        None => -1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::MaybeUninit;
+
+    use super::*;
+    use crate::mock::REBOOT_TO_DFU;
+    use crate::{CMSIS_DAP_2_MINIMUM, ConfigurationSummary, MODE_CMSIS_DAP2};
+
+    fn plug_in(probes: Vec<MockProbe>) {
+        MOCK_BUS.with(|mock| *mock.borrow_mut() = Some(MockBus::new(probes)));
+    }
+
+    fn switch_mode(mode: u16, error: &mut [c_char]) -> Turbo110Status {
+        unsafe { turbo110_switch_mode(c"MOCK0001".as_ptr(), mode, error.as_mut_ptr(), error.len()) }
+    }
+
+    #[test]
+    fn list_both_states() {
+        plug_in(vec![
+            MockProbe::new("MOCK0001", ProbeState::Runtime),
+            MockProbe::new("MOCK0002", ProbeState::Dfu),
+        ]);
+
+        let mut probes = [MaybeUninit::<Turbo110Probe>::uninit(); 1];
+        let mut count = 0;
+        let status = unsafe { turbo110_list(probes[0].as_mut_ptr(), 1, &mut count) };
+        assert_eq!(status, Turbo110Status::Ok);
+        assert_eq!(count, 2);
+        let first = unsafe { probes[0].assume_init() };
+        assert_eq!(first.dfu, 0);
+        let serial = unsafe { CStr::from_ptr(first.serial.as_ptr()) };
+        assert_eq!(serial, c"MOCK0001");
+    }
+
+    #[test]
+    fn switch_mode_from_runtime() {
+        let probe = MockProbe::new("MOCK0001", ProbeState::Runtime);
+        plug_in(vec![probe.clone()]);
+
+        let mut error = [0; 128];
+        assert_eq!(switch_mode(MODE_CMSIS_DAP2, &mut error), Turbo110Status::Ok);
+        let summary = ConfigurationSummary::parse(&probe.bootloader.configuration());
+        assert_eq!(summary.mode, ProbeMode::CmsisDap2);
+        assert_eq!(probe.state(), ProbeState::Runtime);
+        assert_eq!(
+            probe.firmware.written().last(),
+            Some(&(0x02, REBOOT_TO_DFU.to_vec()))
+        );
+    }
+
+    #[test]
+    fn switch_mode_reports_old_firmware() {
+        let probe = MockProbe {
+            firmware: MockFirmware::xds110(CMSIS_DAP_2_MINIMUM - 1, 0),
+            ..MockProbe::new("MOCK0001", ProbeState::Runtime)
+        };
+        plug_in(vec![probe.clone()]);
+
+        let mut error = [0; 128];
+        assert_eq!(
+            switch_mode(MODE_CMSIS_DAP2, &mut error),
+            Turbo110Status::FirmwareTooOld
+        );
+        let copied = unsafe { CStr::from_ptr(error.as_ptr()) };
+        let last = unsafe { CStr::from_ptr(turbo110_last_error()) };
+        assert_eq!(copied, last);
+        assert!(!copied.is_empty());
+        // Nothing past the version query was sent
+        assert_eq!(probe.state(), ProbeState::Runtime);
+    }
+
+    #[test]
+    fn switch_mode_without_a_probe() {
+        plug_in(vec![MockProbe::new("MOCK0002", ProbeState::Runtime)]);
+        let mut error = [0; 128];
+        assert_eq!(
+            switch_mode(MODE_CMSIS_DAP2, &mut error),
+            Turbo110Status::NotFound
+        );
+    }
+}
@@ -0,0 +1,419 @@
+//! Reading and writing the application image.
+
+use std::io::{BufRead, Write};
+use std::ops::Range;
+
+use sha2::{Digest, Sha256};
+
+use crate::dfu;
+use crate::{
+    CONFIGURATION_BLOCK, DFU_BLOCK_SIZE, DfuReader, DfuSuffix, Phase, Warning, Xds110DfuDevice,
+    Xds110Error,
+};
+
+/// How many bytes [`Xds110DfuDevice::read_flash_to`] writes between flushes
+const FLUSH_INTERVAL: usize = 64 * 1024;
+
+/// The image in a firmware file, without its DFU suffix. A suffix naming a
+/// device other than the XDS110 is refused unless `allow_other_device`.
+pub(crate) fn strip_dfu_suffix(
+    file: &[u8],
+    allow_other_device: bool,
+) -> Result<&[u8], Xds110Error> {
+    let (image, suffix) = DfuSuffix::split(file)?;
+    if let Some(suffix) = suffix
+        && !suffix.matches_xds110()
+        && !allow_other_device
+    {
+        return Err(Xds110Error::DfuSuffixMismatch {
+            vendor: suffix.vendor,
+            product: suffix.product,
+        });
+    }
+    Ok(image)
+}
+
+impl Xds110DfuDevice {
+    /// Stream all of flash, from block 0 up to the top reported by
+    /// [`detect_flash_geometry`](Self::detect_flash_geometry), into `output`.
+    /// Returns the number of bytes written.
+    pub fn upload_firmware(
+        &mut self,
+        output: &mut dyn std::io::Write,
+    ) -> Result<usize, Xds110Error> {
+        let total = self.detect_flash_geometry()?.flash_top;
+
+        let mut reader = DfuReader::new(self, 0, total)?;
+        let mut written = 0;
+        loop {
+            let chunk = reader.fill()?;
+            if chunk.is_empty() {
+                return Ok(written);
+            }
+            output.write_all(chunk)?;
+            let n = chunk.len();
+            written += n;
+            reader.consume(n);
+        }
+    }
+
+    /// Stream the flash addresses in `range` into `writer`, holding no more
+    /// than one upload in memory. The range need not start or end on a block
+    /// boundary. Returns the number of bytes written.
+    ///
+    /// Should the read fail part way, everything before the failure has
+    /// been written and flushed, and the error is
+    /// [`Xds110Error::FlashReadFailed`] with the address reached.
+    pub fn read_flash_to(
+        &mut self,
+        writer: &mut impl Write,
+        range: Range<u32>,
+    ) -> Result<usize, Xds110Error> {
+        let len = range.end.saturating_sub(range.start);
+        self.check_flash_range(range.start, len)?;
+        let block = (range.start / DFU_BLOCK_SIZE) as u16;
+        let mut skip = (range.start % DFU_BLOCK_SIZE) as usize;
+        self.recover()?;
+
+        let mut written = 0;
+        let mut unflushed = 0;
+        let mut copy = || -> Result<(), Xds110Error> {
+            let mut reader = DfuReader::with_progress(
+                self,
+                block,
+                skip as u32 + len,
+                Some(|done, total| Phase::ReadingFlash { done, total }),
+            )?;
+            loop {
+                let chunk = reader.fill()?;
+                if chunk.is_empty() {
+                    return Ok(());
+                }
+                let n = chunk.len();
+                let data = &chunk[skip.min(n)..];
+                skip = skip.saturating_sub(n);
+                writer.write_all(data)?;
+                written += data.len();
+                unflushed += data.len();
+                reader.consume(n);
+                if unflushed >= FLUSH_INTERVAL {
+                    writer.flush()?;
+                    unflushed = 0;
+                }
+            }
+        };
+        let result = copy().and_then(|()| Ok(writer.flush()?));
+        if let Err(e) = result {
+            // Leave the output ending cleanly at the failure
+            let _ = writer.flush();
+            return Err(Xds110Error::FlashReadFailed {
+                address: range.start + written as u32,
+                source: Box::new(e),
+            });
+        }
+        Ok(written)
+    }
+
+    /// Write `image` to flash at the application start address. The image
+    /// must fit between the bootloader and the configuration block.
+    ///
+    /// If `expected_sha256` is given, the image is hashed first and nothing
+    /// is sent to the probe unless it matches.
+    pub fn flash_firmware(
+        &mut self,
+        image: &[u8],
+        expected_sha256: Option<[u8; 32]>,
+    ) -> Result<(), Xds110Error> {
+        if let Some(expected) = expected_sha256 {
+            let actual: [u8; 32] = Sha256::digest(image).into();
+            if actual != expected {
+                return Err(Xds110Error::ChecksumMismatch { expected, actual });
+            }
+        }
+
+        let (block, _) = self.application_region(image)?;
+        self.write_blocks(block, image, |done, total| Phase::WritingFirmware {
+            done,
+            total,
+        })?;
+        Ok(())
+    }
+
+    /// Replace the application firmware with `image` and read it back to
+    /// verify it. The whole application region is erased first, so nothing
+    /// of a longer old image is left behind the new one. The bootloader and
+    /// the configuration are never touched.
+    ///
+    /// [`reset`](Self::reset) starts the new firmware afterwards.
+    pub fn program_firmware(&mut self, image: &[u8]) -> Result<(), Xds110Error> {
+        let (block, num_blocks) = self.application_region(image)?;
+        self.erase_region(block, num_blocks)?;
+        self.write_region(block, image)?;
+        self.verify_region(block, image)
+    }
+
+    /// Erase the whole application region, between the bootloader and the
+    /// configuration, as the start of recovering a probe whose firmware is
+    /// beyond repair. The bootloader and the configuration are never
+    /// touched. The probe has no firmware to start until a new image is
+    /// programmed.
+    pub fn mass_erase(&mut self) -> Result<(), Xds110Error> {
+        let (block, num_blocks) = self.application_region(&[])?;
+        self.erase_region(block, num_blocks)
+    }
+
+    /// Erase the blocks `image` covers from `start_block` on, write it there
+    /// and read it back to verify it, reporting progress to the observer as
+    /// it goes. The range is checked like for
+    /// [`write_region`](Self::write_region), so the bootloader is safe unless
+    /// unsafe writes are on.
+    ///
+    /// Should any step fail, the bootloader is brought back to `dfuIDLE` on
+    /// a best-effort basis, aborting the transfer or clearing its error, so
+    /// the device can be used again. The error returned is the original one.
+    pub fn download_image(&mut self, image: &[u8], start_block: u16) -> Result<(), Xds110Error> {
+        if image.is_empty() {
+            return Ok(());
+        }
+        let num_blocks = u16::try_from(image.len().div_ceil(DFU_BLOCK_SIZE as usize))
+            .map_err(|_| Xds110Error::UnreachableAddress(start_block as u32 * DFU_BLOCK_SIZE))?;
+        let result = self
+            .check_writable(start_block as u32 * DFU_BLOCK_SIZE, image.len() as u32)
+            .and_then(|()| self.erase_region(start_block, num_blocks))
+            .and_then(|()| self.write_region(start_block, image))
+            .and_then(|()| self.verify_region(start_block, image));
+        if result.is_err() {
+            let _ = self.return_to_idle();
+        }
+        result
+    }
+
+    /// Read `size` bytes of flash from `start_block` on, the counterpart of
+    /// [`download_image`](Self::download_image). This checks the bootloader
+    /// speaks the Tiva binary protocol, sets up the read with the upload
+    /// prefix turned off, uploads the data in chunks and checks the status
+    /// that ends the upload.
+    ///
+    /// Should any step fail, the bootloader is brought back to `dfuIDLE` on
+    /// a best-effort basis and the original error is returned.
+    pub fn read_image(&mut self, size: u32, start_block: u16) -> Result<Vec<u8>, Xds110Error> {
+        let result = self
+            .ensure_binary_protocol()
+            .and_then(|()| self.read_region(start_block, size));
+        if result.is_err() {
+            let _ = self.return_to_idle();
+        }
+        result
+    }
+
+    /// Read back the flash `image` was written to from `block` on and fail
+    /// at the first byte that differs
+    fn verify_region(&mut self, block: u16, image: &[u8]) -> Result<(), Xds110Error> {
+        let written = self.read_region(block, image.len() as u32)?;
+        if let Some(i) = written.iter().zip(image).position(|(a, b)| a != b) {
+            return Err(Xds110Error::FirmwareVerifyFailed {
+                address: block as u32 * DFU_BLOCK_SIZE + i as u32,
+            });
+        }
+        Ok(())
+    }
+
+    /// [`program_firmware`](Self::program_firmware) with the contents of a
+    /// firmware file, which may end in a DFU suffix. The suffix is checked
+    /// and stripped; one that names a device other than the XDS110 fails
+    /// with [`Xds110Error::DfuSuffixMismatch`] unless `allow_other_device`
+    /// is set.
+    ///
+    /// A `DFU_CMD_PROG` prefix in front of the image is stripped as well.
+    /// The image still goes to the application start address, with
+    /// [`Warning::PrefixAddressMismatch`] if the prefix names another.
+    /// Returns the size of the image that was written.
+    pub fn program_firmware_file(
+        &mut self,
+        file: &[u8],
+        allow_other_device: bool,
+    ) -> Result<usize, Xds110Error> {
+        let image = strip_dfu_suffix(file, allow_other_device)?;
+        let image = match dfu::split_prog_prefix(image) {
+            Some((block, image)) => {
+                let prefix = block as u32 * DFU_BLOCK_SIZE;
+                let used = self.detect_flash_geometry()?.app_start;
+                if prefix != used {
+                    self.warn(&Warning::PrefixAddressMismatch { prefix, used });
+                }
+                image
+            }
+            None => image,
+        };
+        self.program_firmware(image)?;
+        Ok(image.len())
+    }
+
+    /// The first block and the number of blocks between the bootloader and
+    /// the configuration, failing if `image` does not fit in them
+    fn application_region(&mut self, image: &[u8]) -> Result<(u16, u16), Xds110Error> {
+        let geometry = self.detect_flash_geometry()?;
+        let capacity =
+            (CONFIGURATION_BLOCK as u32 * DFU_BLOCK_SIZE).saturating_sub(geometry.app_start);
+        if image.len() > capacity as usize {
+            return Err(Xds110Error::ImageTooLarge {
+                size: image.len(),
+                capacity: capacity as usize,
+            });
+        }
+        Ok((
+            (geometry.app_start / DFU_BLOCK_SIZE) as u16,
+            (capacity / DFU_BLOCK_SIZE) as u16,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::dfu::DFU_CMD_WRITE;
+    use crate::mock::{self, MockBootloader, Warnings};
+
+    /// An image that is not all one byte, so a shift shows
+    fn image() -> Vec<u8> {
+        (0..3000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// `image` behind a `DFU_CMD_PROG` header naming `block` and `len`
+    fn prefixed(block: u16, len: u32, image: &[u8]) -> Vec<u8> {
+        let mut file = vec![DFU_CMD_WRITE, 0];
+        file.extend_from_slice(&block.to_le_bytes());
+        file.extend_from_slice(&len.to_le_bytes());
+        file.extend_from_slice(image);
+        file
+    }
+
+    /// What the application region starts with, `len` bytes of it
+    fn application(mock: &MockBootloader, len: usize) -> Vec<u8> {
+        let start = mock::APP_START as usize;
+        mock.lock().flash[start..start + len].to_vec()
+    }
+
+    #[test]
+    fn prog_prefix_is_split_off() {
+        let image = image();
+        let file = prefixed(0x10, image.len() as u32, &image);
+        assert_eq!(dfu::split_prog_prefix(&file), Some((0x10, &image[..])));
+
+        // A header for a different length, one for another command, and
+        // anything too short for a header at all are left alone
+        let file = prefixed(0x10, image.len() as u32 + 1, &image);
+        assert_eq!(dfu::split_prog_prefix(&file), None);
+        let mut file = prefixed(0x10, image.len() as u32, &image);
+        file[0] = 0x02;
+        assert_eq!(dfu::split_prog_prefix(&file), None);
+        assert_eq!(dfu::split_prog_prefix(&file[..7]), None);
+    }
+
+    #[test]
+    fn prefixed_file_lands_at_the_application_start() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        let warnings = Arc::new(Warnings::default());
+        dfu.set_observer(warnings.clone());
+        let image = image();
+        let block = (mock::APP_START / DFU_BLOCK_SIZE) as u16;
+
+        let file = prefixed(block, image.len() as u32, &image);
+        assert_eq!(
+            dfu.program_firmware_file(&file, false).unwrap(),
+            image.len()
+        );
+        assert_eq!(application(&mock, image.len()), image);
+        assert_eq!(warnings.seen(), []);
+    }
+
+    #[test]
+    fn prefix_naming_another_address_is_warned_about() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        let warnings = Arc::new(Warnings::default());
+        dfu.set_observer(warnings.clone());
+        let image = image();
+
+        let file = prefixed(0x40, image.len() as u32, &image);
+        assert_eq!(
+            dfu.program_firmware_file(&file, false).unwrap(),
+            image.len()
+        );
+        // Written where the bootloader starts the application all the same
+        assert_eq!(application(&mock, image.len()), image);
+        assert_eq!(
+            warnings.seen(),
+            [Warning::PrefixAddressMismatch {
+                prefix: 0x40 * DFU_BLOCK_SIZE,
+                used: mock::APP_START,
+            }]
+        );
+    }
+
+    #[test]
+    fn file_without_a_prefix_is_written_whole() {
+        let image = image();
+        let block = (mock::APP_START / DFU_BLOCK_SIZE) as u16;
+        for file in [
+            image.clone(),
+            prefixed(block, image.len() as u32 - 1, &image),
+        ] {
+            let mock = MockBootloader::new();
+            let mut dfu = mock.open();
+            let warnings = Arc::new(Warnings::default());
+            dfu.set_observer(warnings.clone());
+
+            assert_eq!(dfu.program_firmware_file(&file, false).unwrap(), file.len());
+            assert_eq!(application(&mock, file.len()), file);
+            assert_eq!(warnings.seen(), []);
+        }
+    }
+
+    #[test]
+    fn checksum_mismatch_sends_nothing() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        let image = image();
+        let actual: [u8; 32] = Sha256::digest(&image).into();
+        let mut expected = actual;
+        expected[31] ^= 1;
+        mock.lock().requests.clear();
+
+        let result = dfu.flash_firmware(&image, Some(expected));
+        match result {
+            Err(Xds110Error::ChecksumMismatch {
+                expected: e,
+                actual: a,
+            }) => {
+                assert_eq!(e, expected);
+                assert_eq!(a, actual);
+            }
+            other => panic!("{:?}", other),
+        }
+        let requests = mock.lock().requests.clone();
+        assert!(
+            !requests
+                .iter()
+                .any(|&(request, _)| request == dfu::DFU_DNLOAD)
+        );
+        assert_eq!(
+            application(&mock, image.len()),
+            [mock::APPLICATION_FILL; 3000]
+        );
+    }
+
+    #[test]
+    fn matching_checksum_is_flashed() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        let image = image();
+        let sha256: [u8; 32] = Sha256::digest(&image).into();
+
+        dfu.flash_firmware(&image, Some(sha256)).unwrap();
+        assert_eq!(application(&mock, image.len()), image);
+    }
+}
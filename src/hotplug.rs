@@ -0,0 +1,87 @@
+//! Noticing probes being plugged in and unplugged.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::Context;
+use std::time::Duration;
+
+use futures_core::Stream;
+use nusb::DeviceId;
+use nusb::hotplug::{HotplugEvent, HotplugWatch};
+
+use crate::ProbeInfo;
+use crate::runtime::{block_on, block_on_timeout};
+
+/// A probe coming or going, see [`watch_probes`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeEvent {
+    Connected(ProbeInfo),
+    /// Carries the probe as it was when it connected
+    Disconnected(ProbeInfo),
+}
+
+/// Reports XDS110s in either state as they are plugged in and unplugged.
+/// Iterating blocks until the next event.
+///
+/// A probe that switches mode drops off the bus and comes back, so it shows
+/// up as a disconnection followed by a connection in the other state.
+pub struct ProbeWatcher {
+    watch: HotplugWatch,
+    /// Every probe attached right now, as nusb only reports the ID of a
+    /// device that went away
+    attached: HashMap<DeviceId, ProbeInfo>,
+}
+
+/// Start watching for probes. Probes that are attached already are not
+/// reported as connected, get them from [`list_probes`](crate::list_probes).
+pub fn watch_probes() -> Result<ProbeWatcher, std::io::Error> {
+    // Watch before listing so nothing attached in between is missed
+    let watch = nusb::watch_devices()?;
+    let attached = nusb::list_devices()?
+        .filter_map(|device| Some((device.id(), ProbeInfo::from_device(&device)?)))
+        .collect();
+    Ok(ProbeWatcher { watch, attached })
+}
+
+impl ProbeWatcher {
+    /// Like [`Iterator::next`], but gives up with `None` after `timeout`
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<ProbeEvent> {
+        block_on_timeout(self.next_event(), timeout)
+    }
+
+    /// The next event that concerns a probe, skipping all other devices
+    async fn next_event(&mut self) -> ProbeEvent {
+        loop {
+            let event = std::future::poll_fn(|cx: &mut Context<'_>| {
+                Pin::new(&mut self.watch).poll_next(cx)
+            })
+            .await;
+            match event {
+                Some(HotplugEvent::Connected(device)) => {
+                    let Some(probe) = ProbeInfo::from_device(&device) else {
+                        continue;
+                    };
+                    // The listing may have picked it up already
+                    if self.attached.insert(device.id(), probe.clone()).is_none() {
+                        return ProbeEvent::Connected(probe);
+                    }
+                }
+                Some(HotplugEvent::Disconnected(id)) => {
+                    if let Some(probe) = self.attached.remove(&id) {
+                        return ProbeEvent::Disconnected(probe);
+                    }
+                }
+                // nusb's watch never ends
+                None => std::future::pending::<()>().await,
+            }
+        }
+    }
+}
+
+impl Iterator for ProbeWatcher {
+    type Item = ProbeEvent;
+
+    fn next(&mut self) -> Option<ProbeEvent> {
+        Some(block_on(self.next_event()))
+    }
+}
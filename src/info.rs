@@ -0,0 +1,72 @@
+use crate::{
+    ProbeState, XDS110_DFU_DEVICES, XDS110_USB_DEVICES, Xds110Error, Xds110Probe, usb_util,
+};
+
+/// An attached probe, as seen without opening it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ProbeInfo {
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::serde_util::hex_u16")
+    )]
+    pub vid: u16,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::serde_util::hex_u16")
+    )]
+    pub pid: u16,
+    pub serial: Option<String>,
+    pub bus: u8,
+    pub address: u8,
+    /// Hub ports from the root hub to the probe, where the platform reports them
+    pub port_chain: Option<Vec<u8>>,
+    pub state: ProbeState,
+}
+
+impl ProbeInfo {
+    /// Describe `device` if it is an XDS110 in either state
+    pub(crate) fn from_device(device: &nusb::DeviceInfo) -> Option<Self> {
+        let ids = (device.vendor_id(), device.product_id());
+        if XDS110_USB_DEVICES.iter().any(|x| (x.vid, x.pid) == ids) {
+            Some(ProbeInfo::new(device, ProbeState::Runtime))
+        } else if XDS110_DFU_DEVICES.iter().any(|x| (x.vid, x.pid) == ids) {
+            Some(ProbeInfo::new(device, ProbeState::Dfu))
+        } else {
+            None
+        }
+    }
+
+    fn new(device: &nusb::DeviceInfo, state: ProbeState) -> Self {
+        ProbeInfo {
+            vid: device.vendor_id(),
+            pid: device.product_id(),
+            serial: device.serial_number().map(str::to_owned),
+            bus: device.bus_number(),
+            address: device.device_address(),
+            port_chain: usb_util::port_chain(device),
+            state,
+        }
+    }
+
+    /// Open this probe, for instance after picking it from
+    /// [`list_probes`]. Fails with [`std::io::ErrorKind::NotFound`] if it has
+    /// gone away or changed state since it was listed.
+    pub fn open(&self) -> Result<Xds110Probe, Xds110Error> {
+        let device = nusb::list_devices()?
+            .find(|device| {
+                device.bus_number() == self.bus
+                    && device.device_address() == self.address
+                    && (device.vendor_id(), device.product_id()) == (self.vid, self.pid)
+            })
+            .ok_or(std::io::Error::from(std::io::ErrorKind::NotFound))?;
+        Xds110Probe::from_device_info(device)
+    }
+}
+
+/// List every attached XDS110, in either state
+pub fn list_probes() -> Result<Vec<ProbeInfo>, std::io::Error> {
+    Ok(nusb::list_devices()?
+        .filter_map(|device| ProbeInfo::from_device(&device))
+        .collect())
+}
@@ -0,0 +1,526 @@
+//! Scanning the JTAG chain behind a probe in CMSIS-DAP mode.
+//!
+//! The scan only relies on what IEEE 1149.1 requires of every device: in
+//! Test-Logic-Reset each data register holds either a 32-bit IDCODE, which
+//! always has bit 0 set, or the single 0 bit of BYPASS, and Capture-IR loads
+//! `01` into the low bits of each instruction register.
+
+use crate::cmsis_dap::DAP_OK;
+use crate::{CmsisDapPort, Xds110Error, Xds110UsbDevice};
+
+const DAP_JTAG_SEQUENCE: u8 = 0x14;
+
+/// The most TCK cycles a single `DAP_JTAG_Sequence` entry can describe
+const MAX_SEQUENCE_BITS: usize = 64;
+/// The probe's CMSIS-DAP packet size, which both a command and its
+/// response have to fit
+const PACKET_SIZE: usize = 64;
+
+/// Chains longer than this are taken to be broken
+const MAX_DEVICES: usize = 16;
+/// Enough data register bits for the longest chain plus the all-ones word
+/// that marks its end
+const DR_SCAN_BITS: usize = (MAX_DEVICES + 1) * 32;
+/// Enough instruction register bits for the longest chain
+const IR_SCAN_BITS: usize = 512;
+
+/// A device found by [`Xds110UsbDevice::jtag_scan`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JtagDevice {
+    /// The IDCODE, or 0 for a device that has none and comes up in BYPASS
+    pub idcode: u32,
+    /// The length of the instruction register in bits
+    pub ir_length: u8,
+}
+
+/// One entry of `DAP_JTAG_Sequence`: `bits` TCK cycles with TMS and TDI held
+/// at the same level throughout
+#[derive(Clone, Copy)]
+struct Sequence {
+    bits: usize,
+    tms: bool,
+    tdi: bool,
+    capture: bool,
+}
+
+impl Sequence {
+    /// Walk the TAP state machine without looking at TDO
+    fn tms(tms: bool, bits: usize) -> Self {
+        Sequence {
+            bits,
+            tms,
+            tdi: true,
+            capture: false,
+        }
+    }
+
+    /// Shift `bits` copies of `tdi` into the selected register and capture
+    /// what comes out, staying in the Shift state
+    fn shift(tdi: bool, bits: usize) -> Self {
+        Sequence {
+            bits,
+            tms: false,
+            tdi,
+            capture: true,
+        }
+    }
+}
+
+impl Xds110UsbDevice {
+    /// Detect the devices on the JTAG chain from their IDCODE and
+    /// instruction register lengths. The devices are listed in the order
+    /// their bits come out, starting with the one nearest TDO.
+    ///
+    /// This needs the probe in CMSIS-DAP mode. It connects the JTAG port,
+    /// leaves every device in Test-Logic-Reset and disconnects again.
+    pub fn jtag_scan(&self) -> Result<Vec<JtagDevice>, Xds110Error> {
+        self.cmsis_dap_connect(CmsisDapPort::Jtag)?;
+        let devices = self.scan_chain();
+        // A failed scan is the more useful error to report
+        let disconnected = self.cmsis_dap_disconnect();
+        let devices = devices?;
+        disconnected?;
+        Ok(devices)
+    }
+
+    fn scan_chain(&self) -> Result<Vec<JtagDevice>, Xds110Error> {
+        // Test-Logic-Reset, then Run-Test/Idle, Select-DR-Scan, Capture-DR
+        // and Shift-DR
+        let dr = self.jtag_sequence(&[
+            Sequence::tms(true, 5),
+            Sequence::tms(false, 1),
+            Sequence::tms(true, 1),
+            Sequence::tms(false, 2),
+            Sequence::shift(true, DR_SCAN_BITS),
+        ])?;
+        let idcodes = parse_idcodes(&dr)?;
+
+        // Exit1-DR, Update-DR, Select-DR-Scan, Select-IR-Scan, then
+        // Capture-IR and Shift-IR. Zeros go in first and ones last, so
+        // Update-IR loads BYPASS rather than what is often EXTEST.
+        let ir = self.jtag_sequence(&[
+            Sequence::tms(true, 4),
+            Sequence::tms(false, 2),
+            Sequence::shift(false, IR_SCAN_BITS),
+            Sequence::shift(true, IR_SCAN_BITS),
+            Sequence::tms(true, 6),
+        ])?;
+        let (captured, flushed) = ir.split_at(IR_SCAN_BITS);
+        // The zeros that went in come out once the whole chain has been
+        // shifted through
+        let ir_length = flushed
+            .iter()
+            .position(|&bit| bit)
+            .ok_or(Xds110Error::JtagChainBroken)?;
+        let ir_lengths = split_instruction_registers(&captured[..ir_length], idcodes.len())?;
+
+        Ok(idcodes
+            .into_iter()
+            .zip(ir_lengths)
+            .map(|(idcode, ir_length)| JtagDevice { idcode, ir_length })
+            .collect())
+    }
+
+    /// Run `sequences` and return the TDO bits of those that capture, in
+    /// the order they were clocked out
+    fn jtag_sequence(&self, sequences: &[Sequence]) -> Result<Vec<bool>, Xds110Error> {
+        let chunks: Vec<Sequence> = sequences
+            .iter()
+            .flat_map(|sequence| {
+                (0..sequence.bits)
+                    .step_by(MAX_SEQUENCE_BITS)
+                    .map(move |start| Sequence {
+                        bits: (sequence.bits - start).min(MAX_SEQUENCE_BITS),
+                        ..*sequence
+                    })
+            })
+            .collect();
+
+        let dap = self.cmsis_dap()?;
+        let mut tdo = Vec::new();
+        for command in batches(&chunks) {
+            let mut request = vec![DAP_JTAG_SEQUENCE, command.len() as u8];
+            let mut expected = 2;
+            for sequence in command {
+                // A count of 0 stands for 64
+                let info = (sequence.bits % MAX_SEQUENCE_BITS) as u8
+                    | (sequence.tms as u8) << 6
+                    | (sequence.capture as u8) << 7;
+                request.push(info);
+                let tdi = if sequence.tdi { 0xff } else { 0x00 };
+                request.extend(std::iter::repeat_n(tdi, sequence.bits.div_ceil(8)));
+                if sequence.capture {
+                    expected += sequence.bits.div_ceil(8);
+                }
+            }
+
+            let mut response = [0u8; PACKET_SIZE];
+            let len = dap.command(&request, &mut response)?;
+            if len < expected {
+                return Err(Xds110Error::CmsisDapInvalidResponse {
+                    command: DAP_JTAG_SEQUENCE,
+                });
+            }
+            if response[1] != DAP_OK {
+                return Err(Xds110Error::CmsisDapCommandFailed {
+                    command: DAP_JTAG_SEQUENCE,
+                });
+            }
+
+            let mut data = &response[2..];
+            for sequence in command.iter().filter(|sequence| sequence.capture) {
+                let (bytes, rest) = data.split_at(sequence.bits.div_ceil(8));
+                tdo.extend((0..sequence.bits).map(|bit| bytes[bit / 8] & (1 << (bit % 8)) != 0));
+                data = rest;
+            }
+        }
+        Ok(tdo)
+    }
+}
+
+/// Group `sequences` into `DAP_JTAG_Sequence` commands. Each entry takes its
+/// info byte and its TDI bytes in the request and, if it captures, as many
+/// TDO bytes in the response; both start with two bytes of their own.
+fn batches(sequences: &[Sequence]) -> Vec<&[Sequence]> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let (mut request, mut response) = (2, 2);
+    for (i, sequence) in sequences.iter().enumerate() {
+        let bytes = sequence.bits.div_ceil(8);
+        let captured = if sequence.capture { bytes } else { 0 };
+        if request + 1 + bytes > PACKET_SIZE || response + captured > PACKET_SIZE {
+            batches.push(&sequences[start..i]);
+            start = i;
+            (request, response) = (2, 2);
+        }
+        request += 1 + bytes;
+        response += captured;
+    }
+    if start < sequences.len() {
+        batches.push(&sequences[start..]);
+    }
+    batches
+}
+
+/// Split the data registers read out of Test-Logic-Reset into IDCODEs. The
+/// chain ends where the ones shifted in at TDI come out as an IDCODE of all
+/// ones, which is not a valid one.
+fn parse_idcodes(dr: &[bool]) -> Result<Vec<u32>, Xds110Error> {
+    let mut idcodes = Vec::new();
+    let mut bits = dr;
+    while idcodes.len() <= MAX_DEVICES {
+        match bits {
+            [false, rest @ ..] => {
+                idcodes.push(0);
+                bits = rest;
+            }
+            _ if bits.len() >= 32 => {
+                let (word, rest) = bits.split_at(32);
+                let idcode = word
+                    .iter()
+                    .rev()
+                    .fold(0, |idcode, &bit| idcode << 1 | bit as u32);
+                if idcode == u32::MAX {
+                    return Ok(idcodes);
+                }
+                idcodes.push(idcode);
+                bits = rest;
+            }
+            _ => break,
+        }
+    }
+    // TDO stuck low looks like an endless row of BYPASS devices
+    Err(Xds110Error::JtagChainBroken)
+}
+
+/// Work out the length of each of `devices` instruction registers from what
+/// Capture-IR loaded into them, nearest TDO first. Each register starts with
+/// `1, 0`, which only settles the split when no other captured bits look the
+/// same.
+fn split_instruction_registers(captured: &[bool], devices: usize) -> Result<Vec<u8>, Xds110Error> {
+    let unresolved = || Xds110Error::JtagChainUnresolved {
+        devices,
+        ir_length: captured.len(),
+    };
+    let starts: Vec<usize> = match devices {
+        0 if captured.is_empty() => return Ok(Vec::new()),
+        1 => vec![0],
+        _ => (0..captured.len().saturating_sub(1))
+            .filter(|&i| captured[i] && !captured[i + 1])
+            .collect(),
+    };
+    if starts.len() != devices || starts.first() != Some(&0) {
+        return Err(unresolved());
+    }
+    starts
+        .iter()
+        .zip(starts.iter().skip(1).chain([&captured.len()]))
+        .map(|(start, end)| u8::try_from(end - start).map_err(|_| unresolved()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::mock::MockFirmware;
+
+    const DAP_CONNECT: u8 = 0x02;
+    const DAP_DISCONNECT: u8 = 0x03;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Tap {
+        Reset,
+        Idle,
+        SelectDr,
+        CaptureDr,
+        ShiftDr,
+        Exit1Dr,
+        PauseDr,
+        Exit2Dr,
+        UpdateDr,
+        SelectIr,
+        CaptureIr,
+        ShiftIr,
+        Exit1Ir,
+        PauseIr,
+        Exit2Ir,
+        UpdateIr,
+    }
+
+    impl Tap {
+        fn next(self, tms: bool) -> Tap {
+            use Tap::*;
+            match (self, tms) {
+                (Reset, true) | (SelectIr, true) => Reset,
+                (Reset, false) | (Idle, false) | (UpdateDr, false) | (UpdateIr, false) => Idle,
+                (Idle, true) | (UpdateDr, true) | (UpdateIr, true) => SelectDr,
+                (SelectDr, true) => SelectIr,
+                (SelectDr, false) => CaptureDr,
+                (CaptureDr, false) | (ShiftDr, false) | (Exit2Dr, false) => ShiftDr,
+                (CaptureDr, true) | (ShiftDr, true) => Exit1Dr,
+                (Exit1Dr, false) | (PauseDr, false) => PauseDr,
+                (PauseDr, true) => Exit2Dr,
+                (Exit1Dr, true) | (Exit2Dr, true) => UpdateDr,
+                (SelectIr, false) => CaptureIr,
+                (CaptureIr, false) | (ShiftIr, false) | (Exit2Ir, false) => ShiftIr,
+                (CaptureIr, true) | (ShiftIr, true) => Exit1Ir,
+                (Exit1Ir, false) | (PauseIr, false) => PauseIr,
+                (PauseIr, true) => Exit2Ir,
+                (Exit1Ir, true) | (Exit2Ir, true) => UpdateIr,
+            }
+        }
+    }
+
+    /// A JTAG chain, nearest TDO first, clocked through `DAP_JTAG_Sequence`.
+    /// Only Test-Logic-Reset selects IDCODE; after an IR update every
+    /// device is in BYPASS.
+    struct Chain {
+        /// The IDCODE, if any, and the bits Capture-IR loads, LSB first
+        devices: Vec<(Option<u32>, Vec<bool>)>,
+        state: Tap,
+        idcode_selected: bool,
+        shifting: VecDeque<bool>,
+        /// TDO reads 0 whatever is shifted
+        stuck_low: bool,
+    }
+
+    impl Chain {
+        fn new(devices: Vec<(Option<u32>, Vec<bool>)>) -> Self {
+            Chain {
+                devices,
+                state: Tap::Reset,
+                idcode_selected: true,
+                shifting: VecDeque::new(),
+                stuck_low: false,
+            }
+        }
+
+        /// One TCK cycle, returning TDO
+        fn clock(&mut self, tms: bool, tdi: bool) -> bool {
+            let mut tdo = false;
+            match self.state {
+                Tap::CaptureDr => {
+                    let idcode = self.idcode_selected;
+                    self.shifting = self
+                        .devices
+                        .iter()
+                        .flat_map(|(code, _)| match code {
+                            Some(code) if idcode => {
+                                (0..32).map(|bit| code >> bit & 1 != 0).collect()
+                            }
+                            _ => vec![false],
+                        })
+                        .collect();
+                }
+                Tap::CaptureIr => {
+                    self.shifting = self.devices.iter().flat_map(|(_, ir)| ir.clone()).collect();
+                }
+                Tap::ShiftDr | Tap::ShiftIr => {
+                    tdo = self.shifting.pop_front().unwrap() && !self.stuck_low;
+                    self.shifting.push_back(tdi);
+                }
+                Tap::UpdateIr => self.idcode_selected = false,
+                _ => {}
+            }
+            self.state = self.state.next(tms);
+            if self.state == Tap::Reset {
+                self.idcode_selected = true;
+            }
+            tdo
+        }
+
+        /// Carry out a `DAP_JTAG_Sequence` command
+        fn sequence(&mut self, request: &[u8]) -> Vec<u8> {
+            let mut response = vec![DAP_JTAG_SEQUENCE, DAP_OK];
+            let mut at = 2;
+            for _ in 0..request[1] {
+                let info = request[at];
+                let bits = match (info & 0x3f) as usize {
+                    0 => MAX_SEQUENCE_BITS,
+                    bits => bits,
+                };
+                let tdi = &request[at + 1..at + 1 + bits.div_ceil(8)];
+                at += 1 + tdi.len();
+                let mut captured = vec![0u8; bits.div_ceil(8)];
+                for bit in 0..bits {
+                    if self.clock(info & 0x40 != 0, tdi[bit / 8] >> (bit % 8) & 1 != 0) {
+                        captured[bit / 8] |= 1 << (bit % 8);
+                    }
+                }
+                if info & 0x80 != 0 {
+                    response.extend(captured);
+                }
+            }
+            assert_eq!(at, request.len());
+            response
+        }
+    }
+
+    /// A probe with `chain` behind its CMSIS-DAP interface
+    fn probe(chain: &Arc<Mutex<Chain>>) -> (Xds110UsbDevice, MockFirmware) {
+        let chain = chain.clone();
+        let dap = MockFirmware::new(move |_, request| {
+            Some(match request[0] {
+                DAP_CONNECT => vec![DAP_CONNECT, request[1]],
+                DAP_DISCONNECT => vec![DAP_DISCONNECT, DAP_OK],
+                DAP_JTAG_SEQUENCE => chain.lock().unwrap().sequence(request),
+                command => panic!("unexpected command {:#04x}", command),
+            })
+        });
+        (MockFirmware::xds110(0, 0).open_with_cmsis_dap(&dap), dap)
+    }
+
+    /// Capture-IR bits for an instruction register of `len` bits, with the
+    /// mandatory `1, 0` and then `rest`
+    fn ir(len: usize, rest: bool) -> Vec<bool> {
+        let mut bits = vec![true, false];
+        bits.resize(len, rest);
+        bits
+    }
+
+    #[test]
+    fn scan_reads_idcodes_and_instruction_registers() {
+        let chain = Arc::new(Mutex::new(Chain::new(vec![
+            (Some(0x4ba0_0477), ir(4, false)),
+            // No IDCODE, and captured bits after the `1, 0` that are ones
+            (None, ir(6, true)),
+            (Some(0x0b98_c02f), ir(6, false)),
+        ])));
+        let (device, dap) = probe(&chain);
+
+        assert_eq!(
+            device.jtag_scan().unwrap(),
+            [
+                JtagDevice {
+                    idcode: 0x4ba0_0477,
+                    ir_length: 4
+                },
+                JtagDevice {
+                    idcode: 0,
+                    ir_length: 6
+                },
+                JtagDevice {
+                    idcode: 0x0b98_c02f,
+                    ir_length: 6
+                },
+            ]
+        );
+        assert_eq!(chain.lock().unwrap().state, Tap::Reset);
+        let sent = dap.written();
+        assert_eq!(sent[0].1, [DAP_CONNECT, CmsisDapPort::Jtag as u8]);
+        assert_eq!(sent.last().unwrap().1, [DAP_DISCONNECT]);
+    }
+
+    #[test]
+    fn stuck_tdo_is_a_broken_chain() {
+        let mut chain = Chain::new(vec![(Some(0x4ba0_0477), ir(4, false))]);
+        chain.stuck_low = true;
+        let (device, dap) = probe(&Arc::new(Mutex::new(chain)));
+
+        assert!(matches!(
+            device.jtag_scan(),
+            Err(Xds110Error::JtagChainBroken)
+        ));
+        assert_eq!(dap.written().last().unwrap().1, [DAP_DISCONNECT]);
+    }
+
+    #[test]
+    fn ambiguous_instruction_registers_are_unresolved() {
+        // The second register's captured bits hold another `1, 0`
+        let mut second = ir(6, false);
+        second[3] = true;
+        let chain = Chain::new(vec![
+            (Some(0x4ba0_0477), ir(4, false)),
+            (Some(0x0b98_c02f), second),
+        ]);
+        let (device, _) = probe(&Arc::new(Mutex::new(chain)));
+
+        assert!(matches!(
+            device.jtag_scan(),
+            Err(Xds110Error::JtagChainUnresolved {
+                devices: 2,
+                ir_length: 10
+            })
+        ));
+    }
+
+    fn sizes(batch: &[Sequence]) -> (usize, usize) {
+        batch.iter().fold((2, 2), |(request, response), sequence| {
+            let bytes = sequence.bits.div_ceil(8);
+            (
+                request + 1 + bytes,
+                response + if sequence.capture { bytes } else { 0 },
+            )
+        })
+    }
+
+    #[test]
+    fn batches_fit_a_packet() {
+        // Eight full 64-bit shifts used to go out as one 74-byte command
+        let sequences = [Sequence::shift(true, MAX_SEQUENCE_BITS); 16];
+        let batches = batches(&sequences);
+        assert_eq!(
+            batches.iter().map(|batch| batch.len()).collect::<Vec<_>>(),
+            [6, 6, 4]
+        );
+        for batch in &batches {
+            let (request, response) = sizes(batch);
+            assert!(request <= PACKET_SIZE && response <= PACKET_SIZE);
+        }
+    }
+
+    #[test]
+    fn short_sequences_share_a_command() {
+        let sequences = [
+            Sequence::tms(true, 5),
+            Sequence::tms(false, 1),
+            Sequence::tms(true, 1),
+            Sequence::tms(false, 2),
+            Sequence::shift(true, MAX_SEQUENCE_BITS),
+        ];
+        assert_eq!(batches(&sequences).len(), 1);
+    }
+}
@@ -0,0 +1,1270 @@
+//! Switch TI XDS110 debug probes into CMSIS-DAP 2.0 mode.
+//!
+//! A probe is either running its normal firmware ([`Xds110UsbDevice`]) or
+//! sitting in the Tiva DFU bootloader ([`Xds110DfuDevice`]). [`Xds110Probe`]
+//! wraps both states and moves between them.
+
+use bus::{Bus, UsbBus};
+use dfu::{DfuInterface, DfuState, DfuStatus, FlashGeometry, TivaCommand};
+use error::TransferContext;
+use nusb::transfer::Direction;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+mod background;
+mod bus;
+mod cancel;
+mod cmsis_dap;
+mod commands;
+mod config;
+pub mod dfu;
+mod dfu_suffix;
+mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod firmware;
+mod hotplug;
+mod info;
+mod jtag;
+mod memory;
+#[cfg(any(test, feature = "ffi-mock"))]
+#[cfg_attr(not(test), allow(dead_code))]
+mod mock;
+mod mode;
+mod observer;
+mod patch;
+mod probe;
+mod retry;
+mod runtime;
+#[cfg(feature = "serde")]
+mod serde_util;
+mod stream;
+mod swd;
+mod switch;
+mod trace;
+mod transcript;
+mod transport;
+mod usb_util;
+mod version;
+
+pub use background::{OperationEvent, OperationHandle};
+pub use cancel::CancelToken;
+pub use cmsis_dap::CmsisDapPort;
+pub use commands::SupportedCommands;
+pub use config::{
+    ConfigurationSummary, MAGIC_BYTES, MAGIC_OFFSET, MODE_OFFSET, Xds110Configuration,
+    ensure_configuration_version_is_current,
+};
+pub use dfu_suffix::{DFU_SUFFIX_LEN, DfuSuffix};
+pub use error::Xds110Error;
+pub use hotplug::{ProbeEvent, ProbeWatcher, watch_probes};
+pub use info::{ProbeInfo, list_probes};
+pub use jtag::JtagDevice;
+pub use memory::MemoryRegion;
+pub use mode::{MODE_CMSIS_DAP2, ParseModeError, ProbeMode};
+pub use observer::{Observer, Phase, Warning};
+pub use patch::{ConfigPatch, ParsePatchError};
+pub use probe::{ProbeState, Xds110Probe};
+pub use retry::DfuOptions;
+pub use stream::{DfuReader, DfuWriter, TransferStats};
+pub use swd::{DapTransfer, SwdAck};
+pub use switch::{
+    SetModeOutcome, SwitchOptions, SwitchOutcome, convert_dfu_device, erase_flash,
+    flash_firmware_file, patch_config, read_config, recover_probe, rewrite_config, switch_mode,
+    switch_mode_on,
+};
+pub use trace::{StatusTrace, TraceEntry};
+#[cfg(feature = "transcript")]
+pub use transcript::{TranscriptFormat, record_transcript, record_transcript_as, stop_transcript};
+pub use transport::Transport;
+pub use version::{FirmwareVersion, FirmwareVersionError};
+
+/// The first firmware version that supports CMSIS-DAP 2.0 (mode 4).
+pub const CMSIS_DAP_2_MINIMUM: u32 = 0x03_00_00_08;
+/// Size of the configuration block at the top of flash, in bytes.
+pub const CONFIGURATION_SIZE: u16 = 16384;
+/// First 1024-byte flash block of the configuration.
+const CONFIGURATION_BLOCK: u16 = 0x03f0;
+/// Size of the blocks the Tiva commands address flash in.
+const DFU_BLOCK_SIZE: u32 = 1024;
+/// Where the bootloader jumps to leave DFU mode, unless
+/// [`Xds110DfuDevice::set_reset_address`] says otherwise. This lies outside
+/// flash.
+pub const RESET_ADDRESS: u32 = 0x0100_df20;
+/// How long the device may wait after `DFU_DETACH` for the bus reset.
+const DETACH_TIMEOUT_MS: u16 = 1000;
+/// How long [`Xds110DfuDevice`] waits for the bootloader to reach the state a
+/// step needs before failing with [`Xds110Error::WaitTimeout`].
+pub const DFU_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long [`Xds110UsbDevice::firmware_version`] waits for each transfer.
+pub const FIRMWARE_VERSION_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub(crate) struct Xds110DfuDeviceMatch {
+    vid: u16,
+    pid: u16,
+}
+
+pub(crate) const XDS110_DFU_DEVICES: &[Xds110DfuDeviceMatch] = &[Xds110DfuDeviceMatch {
+    vid: 0x1cbe,
+    pid: 0x00ff,
+}];
+
+#[derive(Debug)]
+pub(crate) struct Xds110UsbDeviceMatch {
+    vid: u16,
+    pid: u16,
+    epin: u8,
+    epout: u8,
+    interface: u8,
+}
+
+pub(crate) const XDS110_USB_DEVICES: &[Xds110UsbDeviceMatch] = &[
+    Xds110UsbDeviceMatch {
+        vid: 0x0451,
+        pid: 0xbef3,
+        epin: 0x83,
+        epout: 0x02,
+        interface: 2,
+    },
+    Xds110UsbDeviceMatch {
+        vid: 0x0451,
+        pid: 0xbef4,
+        epin: 0x83,
+        epout: 0x02,
+        interface: 2,
+    },
+    Xds110UsbDeviceMatch {
+        vid: 0x1cbe,
+        pid: 0x02a5,
+        epin: 0x81,
+        epout: 0x01,
+        interface: 0,
+    },
+];
+
+pub struct Xds110UsbDevice {
+    device_handle: Box<dyn Transport>,
+    epout: u8,
+    epin: u8,
+    serial: Option<String>,
+    /// Bus and hub ports, which stay the same across re-enumeration
+    port_path: Option<(u8, Vec<u8>)>,
+    /// Where the probe is looked for when it re-enumerates
+    bus: Arc<dyn Bus>,
+    cmsis_dap: cmsis_dap::LazyCmsisDap,
+}
+
+pub struct Xds110DfuDevice {
+    device_handle: Box<dyn Transport>,
+    /// The interface the class requests are addressed to
+    interface: DfuInterface,
+    /// The `wValue` block number of the next `DFU_DNLOAD` or `DFU_UPLOAD`.
+    /// Every command starts a new transfer and restarts it from 0.
+    packet_count: u16,
+    serial: Option<String>,
+    port_path: Option<(u8, Vec<u8>)>,
+    bus: Arc<dyn Bus>,
+    bus_address: Option<(u8, u8)>,
+    observer: Option<Arc<dyn Observer>>,
+    trace: Option<StatusTrace>,
+    cancel: Option<CancelToken>,
+    wait_timeout: Duration,
+    options: DfuOptions,
+    /// Cached answer of [`detect_flash_geometry`](Self::detect_flash_geometry)
+    geometry: Option<FlashGeometry>,
+    /// Set once the bootloader has been told to go away, or has been
+    /// cleaned up already, so dropping does not talk to it
+    left_bootloader: bool,
+    /// Whether raw writes may touch the bootloader
+    unsafe_writes: bool,
+    /// Whether a configuration in an unrecognized layout may be rewritten
+    unrecognized_layout: bool,
+    /// The firmware the probe ran before it entered the bootloader, when
+    /// that was asked
+    firmware: Option<FirmwareVersion>,
+    /// Whether configuration writes are read back
+    verify_writes: bool,
+    /// Where [`reset`](Self::reset) starts execution
+    reset_address: u32,
+    /// Data uploads and downloads so far, counted for [`TransferStats`]
+    chunks: usize,
+    last_stats: Option<TransferStats>,
+    /// The status the bootloader was first found in when it was not idle
+    interrupted: Option<DfuStatus>,
+}
+
+impl Xds110UsbDevice {
+    pub fn reboot_to_dfu(self) -> Result<(), std::io::Error> {
+        // Send the "Reboot to DFU mode" packet.
+        self.device_handle.write_bulk(
+            self.epout,
+            &[0x2a, 0x01, 0x00, 0x26],
+            Duration::from_secs(1),
+        )?;
+        Ok(())
+    }
+    pub fn firmware_version(&self) -> Result<FirmwareVersion, std::io::Error> {
+        self.firmware_version_with_timeout(FIRMWARE_VERSION_TIMEOUT)
+    }
+
+    /// Like [`firmware_version`](Self::firmware_version), waiting up to
+    /// `timeout` for each of the request and the response
+    pub fn firmware_version_with_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<FirmwareVersion, std::io::Error> {
+        self.device_handle
+            .write_bulk(self.epout, &[0x2a, 0x01, 0x00, 0x03], timeout)?;
+        let mut version = [0u8; 13];
+        let response = self
+            .device_handle
+            .read_bulk(self.epin, &mut version, timeout)?;
+        if response < 11 {
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+        Ok(FirmwareVersion(u32::from_le_bytes(
+            version[7..11].try_into().unwrap(),
+        )))
+    }
+
+    /// Fail with [`FirmwareVersionError::TooOld`] if the firmware is older
+    /// than `minimum`, such as [`CMSIS_DAP_2_MINIMUM`], otherwise return the
+    /// version found
+    pub fn check_minimum_firmware(
+        &self,
+        minimum: u32,
+    ) -> Result<FirmwareVersion, FirmwareVersionError> {
+        self.check_minimum_firmware_with_timeout(FirmwareVersion(minimum), FIRMWARE_VERSION_TIMEOUT)
+    }
+
+    /// Whether the firmware is too old for CMSIS-DAP 2.0 and needs updating
+    /// before switching to [`ProbeMode::CmsisDap2`]. Nothing is changed on the
+    /// probe.
+    pub fn needs_cmsis_dap2_update(&self) -> Result<bool, std::io::Error> {
+        Ok(self.firmware_version()? < FirmwareVersion(CMSIS_DAP_2_MINIMUM))
+    }
+
+    pub(crate) fn check_minimum_firmware_with_timeout(
+        &self,
+        minimum: FirmwareVersion,
+        timeout: Duration,
+    ) -> Result<FirmwareVersion, FirmwareVersionError> {
+        let found = self.firmware_version_with_timeout(timeout)?;
+        if found < minimum {
+            return Err(FirmwareVersionError::TooOld { found, minimum });
+        }
+        Ok(found)
+    }
+
+    pub fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+}
+
+impl Xds110DfuDevice {
+    pub fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    /// The bus number and device address the bootloader enumerated at, if
+    /// the device was found by enumeration
+    pub fn bus_address(&self) -> Option<(u8, u8)> {
+        self.bus_address
+    }
+
+    /// Timing of the last flash read or write that completed, such as the
+    /// one of [`read_configuration`](Self::read_configuration) or
+    /// [`write_configuration`](Self::write_configuration). A verified write
+    /// ends with a read, whose stats these are.
+    pub fn last_transfer_stats(&self) -> Option<TransferStats> {
+        self.last_stats
+    }
+
+    /// The status the bootloader was in when it was first found outside
+    /// `dfuIDLE`, left there by a session that was interrupted, for instance
+    /// between the setup and the data of a write. It was brought back to idle
+    /// before going on; this is only a record of what was found. Usually set
+    /// already when the device is opened, before an observer could hear of
+    /// it as [`Warning::InterruptedSession`].
+    pub fn interrupted_session(&self) -> Option<DfuStatus> {
+        self.interrupted
+    }
+
+    /// The DFU interface found in the configuration descriptor, which the
+    /// class requests are addressed to
+    pub fn dfu_interface(&self) -> DfuInterface {
+        self.interface
+    }
+
+    /// Report progress of subsequent operations to `observer`
+    pub fn set_observer(&mut self, observer: Arc<dyn Observer>) {
+        self.observer = Some(observer);
+    }
+
+    fn notify(&self, phase: Phase) {
+        if let Some(observer) = &self.observer {
+            observer.phase(phase);
+        }
+    }
+
+    fn warn(&self, warning: &Warning) {
+        if let Some(observer) = &self.observer {
+            observer.warning(warning);
+        }
+    }
+
+    /// Record every status the bootloader returns from now on into `trace`
+    pub fn set_status_trace(&mut self, trace: StatusTrace) {
+        self.trace = Some(trace);
+    }
+
+    pub fn status_trace(&self) -> Option<&StatusTrace> {
+        self.trace.as_ref()
+    }
+
+    /// Give up on the bootloader reaching the state a step waits for after
+    /// `timeout`, instead of [`DFU_WAIT_TIMEOUT`]
+    pub fn set_wait_timeout(&mut self, timeout: Duration) {
+        self.wait_timeout = timeout;
+    }
+
+    /// Allow raw writes below the application start address, where the
+    /// bootloader itself lives. Overwriting it can leave the probe unable to
+    /// boot at all.
+    pub fn set_unsafe_writes(&mut self, allowed: bool) {
+        self.unsafe_writes = allowed;
+    }
+
+    /// Allow [`set_mode`](Self::set_mode) and
+    /// [`patch_configuration`](Self::patch_configuration) to rewrite a
+    /// configuration whose layout is
+    /// [unrecognized](ConfigurationSummary::unrecognized_layout). They fail
+    /// with [`Xds110Error::UnrecognizedConfigLayout`] otherwise, as the
+    /// layout may be a newer one that they would corrupt.
+    pub fn set_allow_unrecognized_layout(&mut self, allowed: bool) {
+        self.unrecognized_layout = allowed;
+    }
+
+    /// Fail if `configuration`, as read from the probe, must not be
+    /// rewritten because of its layout, bringing it up to date with the
+    /// firmware otherwise
+    fn check_layout(&self, configuration: &mut Xds110Configuration) -> Result<(), Xds110Error> {
+        if self.unrecognized_layout {
+            return Ok(());
+        }
+        ensure_configuration_version_is_current(configuration, self.firmware)?;
+        Ok(())
+    }
+
+    /// Read the configuration back after each write and compare it with
+    /// what was written. This is on by default; turning it off saves a
+    /// read of 16 KiB.
+    pub fn set_verify_writes(&mut self, verify: bool) {
+        self.verify_writes = verify;
+    }
+
+    /// Have [`reset`](Self::reset) start execution at `address` instead of
+    /// [`RESET_ADDRESS`], for instance after flashing firmware linked for
+    /// another address. Anything but the default must lie in flash.
+    pub fn set_reset_address(&mut self, address: u32) -> Result<(), Xds110Error> {
+        if address != RESET_ADDRESS {
+            self.check_flash_range(address, 4)?;
+        }
+        self.reset_address = address;
+        Ok(())
+    }
+
+    /// Check `token` between transfers of subsequent reads and writes, and
+    /// stop with [`Xds110Error::Cancelled`] once it has been cancelled
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = Some(token);
+    }
+
+    /// If cancellation was requested, bring the bootloader back to
+    /// `dfuIDLE` and fail
+    fn check_cancelled(&self) -> Result<(), Xds110Error> {
+        if !self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            return Ok(());
+        }
+        self.return_to_idle()?;
+        Err(Xds110Error::Cancelled)
+    }
+
+    /// Abort whatever the bootloader is doing, or clear its error, and wait
+    /// for `dfuIDLE`
+    fn return_to_idle(&self) -> Result<(), Xds110Error> {
+        let poll = || {
+            let status = self.get_status()?;
+            match status.state {
+                DfuState::DfuIdle => {}
+                // Let the device finish what it is busy with first
+                DfuState::DnloadSync | DfuState::DnBusy | DfuState::ManifestSync => {}
+                DfuState::Error => dfu::clear_status(&*self.device_handle, self.interface.number)
+                    .context("DFU_CLRSTATUS")?,
+                _ => {
+                    dfu::abort(&*self.device_handle, self.interface.number).context("DFU_ABORT")?
+                }
+            }
+            Ok(status)
+        };
+        dfu::poll_for_state(poll, DfuState::DfuIdle, self.wait_timeout)?;
+        Ok(())
+    }
+
+    fn trace(&self, status: DfuStatus) -> DfuStatus {
+        if let Some(trace) = &self.trace {
+            trace.record(status);
+        }
+        status
+    }
+
+    fn get_status(&self) -> Result<DfuStatus, Xds110Error> {
+        let status = dfu::get_status(&*self.device_handle, self.interface.number)
+            .context("DFU_GETSTATUS")?;
+        Ok(self.trace(status))
+    }
+
+    /// Poll the status until the bootloader reports `state`. Fails with
+    /// [`Xds110Error::BootloaderError`] as soon as it reports `dfuERROR`
+    /// instead.
+    fn wait_for_state(&self, state: DfuState) -> Result<DfuStatus, Xds110Error> {
+        let poll = || {
+            let status = self.get_status()?;
+            if status.state == DfuState::Error && state != DfuState::Error {
+                return Err(Xds110Error::BootloaderError {
+                    status: status.status,
+                    // Only a nicety, the error stands without it
+                    description: self.get_status_string(&status).ok().flatten(),
+                });
+            }
+            Ok(status)
+        };
+        dfu::poll_for_state(poll, state, self.wait_timeout)
+    }
+
+    /// The string descriptor the bootloader attached to `status` through
+    /// `iString`, if it did
+    pub fn get_status_string(&self, status: &DfuStatus) -> Result<Option<String>, Xds110Error> {
+        if status.string_index == 0 {
+            return Ok(None);
+        }
+        Ok(Some(
+            self.device_handle.string_descriptor(status.string_index)?,
+        ))
+    }
+
+    /// Check the bootloader answers `DFU_GETSTATUS` with a well-formed
+    /// six-byte status, reporting `dfuIDLE` and OK. Meant for right after the
+    /// probe enumerates in DFU mode, before anything else is sent. Anything
+    /// else is reported as [`Warning::UnexpectedStatus`] and fails with
+    /// [`Xds110Error::UnexpectedState`]; [`recover`](Self::recover) does not
+    /// run first, so nothing is cleared.
+    pub fn ping_dfu(&self) -> Result<DfuStatus, Xds110Error> {
+        let bytes = dfu::get_status_bytes(&*self.device_handle, self.interface.number)
+            .context("DFU_GETSTATUS")?;
+        let status = match DfuStatus::parse(&bytes) {
+            Some(status) if bytes.len() == 6 => self.trace(status),
+            _ => return Err(Xds110Error::InvalidDfuResponse("DFU_GETSTATUS")),
+        };
+        if status.status != 0 || status.state != DfuState::DfuIdle {
+            self.warn(&Warning::UnexpectedStatus {
+                state: status.state,
+                status: status.status,
+            });
+            return Err(Xds110Error::UnexpectedState {
+                state: status.state,
+                status: status.status,
+            });
+        }
+        Ok(status)
+    }
+
+    /// Ensure the target speaks the Tiva DFU binary protocol, failing with
+    /// [`Xds110Error::NotTivaProtocol`] if its answer lacks the marker
+    pub fn ensure_binary_protocol(&self) -> Result<(), Xds110Error> {
+        self.notify(Phase::EnsuringBinaryProtocol);
+        let response = dfu::query_tiva_protocol(&*self.device_handle, self.interface.number);
+        let response = match self.checked(response, "Tiva protocol query") {
+            Err(Xds110Error::Stalled { .. }) => return Err(Xds110Error::TivaQueryUnsupported),
+            response => response?,
+        };
+        if !dfu::is_tiva_protocol(&response) {
+            return Err(Xds110Error::NotTivaProtocol(response));
+        }
+        Ok(())
+    }
+
+    /// Attach `operation` to a failed transfer. A stall is the bootloader
+    /// refusing the request: ask it why and clear the error, so it can be
+    /// used again, and report the reason as [`Xds110Error::Stalled`].
+    fn checked<T>(
+        &self,
+        result: Result<T, nusb::transfer::TransferError>,
+        operation: &'static str,
+    ) -> Result<T, Xds110Error> {
+        match result {
+            Err(nusb::transfer::TransferError::Stall) => {
+                let status = self.get_status()?;
+                if status.state == DfuState::Error {
+                    dfu::clear_status(&*self.device_handle, self.interface.number)
+                        .context("DFU_CLRSTATUS")?;
+                }
+                Err(Xds110Error::Stalled {
+                    operation,
+                    status: status.status,
+                })
+            }
+            result => result.context(operation),
+        }
+    }
+
+    /// The block number for the next transfer, advancing the counter. Large
+    /// transfers wrap around from 0xffff to 0 as DFU block numbers do.
+    fn next_packet(&mut self) -> u16 {
+        let packet = self.packet_count;
+        self.packet_count = packet.wrapping_add(1);
+        packet
+    }
+
+    /// Send a Tiva command, advancing the packet counter
+    fn command(&mut self, command: TivaCommand) -> Result<DfuStatus, Xds110Error> {
+        let bytes = command.to_bytes();
+        self.dfu_command(bytes[0], &bytes[1..])
+    }
+
+    /// Send the raw Tiva command `cmd`, followed by `payload`, as a
+    /// `DFU_DNLOAD`, and return the status the bootloader answers with. Like
+    /// every other command, this restarts the packet counter.
+    ///
+    /// This is for commands and variants this crate does not wrap. Nothing
+    /// checks what the command does, so it can overwrite or erase anything.
+    pub fn dfu_command(&mut self, cmd: u8, payload: &[u8]) -> Result<DfuStatus, Xds110Error> {
+        let mut bytes = Vec::with_capacity(1 + payload.len());
+        bytes.push(cmd);
+        bytes.extend_from_slice(payload);
+        // The Tiva bootloader takes where the data goes from the command,
+        // never from wValue, which only numbers the transfers that follow
+        // it. So each command can start the count afresh, like a new
+        // download does in DFU, and a count that wraps in a long transfer
+        // cannot point anywhere else.
+        self.packet_count = 0;
+        let packet = self.next_packet();
+        let result = dfu::download(&*self.device_handle, self.interface.number, packet, &bytes);
+        self.checked(result, dfu::command_name(cmd))?;
+        self.get_status()
+    }
+
+    /// Upload `len` bytes prepared by the last command, such as
+    /// [`TivaCommand::Read`] or [`TivaCommand::Info`], advancing the packet
+    /// counter and fetching the status that must follow
+    pub fn dfu_upload(&mut self, len: u16) -> Result<Vec<u8>, Xds110Error> {
+        let packet = self.next_packet();
+        let bytes = dfu::upload(&*self.device_handle, self.interface.number, packet, len);
+        let bytes = self.checked(bytes, "block upload")?;
+        self.get_status()?;
+        Ok(bytes)
+    }
+
+    pub fn read_configuration(&mut self) -> Result<Xds110Configuration, Xds110Error> {
+        self.check_configuration_location()?;
+        self.with_retries("configuration read", |dfu| {
+            let bytes = dfu.read_blocks(
+                CONFIGURATION_BLOCK,
+                CONFIGURATION_SIZE as u32,
+                Some(|done, total| Phase::ReadingConfig { done, total }),
+            )?;
+            Xds110Configuration::try_from(&bytes[..])
+                .map_err(|_| Xds110Error::InvalidDfuResponse("DFU_UPLOAD"))
+        })
+    }
+
+    /// Read `length` bytes of flash from `start_block` on. The range must lie
+    /// in flash but need not end on a block boundary.
+    ///
+    /// Any number of regions can be read in one bootloader session. Each
+    /// read starts by bringing the bootloader back to `dfuIDLE`, so one that
+    /// failed or was stopped part way does not upset the next.
+    pub fn read_region(&mut self, start_block: u16, length: u32) -> Result<Vec<u8>, Xds110Error> {
+        self.read_blocks(start_block, length, None)
+    }
+
+    /// Upload `len` bytes from flash starting at `block`, reporting progress
+    /// as the phase built by `progress`
+    fn read_blocks(
+        &mut self,
+        block: u16,
+        len: u32,
+        progress: Option<stream::Progress>,
+    ) -> Result<Vec<u8>, Xds110Error> {
+        self.check_flash_range(block as u32 * DFU_BLOCK_SIZE, len)?;
+        self.recover()?;
+        let started = (Instant::now(), self.chunks);
+        let data = DfuReader::with_progress(self, block, len, progress)?.read_all()?;
+        let stats = self.finish_stats(started, data.len());
+        self.notify(Phase::ReadStats(stats));
+        Ok(data)
+    }
+
+    /// Query the bootloader for the flash layout. The answer is kept, so
+    /// only the first call talks to the bootloader.
+    pub fn detect_flash_geometry(&mut self) -> Result<FlashGeometry, Xds110Error> {
+        if let Some(geometry) = self.geometry {
+            return Ok(geometry);
+        }
+        self.command(TivaCommand::Info)?;
+        let bytes = self.dfu_upload(FlashGeometry::SIZE)?;
+        let geometry =
+            FlashGeometry::parse(&bytes).ok_or(Xds110Error::InvalidDfuResponse("DFU_CMD_INFO"))?;
+        self.geometry = Some(geometry);
+        Ok(geometry)
+    }
+
+    /// Fail unless `len` bytes from `address` lie in flash
+    pub(crate) fn check_flash_range(&mut self, address: u32, len: u32) -> Result<(), Xds110Error> {
+        let geometry = self.detect_flash_geometry()?;
+        if address
+            .checked_add(len)
+            .is_none_or(|end| end > geometry.flash_top)
+        {
+            return Err(Xds110Error::UnreachableAddress(address));
+        }
+        Ok(())
+    }
+
+    /// Fail unless `len` bytes from `address` lie in flash and, unless
+    /// unsafe writes are allowed, clear of the bootloader
+    pub(crate) fn check_writable(&mut self, address: u32, len: u32) -> Result<(), Xds110Error> {
+        self.check_flash_range(address, len)?;
+        let app_start = self.detect_flash_geometry()?.app_start;
+        if address < app_start && !self.unsafe_writes {
+            return Err(Xds110Error::BootloaderOverlap { address, app_start });
+        }
+        Ok(())
+    }
+
+    /// Fail unless the flash layout has the configuration in its own erase
+    /// block at the top of flash, where this crate expects it
+    fn check_configuration_location(&mut self) -> Result<(), Xds110Error> {
+        let geometry = self.detect_flash_geometry()?;
+        let start = CONFIGURATION_BLOCK as u32 * DFU_BLOCK_SIZE;
+        if start < geometry.app_start
+            || start + CONFIGURATION_SIZE as u32 != geometry.flash_top
+            || geometry.block_size == 0
+            || !start.is_multiple_of(geometry.block_size as u32)
+        {
+            return Err(Xds110Error::UnexpectedFlashLayout(geometry));
+        }
+        Ok(())
+    }
+
+    /// Write `configuration` and, unless
+    /// [`set_verify_writes`](Self::set_verify_writes) turned it off, read it
+    /// back. A read-back that differs fails with
+    /// [`Xds110Error::VerifyFailed`] and the first differing offset.
+    pub fn write_configuration(
+        &mut self,
+        configuration: &Xds110Configuration,
+    ) -> Result<(), Xds110Error> {
+        self.check_configuration_location()?;
+        self.with_retries("configuration write", |dfu| {
+            dfu.write_blocks(
+                CONFIGURATION_BLOCK,
+                configuration.to_bytes(),
+                |done, total| Phase::WritingConfig { done, total },
+            )
+        })?;
+        if !self.verify_writes {
+            return Ok(());
+        }
+
+        let written = self.read_region(CONFIGURATION_BLOCK, CONFIGURATION_SIZE as u32)?;
+        if let Some(offset) = written
+            .iter()
+            .zip(configuration.to_bytes())
+            .position(|(a, b)| a != b)
+        {
+            return Err(Xds110Error::VerifyFailed {
+                offset: offset as u16,
+            });
+        }
+        Ok(())
+    }
+
+    /// Write `data` to flash from `start_block` on. The range must lie in
+    /// flash and, unless [`set_unsafe_writes`](Self::set_unsafe_writes)
+    /// allows it, above the bootloader. A length that is not a multiple of
+    /// the block size is fine.
+    pub fn write_region(&mut self, start_block: u16, data: &[u8]) -> Result<(), Xds110Error> {
+        let len = u32::try_from(data.len())
+            .map_err(|_| Xds110Error::UnreachableAddress(start_block as u32 * DFU_BLOCK_SIZE))?;
+        self.check_writable(start_block as u32 * DFU_BLOCK_SIZE, len)?;
+        self.write_blocks(start_block, data, |done, total| Phase::WritingFirmware {
+            done,
+            total,
+        })
+    }
+
+    /// Erase `num_blocks` 1024-byte blocks from `start_block` on, leaving
+    /// them `0xff`. The same bounds as for
+    /// [`write_region`](Self::write_region) apply. Returns once the
+    /// bootloader has finished and is idle again.
+    pub fn erase_region(&mut self, start_block: u16, num_blocks: u16) -> Result<(), Xds110Error> {
+        self.check_writable(
+            start_block as u32 * DFU_BLOCK_SIZE,
+            num_blocks as u32 * DFU_BLOCK_SIZE,
+        )?;
+        self.wait_for_state(DfuState::DfuIdle)?;
+        self.command(TivaCommand::Erase {
+            block: start_block,
+            num_blocks,
+        })?;
+        // Polls through the erase, then leaves dfuDNLOAD-IDLE
+        self.return_to_idle()
+    }
+
+    /// Download `data` to flash starting at `block`, reporting progress as
+    /// the phase built by `progress`
+    fn write_blocks(
+        &mut self,
+        block: u16,
+        data: &[u8],
+        progress: fn(usize, usize) -> Phase,
+    ) -> Result<(), Xds110Error> {
+        self.recover()?;
+        let started = (Instant::now(), self.chunks);
+        let mut writer = DfuWriter::with_progress(self, block, data.len() as u32, Some(progress))?;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let n = writer.push(rest)?;
+            rest = &rest[n..];
+        }
+        writer.finish()?;
+        let stats = self.finish_stats(started, data.len());
+        self.notify(Phase::WriteStats(stats));
+        Ok(())
+    }
+
+    /// Record the stats of a read or write of `bytes` that started at the
+    /// time and chunk count in `started`
+    fn finish_stats(&mut self, started: (Instant, usize), bytes: usize) -> TransferStats {
+        let stats = TransferStats {
+            bytes,
+            duration: started.0.elapsed(),
+            chunks: self.chunks - started.1,
+        };
+        self.last_stats = Some(stats);
+        stats
+    }
+
+    /// Leave the bootloader and jump to `address`.
+    ///
+    /// The Tiva bootloader has no separate run command; this is the reset
+    /// command with a caller-chosen address instead of the one that starts
+    /// the normal firmware. The bootloader is gone once this returns, so the
+    /// device cannot be used for anything else afterwards.
+    pub fn execute_at(&mut self, address: u32) -> Result<(), Xds110Error> {
+        self.wait_for_state(DfuState::DfuIdle)?;
+        self.command(TivaCommand::Reset { addr: address })?;
+        self.left_bootloader = true;
+        Ok(())
+    }
+
+    /// Abort whatever the bootloader is doing and wait for it to be idle
+    /// before closing the device, leaving the probe in the bootloader ready
+    /// for the next session. Unlike dropping the device, this waits and
+    /// reports failures.
+    pub fn close_gracefully(mut self) -> Result<(), Xds110Error> {
+        self.return_to_idle()?;
+        self.left_bootloader = true;
+        Ok(())
+    }
+
+    /// Leave the bootloader and start the normal firmware. Once the DFU
+    /// device has gone away, this waits for the probe to re-enumerate and
+    /// returns it ready for use.
+    pub fn reset(mut self) -> Result<Xds110UsbDevice, Xds110Error> {
+        self.leave_bootloader()?;
+
+        let port_path = self.port_path.take();
+        let serial = self.serial.take();
+        let cancel = self.cancel.take();
+        let bus = self.bus.clone();
+        drop(self);
+        let same_probe = |device: &ProbeInfo| {
+            probe::is_same_probe(device, port_path.as_ref(), serial.as_deref())
+        };
+        // Only this probe's bootloader, not another one still attached
+        probe::wait_for_removal(
+            &*bus,
+            |device| device.state == ProbeState::Dfu && same_probe(device),
+            cancel.as_ref(),
+        )?;
+        probe::wait_for_reenumeration(|| bus.open_xds110(&same_probe), cancel.as_ref())
+    }
+
+    /// Leave the bootloader without the Tiva reset command and its jump
+    /// address: send `DFU_DETACH`, then reset the USB port so the probe
+    /// re-enumerates. What runs afterwards is up to the bootloader. A stalled
+    /// detach is ignored, as the port reset is what forces re-enumeration.
+    ///
+    /// The device cannot be used for anything else afterwards. Not supported
+    /// on Windows.
+    pub fn soft_reset(&mut self) -> Result<(), Xds110Error> {
+        self.notify(Phase::Resetting);
+        self.wait_for_state(DfuState::DfuIdle)?;
+        match dfu::detach(
+            &*self.device_handle,
+            self.interface.number,
+            DETACH_TIMEOUT_MS,
+        ) {
+            Ok(()) | Err(nusb::transfer::TransferError::Stall) => {}
+            Err(e) => return Err(e).context("DFU_DETACH"),
+        }
+        self.left_bootloader = true;
+        self.device_handle.reset_port()?;
+        Ok(())
+    }
+
+    /// Send the reset that starts the normal firmware, without waiting for
+    /// the probe to come back
+    fn leave_bootloader(&mut self) -> Result<(), Xds110Error> {
+        // The last step, so a write that has not settled shows up here
+        let reset_timeout = |e| match e {
+            Xds110Error::WaitTimeout { state, status, .. } => {
+                Xds110Error::ResetTimeout { state, status }
+            }
+            e => e,
+        };
+        self.notify(Phase::Resetting);
+        self.wait_for_state(DfuState::DfuIdle)
+            .map_err(reset_timeout)?;
+        self.command(TivaCommand::Reset {
+            addr: self.reset_address,
+        })?;
+        self.left_bootloader = true;
+        self.wait_for_state(DfuState::DfuIdle)
+            .map_err(reset_timeout)?;
+        Ok(())
+    }
+}
+
+/// Sends `DFU_ABORT` on a best-effort basis, so a transfer cut short by an
+/// error does not stay open. Errors are ignored; use
+/// [`close_gracefully`](Xds110DfuDevice::close_gracefully) to see them.
+impl Drop for Xds110DfuDevice {
+    fn drop(&mut self) {
+        if !self.left_bootloader {
+            let _ = dfu::abort(&*self.device_handle, self.interface.number);
+        }
+    }
+}
+
+impl Xds110UsbDevice {
+    /// Open a probe the caller has already found, validating its interface
+    /// and endpoints and claiming it
+    pub fn from_device_info(device: nusb::DeviceInfo) -> Result<Self, std::io::Error> {
+        let Some(candidate_match) = XDS110_USB_DEVICES
+            .iter()
+            .find(|x| device.vendor_id() == x.vid && device.product_id() == x.pid)
+        else {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        };
+        let epin = candidate_match.epin;
+        let epout = candidate_match.epout;
+        let iface = candidate_match.interface;
+
+        let mut epout_found = false;
+        let mut epin_found = false;
+
+        let device_handle = device.open()?;
+
+        let mut configs = device_handle.configurations();
+        let Some(config) = configs.next() else {
+            return Err(std::io::ErrorKind::NotFound.into());
+        };
+        let Some(interface) = config.interfaces().find(|x| x.interface_number() == iface) else {
+            return Err(std::io::ErrorKind::NotFound.into());
+        };
+
+        for alt_setting in interface.alt_settings() {
+            for endpoint in alt_setting.endpoints() {
+                if endpoint.address() == epout {
+                    usb_util::check_bulk_endpoint(&endpoint, Direction::Out)?;
+                    epout_found = true;
+                } else if endpoint.address() == epin {
+                    usb_util::check_bulk_endpoint(&endpoint, Direction::In)?;
+                    epin_found = true;
+                }
+            }
+        }
+
+        if !epout_found || !epin_found {
+            return Err(std::io::ErrorKind::NotFound.into());
+        }
+
+        let cmsis_dap = cmsis_dap::LazyCmsisDap::of(device.clone(), device_handle.clone());
+        usb_util::detach_kernel_driver_if_needed(&device_handle, iface)?;
+        let device_handle = device_handle.claim_interface(iface)?;
+
+        Ok(Xds110UsbDevice {
+            device_handle: Box::new(device_handle),
+            epout,
+            epin,
+            serial: device.serial_number().map(str::to_owned),
+            port_path: usb_util::port_path(&device),
+            bus: Arc::new(UsbBus),
+            cmsis_dap,
+        })
+    }
+}
+
+impl Xds110DfuDevice {
+    /// Open a bootloader the caller has already found
+    pub fn from_device_info(device: nusb::DeviceInfo) -> Result<Self, std::io::Error> {
+        if !XDS110_DFU_DEVICES
+            .iter()
+            .any(|x| device.vendor_id() == x.vid && device.product_id() == x.pid)
+        {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+
+        let device_handle = device.open()?;
+
+        // TODO: We may need to claim the DFU interface on Windows, in which case this
+        // struct will need to grow an `enum`.
+
+        let mut dfu = Xds110DfuDevice::new(device_handle)?;
+        dfu.serial = device.serial_number().map(str::to_owned);
+        dfu.port_path = usb_util::port_path(&device);
+        dfu.bus_address = Some((device.bus_number(), device.device_address()));
+        dfu.recover()?;
+        Ok(dfu)
+    }
+
+    /// Bring back a bootloader left outside `dfuIDLE`, for instance in
+    /// `dfuERROR` by a run that died mid-transfer, or in `dfuUPLOAD-IDLE` by
+    /// a read that was not finished
+    fn recover(&mut self) -> Result<(), Xds110Error> {
+        let status = self.get_status()?;
+        if status.state != DfuState::DfuIdle {
+            self.warn(&Warning::InterruptedSession {
+                state: status.state,
+                status: status.status,
+            });
+            self.interrupted.get_or_insert(status);
+            self.return_to_idle()?;
+        }
+        Ok(())
+    }
+
+    /// Wrap `device_handle`, which must have a DFU interface
+    fn new(device_handle: nusb::Device) -> Result<Self, std::io::Error> {
+        let interface = dfu::find_interface(&device_handle).ok_or(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "the device has no DFU interface",
+        ))?;
+        Ok(Self::with_transport(Box::new(device_handle), interface))
+    }
+
+    /// Drive the bootloader through `device_handle`, whose DFU interface is
+    /// `interface`
+    pub(crate) fn with_transport(
+        device_handle: Box<dyn Transport>,
+        interface: DfuInterface,
+    ) -> Self {
+        Xds110DfuDevice {
+            device_handle,
+            interface,
+            packet_count: 0,
+            serial: None,
+            port_path: None,
+            bus: Arc::new(UsbBus),
+            bus_address: None,
+            observer: None,
+            trace: None,
+            cancel: None,
+            wait_timeout: DFU_WAIT_TIMEOUT,
+            options: DfuOptions::default(),
+            geometry: None,
+            left_bootloader: false,
+            unsafe_writes: false,
+            unrecognized_layout: false,
+            firmware: None,
+            verify_writes: true,
+            reset_address: RESET_ADDRESS,
+            chunks: 0,
+            last_stats: None,
+            interrupted: None,
+        }
+    }
+}
+
+pub fn open_xds110() -> Result<Xds110UsbDevice, std::io::Error> {
+    open_xds110_matching(|_| true)
+}
+
+/// Open the probe plugged into `port_chain` on `bus`. Unlike the bus address,
+/// the physical path survives the probe re-enumerating.
+pub fn open_xds110_by_path(bus: u8, port_chain: &[u8]) -> Result<Xds110UsbDevice, std::io::Error> {
+    open_xds110_matching(|device| usb_util::is_at_path(device, bus, port_chain))
+}
+
+fn open_xds110_matching(
+    filter: impl Fn(&nusb::DeviceInfo) -> bool,
+) -> Result<Xds110UsbDevice, std::io::Error> {
+    let devices = nusb::list_devices()?.filter(|x| filter(x));
+    let mut device_info = None;
+    'next_device: for candidate_device in devices {
+        for candidate_match in XDS110_USB_DEVICES {
+            if candidate_device.vendor_id() == candidate_match.vid
+                && candidate_device.product_id() == candidate_match.pid
+            {
+                if device_info.is_some() {
+                    return Err(std::io::ErrorKind::TooManyLinks.into());
+                }
+                device_info = Some(candidate_device);
+                break 'next_device;
+            }
+        }
+    }
+
+    let Some(device) = device_info else {
+        return Err(std::io::ErrorKind::NotFound.into());
+    };
+
+    Xds110UsbDevice::from_device_info(device)
+}
+
+pub fn open_dfu() -> Result<Xds110DfuDevice, std::io::Error> {
+    open_dfu_matching(|_| true)
+}
+
+/// Open the DFU bootloader plugged into `port_chain` on `bus`
+pub fn open_dfu_by_path(bus: u8, port_chain: &[u8]) -> Result<Xds110DfuDevice, std::io::Error> {
+    open_dfu_matching(|device| usb_util::is_at_path(device, bus, port_chain))
+}
+
+fn open_dfu_matching(
+    filter: impl Fn(&nusb::DeviceInfo) -> bool,
+) -> Result<Xds110DfuDevice, std::io::Error> {
+    let devices = nusb::list_devices()?.filter(|x| filter(x));
+    let mut device_info = None;
+    'next_device: for candidate_device in devices {
+        for candidate_match in XDS110_DFU_DEVICES {
+            if candidate_device.vendor_id() == candidate_match.vid
+                && candidate_device.product_id() == candidate_match.pid
+            {
+                if device_info.is_some() {
+                    return Err(std::io::ErrorKind::TooManyLinks.into());
+                }
+                device_info = Some(candidate_device);
+                break 'next_device;
+            }
+        }
+    }
+
+    let Some(device) = device_info else {
+        return Err(std::io::ErrorKind::NotFound.into());
+    };
+
+    Xds110DfuDevice::from_device_info(device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dfu::{DFU_DNLOAD, DFU_UPLOAD};
+    use crate::mock::{self, MockBootloader};
+
+    /// A configuration in which every 1024-byte block differs and no byte
+    /// repeats within 251 bytes, so data landing at the wrong offset shows
+    fn pattern() -> Xds110Configuration {
+        let mut bytes = [0; CONFIGURATION_SIZE as usize];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = ((i % 251) as u8).wrapping_add((i / 1024) as u8 * 17);
+        }
+        Xds110Configuration::from_bytes(bytes)
+    }
+
+    #[test]
+    fn configuration_round_trip() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        let configuration = pattern();
+
+        dfu.write_configuration(&configuration).unwrap();
+        assert_eq!(mock.configuration(), configuration.to_bytes());
+        mock.lock().requests.clear();
+        assert_eq!(dfu.read_configuration().unwrap(), configuration);
+
+        let device = mock.lock();
+        // Without the upload prefix, which would shift everything by 8
+        assert!(!device.header);
+        assert_eq!(device.state, DfuState::DfuIdle);
+        // The uploads are numbered from 1, after the command as block 0
+        let uploads: Vec<u16> = device
+            .requests
+            .iter()
+            .filter(|(request, _)| *request == DFU_UPLOAD)
+            .map(|&(_, value)| value)
+            .collect();
+        assert_eq!(uploads, (1..=16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn cancel_mid_write_returns_to_idle() {
+        let mock = MockBootloader::new();
+        let cancel = CancelToken::new();
+        mock.lock().cancel_after = Some((4, cancel.clone()));
+        let mut dfu = mock.open();
+        dfu.set_cancel_token(cancel);
+
+        let result = dfu.write_configuration(&pattern());
+        assert!(matches!(result, Err(Xds110Error::Cancelled)));
+        {
+            let device = mock.lock();
+            assert_eq!(device.state, DfuState::DfuIdle);
+            // Nothing went out after the transfer that saw the token
+            assert_eq!(device.downloads, 4);
+            assert_eq!(device.requests.last(), Some(&(dfu::DFU_ABORT, 0)));
+        }
+
+        // The bootloader takes the next operation as usual
+        dfu.set_cancel_token(CancelToken::new());
+        dfu.write_configuration(&pattern()).unwrap();
+        assert_eq!(mock.configuration(), pattern().to_bytes());
+    }
+
+    #[test]
+    fn regions_of_odd_sizes() {
+        let start = 0x2_0000;
+        let block = (start / DFU_BLOCK_SIZE) as u16;
+        for (transfer_size, len) in [1024, 600]
+            .into_iter()
+            .flat_map(|size| [1usize, 599, 1023, 1025, 1500, 3333].map(|len| (size, len)))
+        {
+            let mock = MockBootloader::with_transfer_size(transfer_size);
+            let mut dfu = mock.open();
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            dfu.write_region(block, &data).unwrap();
+            {
+                let device = mock.lock();
+                assert_eq!(device.downloads, len.div_ceil(transfer_size as usize));
+                let start = start as usize;
+                assert_eq!(device.flash[start..start + len], data);
+                // Nothing was padded out behind the data
+                assert!(
+                    device.flash[start + len..start + 2 * DFU_BLOCK_SIZE as usize + len]
+                        .iter()
+                        .all(|&b| b == 0xff)
+                );
+                assert_eq!(device.state, DfuState::DfuIdle);
+            }
+            assert_eq!(dfu.read_region(block, len as u32).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn regions_back_to_back() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        // Out of the way, so only the reads upload
+        dfu.detect_flash_geometry().unwrap();
+        let app_block = (mock::APP_START / DFU_BLOCK_SIZE) as u16;
+        let uploads = |from: usize| -> Vec<u16> {
+            mock.lock().requests[from..]
+                .iter()
+                .filter(|(request, _)| *request == DFU_UPLOAD)
+                .map(|&(_, value)| value)
+                .collect()
+        };
+
+        let start = mock.lock().requests.len();
+        let configuration = dfu
+            .read_region(CONFIGURATION_BLOCK, CONFIGURATION_SIZE as u32)
+            .unwrap();
+        assert_eq!(configuration, mock.configuration());
+        assert_eq!(uploads(start), (1..=16).collect::<Vec<_>>());
+        assert_eq!(mock.lock().state, DfuState::DfuIdle);
+
+        let start = mock.lock().requests.len();
+        let application = dfu.read_region(app_block, 3000).unwrap();
+        assert_eq!(application, [mock::APPLICATION_FILL; 3000]);
+        assert_eq!(uploads(start), [1, 2, 3]);
+        assert_eq!(mock.lock().state, DfuState::DfuIdle);
+
+        // And the first region once more, unaffected by the second
+        assert_eq!(
+            dfu.read_region(CONFIGURATION_BLOCK, CONFIGURATION_SIZE as u32)
+                .unwrap(),
+            configuration
+        );
+    }
+
+    #[test]
+    fn transfer_size_sets_the_chunking() {
+        for (transfer_size, chunks) in [(1024, 16), (2048, 8)] {
+            let mock = MockBootloader::with_transfer_size(transfer_size);
+            let mut dfu = mock.open();
+            assert_eq!(dfu.transfer_size(), transfer_size as usize);
+
+            dfu.write_configuration(&pattern()).unwrap();
+            assert_eq!(mock.lock().downloads, chunks);
+            let configuration = dfu.read_configuration().unwrap();
+            assert_eq!(configuration.to_bytes(), pattern().to_bytes());
+            let stats = dfu.last_transfer_stats().unwrap();
+            assert_eq!(stats.bytes, CONFIGURATION_SIZE as usize);
+            assert_eq!(stats.chunks, chunks);
+        }
+    }
+
+    #[test]
+    fn interrupted_session_from_each_state() {
+        for state in [
+            DfuState::DnloadSync,
+            DfuState::DnBusy,
+            DfuState::DnloadIdle,
+            DfuState::ManifestSync,
+            DfuState::UploadIdle,
+            DfuState::Error,
+        ] {
+            let mock = MockBootloader::new();
+            mock.lock().state = state;
+            let mut dfu = mock.open();
+            assert_eq!(
+                dfu.interrupted_session().map(|status| status.state),
+                Some(state)
+            );
+            assert_eq!(mock.lock().state, DfuState::DfuIdle);
+            let configuration = dfu.read_configuration().unwrap();
+            assert_eq!(configuration.to_bytes()[..], mock.configuration()[..]);
+        }
+
+        let mock = MockBootloader::new();
+        let dfu = mock.open();
+        assert_eq!(dfu.interrupted_session(), None);
+        assert_eq!(mock.lock().requests, []);
+    }
+
+    #[test]
+    fn block_numbers_wrap() {
+        // One flash word per transfer, so 256 KiB takes 65540 transfers
+        let mock = MockBootloader::with_transfer_size(4);
+        let mut dfu = mock.open();
+        let block = (mock::APP_START / DFU_BLOCK_SIZE) as u16;
+        let data: Vec<u8> = (0..0x4_0010u32).map(|i| (i % 253) as u8).collect();
+
+        // The mock refuses any block number out of sequence, so past 0xffff
+        // the count went on from 0
+        dfu.write_region(block, &data).unwrap();
+        assert_eq!(mock.lock().downloads, 65540);
+        // The manifest follows the last chunk
+        let last = mock.lock().requests.last().copied();
+        assert_eq!(last, Some((DFU_DNLOAD, 65541u32 as u16)));
+
+        assert_eq!(dfu.read_region(block, data.len() as u32).unwrap(), data);
+        let last = mock.lock().requests.last().copied();
+        assert_eq!(last, Some((DFU_UPLOAD, 65540u32 as u16)));
+    }
+}
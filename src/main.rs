@@ -1,432 +1,1151 @@
-use futures_lite::future::block_on;
-use nusb::transfer::{ControlIn, ControlOut};
+use std::io::Write;
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
-use usb_util::InterfaceExt;
 
-mod usb_util;
+use turbo_110::{
+    CancelToken, ConfigPatch, MAGIC_BYTES, Observer, Phase, ProbeMode, StatusTrace, SwitchOptions,
+    SwitchOutcome, Warning, Xds110Error, Xds110Probe, erase_flash, flash_firmware_file,
+    patch_config, read_config, recover_probe, rewrite_config, switch_mode,
+};
 
-const CMSIS_DAP_2_MINIMUM: u32 = 0x03_00_00_08;
-const CONFIGURATION_SIZE: u16 = 16384;
+/// The options that only exist with the transcript feature, for [`USAGE`]
+#[cfg(feature = "transcript")]
+macro_rules! transcript_usage {
+    () => {
+        "    --transcript <path>
+                    Log every USB transfer to <path>, for bug reports
+    --log-file <path>
+                    Like --transcript, but as JSON lines with timestamps
+"
+    };
+}
+
+#[cfg(not(feature = "transcript"))]
+macro_rules! transcript_usage {
+    () => {
+        ""
+    };
+}
+
+const USAGE: &str = concat!(
+    "Usage: turbo-110 [--mode <mode>] [--ndjson] [--quiet] [--force] [--no-verify]
+                 [--trace-dfu] [--repeat <count>]
+       turbo-110 patch [<offset>=<value>...] [--file <path>] [--quiet] [--force]
+                 [--no-verify]
+       turbo-110 erase <start-block> <num-blocks> --force
+       turbo-110 flash <file> [--ndjson] [--quiet] [--force]
+       turbo-110 recover --mass-erase [--image <file>] --force
+       turbo-110 reboot [--ndjson] [--quiet]
+       turbo-110 test-write [--ndjson] [--quiet]
+       turbo-110 read-config [--raw]
+       turbo-110 power <on|off> [--ndjson]
+
+Switch an attached XDS110 probe into CMSIS-DAP 2.0 mode (mode 4), or into
+another configuration mode with --mode.
+
+With --repeat, the probe is switched to the mode and back to the one it
+started in <count> times over, reopening it after every re-enumeration. A
+failed switch is reported with its iteration number and the run carries on,
+to help track down intermittent USB failures.
+
+The patch command sets individual configuration bytes instead. Either way
+the configuration is read back to verify it unless --no-verify is given.
+Offsets and values are decimal or 0x-prefixed hex. A patch file holds one
+<offset>=<value> per line, with # starting a comment.
+
+The flash command replaces the application firmware with the image in
+<file> and reads it back to verify it. A DFU suffix on the file is checked
+and stripped; a suffix for another device is refused unless --force is
+given. A Tiva DFU_CMD_PROG prefix is stripped too. The bootloader and the
+configuration are left alone.
+
+The test-write command reads the configuration and writes it back
+unchanged, then verifies it and resets the probe. It checks that the whole
+cycle works on a probe before changing its configuration for real.
 
-#[derive(Debug)]
-pub(crate) struct Xds110DfuDeviceMatch {
-    vid: u16,
-    pid: u16,
+The read-config command prints the 16 KiB configuration as a hex dump, or
+as it is with --raw, without changing it, and resets the probe. Nothing but
+the configuration is printed to stdout, so that it can be piped into other
+tools.
+
+The power command switches the 3.3V supply to the target on or off. Only
+probes wired up to power the target support it.
+
+The reboot command only puts the probe into its DFU bootloader, for use with
+another tool such as dfu-util, and prints the bus and address it shows up at
+as <bus>:<address>.
+
+The erase command erases whole 1024-byte flash blocks, which can leave the
+probe without firmware or configuration. It only runs with --force and
+prints the address range before erasing it.
+
+The recover command erases the whole application firmware, for a probe whose
+firmware no longer works. It only runs with --mass-erase and --force, and
+asks for the serial number of the probe to be typed in before erasing. The
+bootloader and the configuration are left alone. With --image the new
+firmware in <file> is then flashed as by the flash command and the probe
+reset into it; without it the probe stays in its bootloader.
+
+Options:
+    --mode <mode>   Mode to switch to, by number or name (default: cmsis-dap2)
+    --list-modes    List the known modes and exit
+    --ndjson        Print progress as newline-delimited JSON events
+    -q, --quiet     Print only errors, warnings and the final result
+    -v, --verbose   Print how long each flash read and write took
+    --force         Switch even if the firmware is too old (unsupported),
+                    patch the reserved magic bytes, allow erase, flash
+                    a file meant for another device, or rewrite a
+                    configuration in a layout it does not recognize
+    --file <path>   Read patches from a file
+    --mass-erase    Erase the whole application firmware (recover)
+    --image <path>  Firmware to flash after the mass erase (recover)
+    --raw           Print the configuration as binary (read-config)
+    --port-path <path>
+                    Use the probe plugged into this port, given as
+                    <bus>-<port>.<port>... (for example 1-3.2)
+    --version-timeout <ms>
+                    How long to wait for the probe to report its firmware
+                    version (default: 100)
+    --reset-address <address>
+                    Where the bootloader starts execution when done
+                    (default: 0x0100df20)
+    --no-verify     Do not read the configuration back after writing it
+",
+    transcript_usage!(),
+    "    --trace-dfu     Print the DFU status history to stderr if the switch fails
+    --repeat <count>
+                    Switch to the mode and back <count> times as a stress test
+    -h, --help      Show this message
+
+Exit status:
+    0   The configuration was changed, or rewritten by test-write, or
+        every --repeat iteration succeeded
+    1   Any other failure, including bad arguments and failed --repeat
+        iterations
+    2   The configuration already matched, nothing was written
+    3   No probe was found
+    4   The firmware is too old for the requested mode
+    5   A USB transfer to the probe failed
+    130 Interrupted by SIGINT or SIGTERM, with the probe left idle in its
+        bootloader"
+);
+
+const EXIT_FAILURE: i32 = 1;
+const EXIT_UNCHANGED: i32 = 2;
+const EXIT_NOT_FOUND: i32 = 3;
+const EXIT_FIRMWARE_TOO_OLD: i32 = 4;
+const EXIT_TRANSFER: i32 = 5;
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// How often to check for SIGINT while waiting for the serial number
+const CONFIRM_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The exit status documented in the usage for `error`
+fn exit_code(error: &(dyn core::error::Error + 'static)) -> i32 {
+    match error.downcast_ref::<Xds110Error>() {
+        Some(Xds110Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => EXIT_NOT_FOUND,
+        Some(Xds110Error::FirmwareTooOld { .. }) => EXIT_FIRMWARE_TOO_OLD,
+        Some(Xds110Error::Io(_) | Xds110Error::Transfer { .. }) => EXIT_TRANSFER,
+        Some(Xds110Error::Cancelled) => EXIT_INTERRUPTED,
+        _ => EXIT_FAILURE,
+    }
 }
 
-pub(crate) const XDS110_DFU_DEVICES: &[Xds110DfuDeviceMatch] = &[Xds110DfuDeviceMatch {
-    vid: 0x1cbe,
-    pid: 0x00ff,
-}];
-
-#[derive(Debug)]
-pub(crate) struct Xds110UsbDeviceMatch {
-    vid: u16,
-    pid: u16,
-    epin: u8,
-    epout: u8,
-    interface: u8,
+struct Args {
+    mode: ProbeMode,
+    ndjson: bool,
+    quiet: bool,
+    force: bool,
+    trace_dfu: bool,
+    verbose: bool,
+    no_verify: bool,
+    transcript: Option<String>,
+    /// Like `transcript`, as JSON lines
+    log_file: Option<String>,
+    reset_address: Option<u32>,
+    version_timeout: Option<Duration>,
+    port_path: Option<(u8, Vec<u8>)>,
+    /// How many round trips to switch through
+    repeat: Option<u32>,
+    /// Set by the patch command
+    patches: Option<Vec<ConfigPatch>>,
+    /// Start block and number of blocks, set by the erase command
+    erase: Option<(u16, u16)>,
+    /// Set by the flash command
+    flash: Option<String>,
+    /// Set by the reboot command
+    reboot: bool,
+    /// Set by the test-write command
+    test_write: bool,
+    /// Set by the recover command
+    recover: bool,
+    /// Set by --mass-erase, which recover requires
+    mass_erase: bool,
+    /// Firmware for recover to flash after erasing
+    image: Option<String>,
+    /// Set by the read-config command
+    read_config: bool,
+    /// Print the configuration as binary rather than a hex dump
+    raw: bool,
+    /// Whether to switch target power on, set by the power command
+    power: Option<bool>,
 }
 
-pub(crate) const XDS110_USB_DEVICES: &[Xds110UsbDeviceMatch] = &[
-    Xds110UsbDeviceMatch {
-        vid: 0x0451,
-        pid: 0xbef3,
-        epin: 0x83,
-        epout: 0x02,
-        interface: 2,
-    },
-    Xds110UsbDeviceMatch {
-        vid: 0x0451,
-        pid: 0xbef4,
-        epin: 0x83,
-        epout: 0x02,
-        interface: 2,
+/// Size of the blocks the erase command counts in
+const BLOCK_SIZE: u32 = 1024;
+
+/// A decimal or `0x`-prefixed hex number
+fn parse_number(s: &str) -> Option<u16> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parse `<bus>-<port>.<port>...`, the form Linux uses in sysfs
+fn parse_port_path(s: &str) -> Option<(u8, Vec<u8>)> {
+    let (bus, ports) = s.split_once('-')?;
+    let ports = ports
+        .split('.')
+        .map(|port| port.parse().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some((bus.parse().ok()?, ports))
+}
+
+/// Patches from a file, one `offset=value` per line
+fn read_patch_file(path: &str) -> Result<Vec<ConfigPatch>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.parse().map_err(|e| format!("{}: {}", path, e)))
+        .collect()
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        Self::parse_from(std::env::args().skip(1))
+    }
+
+    /// Parse `argv`, which does not include the program name
+    fn parse_from(argv: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut args = Args {
+            mode: ProbeMode::CmsisDap2,
+            ndjson: false,
+            quiet: false,
+            force: false,
+            trace_dfu: false,
+            verbose: false,
+            no_verify: false,
+            transcript: None,
+            log_file: None,
+            reset_address: None,
+            version_timeout: None,
+            port_path: None,
+            repeat: None,
+            patches: None,
+            erase: None,
+            flash: None,
+            reboot: false,
+            test_write: false,
+            recover: false,
+            mass_erase: false,
+            image: None,
+            read_config: false,
+            raw: false,
+            power: None,
+        };
+        let mut argv = argv.peekable();
+        if argv.next_if(|arg| arg == "patch").is_some() {
+            args.patches = Some(vec![]);
+        } else if argv.next_if(|arg| arg == "erase").is_some() {
+            let mut number = |name| {
+                let value = argv
+                    .next()
+                    .ok_or_else(|| format!("erase requires <{}>\n\n{}", name, USAGE))?;
+                parse_number(&value).ok_or_else(|| format!("invalid {} `{}`", name, value))
+            };
+            args.erase = Some((number("start-block")?, number("num-blocks")?));
+        } else if argv.next_if(|arg| arg == "flash").is_some() {
+            let path = argv
+                .next()
+                .ok_or_else(|| format!("flash requires <file>\n\n{}", USAGE))?;
+            args.flash = Some(path);
+        } else if argv.next_if(|arg| arg == "reboot").is_some() {
+            args.reboot = true;
+        } else if argv.next_if(|arg| arg == "test-write").is_some() {
+            args.test_write = true;
+        } else if argv.next_if(|arg| arg == "recover").is_some() {
+            args.recover = true;
+        } else if argv.next_if(|arg| arg == "read-config").is_some() {
+            args.read_config = true;
+        } else if argv.next_if(|arg| arg == "power").is_some() {
+            args.power = match argv.next().as_deref() {
+                Some("on") => Some(true),
+                Some("off") => Some(false),
+                _ => return Err(format!("power requires on or off\n\n{}", USAGE)),
+            };
+        }
+        while let Some(arg) = argv.next() {
+            match arg.as_str() {
+                "--file" if args.patches.is_some() => {
+                    let path = argv.next().ok_or("--file requires a value")?;
+                    let patches = read_patch_file(&path)?;
+                    args.patches.as_mut().unwrap().extend(patches);
+                }
+                "--mass-erase" if args.recover => args.mass_erase = true,
+                "--raw" if args.read_config => args.raw = true,
+                "--image" if args.recover => {
+                    let path = argv.next().ok_or("--image requires a value")?;
+                    args.image = Some(path);
+                }
+                "--mode" => {
+                    let value = argv.next().ok_or("--mode requires a value")?;
+                    args.mode = value.parse().map_err(|e| format!("{}", e))?;
+                }
+                "--list-modes" => {
+                    for mode in ProbeMode::KNOWN {
+                        println!(
+                            "{:>3}  {:<12} {}",
+                            mode.number(),
+                            mode.name(),
+                            mode.description()
+                        );
+                    }
+                    std::process::exit(0);
+                }
+                "--version-timeout" => {
+                    let value = argv.next().ok_or("--version-timeout requires a value")?;
+                    let ms = value
+                        .parse()
+                        .map_err(|_| format!("invalid timeout `{}`", value))?;
+                    args.version_timeout = Some(Duration::from_millis(ms));
+                }
+                "--reset-address" => {
+                    let value = argv.next().ok_or("--reset-address requires a value")?;
+                    let address = match value.strip_prefix("0x") {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                        None => value.parse().ok(),
+                    };
+                    args.reset_address =
+                        Some(address.ok_or_else(|| format!("invalid address `{}`", value))?);
+                }
+                "--port-path" => {
+                    let value = argv.next().ok_or("--port-path requires a value")?;
+                    let path = parse_port_path(&value)
+                        .ok_or_else(|| format!("invalid port path `{}`", value))?;
+                    args.port_path = Some(path);
+                }
+                "--repeat" => {
+                    let value = argv.next().ok_or("--repeat requires a value")?;
+                    let count = value
+                        .parse()
+                        .ok()
+                        .filter(|&count| count > 0)
+                        .ok_or_else(|| format!("invalid repeat count `{}`", value))?;
+                    args.repeat = Some(count);
+                }
+                "--transcript" => {
+                    let path = argv.next().ok_or("--transcript requires a value")?;
+                    args.transcript = Some(path);
+                }
+                "--log-file" => {
+                    let path = argv.next().ok_or("--log-file requires a value")?;
+                    args.log_file = Some(path);
+                }
+                "--ndjson" => args.ndjson = true,
+                "-q" | "--quiet" => args.quiet = true,
+                "--force" => args.force = true,
+                "--trace-dfu" => args.trace_dfu = true,
+                "-v" | "--verbose" => args.verbose = true,
+                "--no-verify" => args.no_verify = true,
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                patch if args.patches.is_some() && !patch.starts_with('-') => {
+                    let patch = patch.parse().map_err(|e| format!("{}", e))?;
+                    args.patches.as_mut().unwrap().push(patch);
+                }
+                other => return Err(format!("unrecognized argument `{}`\n\n{}", other, USAGE)),
+            }
+        }
+        if args.patches.as_ref().is_some_and(Vec::is_empty) {
+            return Err(format!("patch requires at least one patch\n\n{}", USAGE));
+        }
+        let command = args.patches.is_some()
+            || args.erase.is_some()
+            || args.flash.is_some()
+            || args.reboot
+            || args.test_write
+            || args.recover
+            || args.read_config
+            || args.power.is_some();
+        if args.repeat.is_some() && command {
+            return Err("--repeat only works with the mode switch".to_owned());
+        }
+        if args.transcript.is_some() && args.log_file.is_some() {
+            return Err("--transcript and --log-file cannot be used together".to_owned());
+        }
+        if args.erase.is_some() && !args.force {
+            return Err("erase destroys flash contents and requires --force".to_owned());
+        }
+        if args.read_config && args.ndjson {
+            return Err(
+                "read-config prints the configuration and cannot print --ndjson".to_owned(),
+            );
+        }
+        if args.recover && !args.mass_erase {
+            return Err(format!("recover requires --mass-erase\n\n{}", USAGE));
+        }
+        if args.recover && !args.force {
+            return Err("recover erases the firmware and requires --force".to_owned());
+        }
+        Ok(args)
+    }
+}
+
+enum Outcome {
+    Switched(SwitchOutcome),
+    /// The number of configuration bytes that changed
+    Patched(usize),
+    /// The address range that was erased
+    Erased {
+        start: u32,
+        end: u32,
     },
-    Xds110UsbDeviceMatch {
-        vid: 0x1cbe,
-        pid: 0x02a5,
-        epin: 0x81,
-        epout: 0x01,
-        interface: 0,
+    /// The configuration was written back unchanged, it selects this mode
+    Rewritten(ProbeMode),
+    /// The number of firmware bytes written
+    Flashed(usize),
+    /// The firmware was erased, and this many bytes of a new image written
+    Recovered(Option<usize>),
+    /// The configuration was printed
+    Dumped,
+    /// Target power was switched on if true, off if false
+    Powered(bool),
+    /// The probe is in the bootloader, at this bus and address if known
+    Rebooted(Option<(u8, u8)>),
+    /// The number of --repeat iterations that ran and how many of them failed
+    Repeated {
+        iterations: u32,
+        failed: u32,
     },
-];
+}
 
-pub struct Xds110UsbDevice {
-    device_handle: nusb::Interface,
-    epout: u8,
-    epin: u8,
+/// Human-readable progress on stdout
+struct ProgressObserver {
+    /// Print transfer stats
+    verbose: bool,
 }
 
-pub struct Xds110DfuDevice {
-    device_handle: nusb::Device,
-    packet_count: u16,
+impl ProgressObserver {
+    fn bar(label: &str, done: usize, total: usize) {
+        const WIDTH: usize = 32;
+        let filled = (done * WIDTH).checked_div(total).unwrap_or(WIDTH);
+        print!(
+            "\r{} [{}{}] {}/{}",
+            label,
+            "#".repeat(filled),
+            " ".repeat(WIDTH - filled),
+            done,
+            total
+        );
+        if done >= total {
+            println!();
+        }
+        std::io::stdout().flush().ok();
+    }
 }
 
-impl Xds110UsbDevice {
-    pub fn reboot_to_dfu(self) -> Result<(), std::io::Error> {
-        // Send the "Reboot to DFU mode" packet.
-        self.device_handle.write_bulk(
-            self.epout,
-            &[0x2a, 0x01, 0x00, 0x26],
-            Duration::from_secs(1),
-        )?;
-        Ok(())
-    }
-    pub fn firmware_version(&self) -> Result<u32, std::io::Error> {
-        let timeout = Duration::from_millis(100);
-        self.device_handle
-            .write_bulk(self.epout, &[0x2a, 0x01, 0x00, 0x03], timeout)?;
-        let mut version = [0u8; 13];
-        let response = self
-            .device_handle
-            .read_bulk(self.epin, &mut version, timeout)?;
-        if response < 11 {
-            return Err(std::io::ErrorKind::InvalidData.into());
-        }
-        Ok(u32::from_le_bytes(version[7..11].try_into().unwrap()))
+impl Observer for ProgressObserver {
+    fn phase(&self, phase: Phase) {
+        match phase {
+            Phase::EnteringDfu => println!("Rebooting into DFU mode..."),
+            Phase::ReadingConfig { done, total } => Self::bar("Reading configuration", done, total),
+            Phase::ReadingFlash { done, total } => Self::bar("Reading flash", done, total),
+            Phase::WritingConfig { done, total } => Self::bar("Writing configuration", done, total),
+            Phase::WritingFirmware { done, total } => Self::bar("Writing firmware", done, total),
+            Phase::Resetting => println!("Resetting into normal mode"),
+            Phase::ReadStats(stats) if self.verbose => println!("read {}", stats),
+            Phase::WriteStats(stats) if self.verbose => println!("wrote {}", stats),
+            Phase::ReadStats(_) | Phase::WriteStats(_) => {}
+            Phase::EnsuringBinaryProtocol | Phase::ReadCommandSent | Phase::WriteComplete => {}
+        }
+    }
+
+    fn warning(&self, warning: &Warning) {
+        // Too old a firmware is worth seeing even with stdout redirected
+        match warning {
+            Warning::FirmwareTooOld { .. } => eprintln!("{}", describe(warning)),
+            _ => println!("{}", describe(warning)),
+        }
     }
 }
 
-impl Xds110DfuDevice {
-    /// Ensure the target speaks the Tiva DFU binary protocol
-    pub fn ensure_binary_protocol(&self) -> Result<(), nusb::transfer::TransferError> {
-        block_on(self.device_handle.control_in(ControlIn {
-            control_type: nusb::transfer::ControlType::Class,
-            recipient: nusb::transfer::Recipient::Interface,
-            request: 0x42,
-            value: 0x23,
-            index: 0,
-            length: 4,
-        }))
-        .into_result()?;
-        Ok(())
-    }
-
-    /// This must be called after every operation
-    fn get_dfu_status(&self) -> Result<Vec<u8>, nusb::transfer::TransferError> {
-        block_on(self.device_handle.control_in(ControlIn {
-            control_type: nusb::transfer::ControlType::Class,
-            recipient: nusb::transfer::Recipient::Interface,
-            request: 3,
-            value: 0,
-            index: 0,
-            length: 6,
-        }))
-        .into_result()
-    }
-
-    pub fn read_configuration(&mut self) -> Result<Vec<u8>, nusb::transfer::TransferError> {
-        // DFU_GETSTATUS
-        println!("Getting DFU status...");
-        let status = block_on(self.device_handle.control_in(ControlIn {
-            control_type: nusb::transfer::ControlType::Class,
-            recipient: nusb::transfer::Recipient::Interface,
-            request: 3,
-            value: self.packet_count,
-            index: 0,
-            length: 6,
-        }))
-        .into_result()?;
-        println!("DFU status: {:x?}", status);
-        self.packet_count += 1;
-        self.get_dfu_status()?;
-
-        // DFU_CMD_READ
-        println!("Setting read command address...");
-        block_on(self.device_handle.control_out(ControlOut {
-            control_type: nusb::transfer::ControlType::Class,
-            recipient: nusb::transfer::Recipient::Interface,
-            request: 1,
-            value: self.packet_count,
-            index: 0,
-            data: &[
-                2, // DFU_CMD_READ
-                0, // Reserved
-                0xf0,
-                0x03,                                // Start block number
-                CONFIGURATION_SIZE.to_le_bytes()[0], // Image size
-                CONFIGURATION_SIZE.to_le_bytes()[1],
-                0,
-                0,
-            ],
-        }))
-        .into_result()?;
-        self.packet_count += 1;
-        self.get_dfu_status()?;
-
-        // Disable the DFU header when reading back
-        println!("Disabling read header...");
-        block_on(self.device_handle.control_out(ControlOut {
-            control_type: nusb::transfer::ControlType::Class,
-            recipient: nusb::transfer::Recipient::Interface,
-            request: 1,
-            value: self.packet_count,
-            index: 0,
-            data: &[
-                6, // DFU_CMD_BIN
-                1, // Disable upload prefix
-                0, 0, 0, 0, 0, 0, 0, 0, 0,
-            ],
-        }))
-        .into_result()?;
-        self.get_dfu_status()?;
-        self.packet_count += 1;
-
-        println!("Reading data...");
-        let mut configuration = vec![];
-        let mut offset = 0;
-        while offset < 16384 {
-            let bytes = block_on(self.device_handle.control_in(ControlIn {
-                control_type: nusb::transfer::ControlType::Class,
-                recipient: nusb::transfer::Recipient::Interface,
-                request: 2,
-                value: self.packet_count,
-                index: offset,
-                length: 1024,
-            }))
-            .into_result()?;
-            self.packet_count += 1;
-            configuration.extend_from_slice(&bytes);
-            offset += 1024;
-        }
-        self.get_dfu_status()?;
-        Ok(configuration)
-    }
-
-    fn write_configuration(
-        &mut self,
-        configuration: &[u8],
-    ) -> Result<(), nusb::transfer::TransferError> {
-        if configuration.len() != CONFIGURATION_SIZE as usize {
-            panic!("Configuration length is unexpected");
-        }
-
-        // DFU_CMD_WRITE
-        println!("Setting write command address...");
-        block_on(self.device_handle.control_out(ControlOut {
-            control_type: nusb::transfer::ControlType::Class,
-            recipient: nusb::transfer::Recipient::Interface,
-            request: 1,
-            value: self.packet_count,
-            index: 0,
-            data: &[
-                1, // DFU_CMD_WRITE
-                0, // Reserved
-                0xf0,
-                0x03,                                // Start block number
-                CONFIGURATION_SIZE.to_le_bytes()[0], // Image size
-                CONFIGURATION_SIZE.to_le_bytes()[1],
-                0,
-                0,
-            ],
-        }))
-        .into_result()?;
-        self.packet_count += 1;
-        self.get_dfu_status()?;
-
-        for data in configuration.chunks(1024) {
-            // Wait for the device to be ready to receive bytes
-            while self.get_dfu_status()?[4] != 5 {}
-            block_on(self.device_handle.control_out(ControlOut {
-                control_type: nusb::transfer::ControlType::Class,
-                recipient: nusb::transfer::Recipient::Interface,
-                request: 1,
-                value: self.packet_count,
-                index: 0,
-                data,
-            }))
-            .into_result()?;
-            self.packet_count += 1;
-        }
-
-        // Finish the download
-        while self.get_dfu_status()?[4] != 5 {}
-        block_on(self.device_handle.control_out(ControlOut {
-            control_type: nusb::transfer::ControlType::Class,
-            recipient: nusb::transfer::Recipient::Interface,
-            request: 1,
-            value: self.packet_count,
-            index: 0,
-            data: &[],
-        }))
-        .into_result()?;
-        self.packet_count += 1;
-
-        while self.get_dfu_status()?[4] != 2 {}
-
-        Ok(())
-    }
-
-    fn reset(mut self) -> Result<(), nusb::transfer::TransferError> {
-        while self.get_dfu_status()?[4] != 2 {}
-        // DFU_CMD_RESET
-        block_on(self.device_handle.control_out(ControlOut {
-            control_type: nusb::transfer::ControlType::Class,
-            recipient: nusb::transfer::Recipient::Interface,
-            request: 1,
-            value: self.packet_count,
-            index: 0,
-            data: &[
-                7, // DFU_CMD_RESET
-                0x20, 0xdf, 0x00, 0x01, 0, 0, 0,
-            ],
-        }))
-        .into_result()?;
-        self.packet_count += 1;
+/// Only the warnings, on stderr, for --quiet and for read-config, whose
+/// stdout is taken by the configuration
+struct WarningObserver;
 
-        while self.get_dfu_status()?[4] != 2 {}
+impl Observer for WarningObserver {
+    fn warning(&self, warning: &Warning) {
+        eprintln!("{}", describe(warning));
+    }
+}
 
-        Ok(())
+/// `warning` as [`ProgressObserver`] and [`WarningObserver`] print it
+fn describe(warning: &Warning) -> String {
+    match warning {
+        Warning::MagicRepaired { found } => format!(
+            "Warning: Magic value not found! Expected {:02x?}, found {:02x?}",
+            MAGIC_BYTES, found
+        ),
+        Warning::FirmwareTooOld { found, minimum } => format!(
+            "WARNING: firmware {} is older than {}, the minimum for CMSIS-DAP 2.0.\n\
+             WARNING: continuing because of --force. This is unsupported and may fail.",
+            found, minimum
+        ),
+        Warning::PrefixAddressMismatch { prefix, used } => format!(
+            "Warning: the image is for {:#010x} but goes to {:#010x}",
+            prefix, used
+        ),
+        Warning::UnexpectedStatus { state, status } => format!(
+            "Warning: the bootloader is in {:?} with status {}",
+            state, status
+        ),
+        Warning::InterruptedSession { state, status } => format!(
+            "Warning: found the bootloader in {:?} with status {}, left by an \
+             interrupted session; brought it back to idle",
+            state, status
+        ),
+        Warning::Retry { operation, attempt } => format!(
+            "Warning: {} failed, retrying (attempt {})",
+            operation, attempt
+        ),
     }
 }
 
-fn open_xds110() -> Result<Xds110UsbDevice, std::io::Error> {
-    let devices = nusb::list_devices()?;
-    let mut device_info = None;
-    'next_device: for candidate_device in devices {
-        for candidate_match in XDS110_USB_DEVICES {
-            if candidate_device.vendor_id() == candidate_match.vid
-                && candidate_device.product_id() == candidate_match.pid
-            {
-                if device_info.is_some() {
-                    return Err(std::io::ErrorKind::TooManyLinks.into());
-                }
-                device_info = Some((
-                    candidate_device,
-                    candidate_match.epin,
-                    candidate_match.epout,
-                    candidate_match.interface,
-                ));
-                break 'next_device;
+/// One JSON object per line on stdout, for scripts
+struct NdjsonObserver;
+
+impl Observer for NdjsonObserver {
+    fn phase(&self, phase: Phase) {
+        match phase {
+            Phase::EnteringDfu => println!(r#"{{"event":"phase","phase":"entering_dfu"}}"#),
+            Phase::EnsuringBinaryProtocol => {
+                println!(r#"{{"event":"phase","phase":"ensuring_binary_protocol"}}"#)
+            }
+            Phase::ReadCommandSent => {
+                println!(r#"{{"event":"phase","phase":"read_command_sent"}}"#)
+            }
+            Phase::ReadingConfig { done, total } => println!(
+                r#"{{"event":"phase","phase":"reading_config","done":{},"total":{}}}"#,
+                done, total
+            ),
+            Phase::ReadingFlash { done, total } => println!(
+                r#"{{"event":"phase","phase":"reading_flash","done":{},"total":{}}}"#,
+                done, total
+            ),
+            Phase::WritingConfig { done, total } => println!(
+                r#"{{"event":"phase","phase":"writing_config","done":{},"total":{}}}"#,
+                done, total
+            ),
+            Phase::WritingFirmware { done, total } => println!(
+                r#"{{"event":"phase","phase":"writing_firmware","done":{},"total":{}}}"#,
+                done, total
+            ),
+            Phase::WriteComplete => println!(r#"{{"event":"phase","phase":"write_complete"}}"#),
+            Phase::Resetting => println!(r#"{{"event":"phase","phase":"resetting"}}"#),
+            Phase::ReadStats(stats) | Phase::WriteStats(stats) => {
+                let name = match phase {
+                    Phase::ReadStats(_) => "read_stats",
+                    _ => "write_stats",
+                };
+                println!(
+                    r#"{{"event":"phase","phase":"{}","bytes":{},"duration_ms":{},"chunks":{}}}"#,
+                    name,
+                    stats.bytes,
+                    stats.duration.as_millis(),
+                    stats.chunks
+                )
             }
         }
     }
 
-    let Some((device, epin, epout, iface)) = device_info else {
-        return Err(std::io::ErrorKind::NotFound.into());
-    };
-
-    let mut epout_found = false;
-    let mut epin_found = false;
+    fn warning(&self, warning: &Warning) {
+        match warning {
+            Warning::MagicRepaired { found } => println!(
+                r#"{{"event":"warning","warning":"magic_repaired","found":"{:02x}{:02x}"}}"#,
+                found[0], found[1]
+            ),
+            Warning::FirmwareTooOld { found, minimum } => println!(
+                r#"{{"event":"warning","warning":"firmware_too_old","found":"{}","minimum":"{}"}}"#,
+                found, minimum
+            ),
+            Warning::PrefixAddressMismatch { prefix, used } => println!(
+                r#"{{"event":"warning","warning":"prefix_address_mismatch","prefix":{},"used":{}}}"#,
+                prefix, used
+            ),
+            Warning::UnexpectedStatus { state, status } => println!(
+                r#"{{"event":"warning","warning":"unexpected_status","state":{},"status":{}}}"#,
+                json_string(&format!("{:?}", state)),
+                status
+            ),
+            Warning::InterruptedSession { state, status } => println!(
+                r#"{{"event":"warning","warning":"interrupted_session","state":{},"status":{}}}"#,
+                json_string(&format!("{:?}", state)),
+                status
+            ),
+            Warning::Retry { operation, attempt } => println!(
+                r#"{{"event":"warning","warning":"retry","operation":{},"attempt":{}}}"#,
+                json_string(operation),
+                attempt
+            ),
+        }
+    }
+}
 
-    let device_handle = device.open()?;
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
 
-    let mut configs = device_handle.configurations();
-    let Some(config) = configs.next() else {
-        return Err(std::io::ErrorKind::NotFound.into());
+fn run(args: &Args, trace: Option<StatusTrace>) -> Result<Outcome, Box<dyn core::error::Error>> {
+    // Progress would end up in the middle of the dumped configuration, but
+    // a warning such as forcing a switch on too old a firmware must not be
+    // lost
+    let observer: Option<Arc<dyn Observer>> = if args.quiet || args.read_config {
+        Some(Arc::new(WarningObserver))
+    } else if args.ndjson {
+        Some(Arc::new(NdjsonObserver))
+    } else {
+        Some(Arc::new(ProgressObserver {
+            verbose: args.verbose,
+        }))
     };
-    let Some(interface) = config.interfaces().find(|x| x.interface_number() == iface) else {
-        return Err(std::io::ErrorKind::NotFound.into());
+
+    let cancel = CancelToken::new();
+    install_interrupt_handler(cancel.clone());
+
+    let options = SwitchOptions {
+        observer,
+        cancel: Some(cancel.clone()),
+        force: args.force,
+        status_trace: trace,
+        version_timeout: args.version_timeout,
+        no_verify: args.no_verify,
+        reset_address: args.reset_address,
+        ..Default::default()
     };
 
-    for alt_setting in interface.alt_settings() {
-        for endpoint in alt_setting.endpoints() {
-            if endpoint.address() == epout {
-                epout_found = true;
-            } else if endpoint.address() == epin {
-                epin_found = true;
+    if let Some(count) = args.repeat {
+        return Ok(repeat_switch(args, count, &options));
+    }
+
+    let probe = open_probe(args)?;
+    if args.reboot {
+        if let (Xds110Probe::Runtime(_), Some(observer)) = (&probe, &options.observer) {
+            observer.phase(Phase::EnteringDfu);
+        }
+        // Dropping the bootloader leaves the probe in it
+        let dfu = probe.enter_dfu_with_cancel(&cancel)?;
+        return Ok(Outcome::Rebooted(dfu.bus_address()));
+    }
+    if let Some((start_block, num_blocks)) = args.erase {
+        let start = start_block as u32 * BLOCK_SIZE;
+        let end = start + num_blocks as u32 * BLOCK_SIZE;
+        if args.ndjson {
+            println!(r#"{{"event":"erasing","start":{},"end":{}}}"#, start, end);
+        } else {
+            println!(
+                "Erasing {:#010x}..{:#010x} ({} blocks)",
+                start, end, num_blocks
+            );
+        }
+        erase_flash(probe, start_block, num_blocks, &options)?;
+        return Ok(Outcome::Erased { start, end });
+    }
+    if args.recover {
+        // Read the image first, so a bad path does not leave the probe erased
+        let image = match &args.image {
+            Some(path) => Some(std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?),
+            None => None,
+        };
+        confirm_serial(probe.serial(), &cancel)?;
+        return Ok(Outcome::Recovered(recover_probe(
+            probe,
+            image.as_deref(),
+            &options,
+        )?));
+    }
+    if let Some(on) = args.power {
+        probe.leave_dfu()?.power_target(on)?;
+        return Ok(Outcome::Powered(on));
+    }
+    if args.read_config {
+        let configuration = read_config(probe, &options)?;
+        let mut stdout = std::io::stdout().lock();
+        if args.raw {
+            stdout.write_all(configuration.to_bytes())?;
+        } else {
+            hexdump(&mut stdout, configuration.to_bytes())?;
+        }
+        stdout.flush()?;
+        return Ok(Outcome::Dumped);
+    }
+    if args.test_write {
+        return Ok(Outcome::Rewritten(rewrite_config(probe, &options)?));
+    }
+    if let Some(path) = &args.flash {
+        let file = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let size = flash_firmware_file(probe, &file, &options)?;
+        return Ok(Outcome::Flashed(size));
+    }
+    Ok(match &args.patches {
+        Some(patches) => Outcome::Patched(patch_config(probe, patches, args.force, &options)?),
+        None => Outcome::Switched(switch_mode(probe, args.mode, &options)?),
+    })
+}
+
+/// Write `bytes` to `out` 16 to a line, as offset, hex and ASCII like `xxd`
+fn hexdump(out: &mut impl Write, bytes: &[u8]) -> std::io::Result<()> {
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        write!(out, "{:08x}:", line * 16)?;
+        for pair in chunk.chunks(2) {
+            write!(out, " ")?;
+            for byte in pair {
+                write!(out, "{:02x}", byte)?;
             }
         }
+        let text: String = chunk
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(out, "  {}", text)?;
+    }
+    Ok(())
+}
+
+/// Have the serial number of `probe` typed in on stdin before erasing it, so
+/// that the wrong probe is not wiped by mistake. Gives up with
+/// [`Xds110Error::Cancelled`] once `cancel` is cancelled.
+fn confirm_serial(
+    serial: Option<&str>,
+    cancel: &CancelToken,
+) -> Result<(), Box<dyn core::error::Error>> {
+    let serial = serial.ok_or("the probe has no serial number to confirm the erase with")?;
+    eprint!(
+        "This erases the firmware of the probe with serial number {}.\n\
+         Type the serial number to continue: ",
+        serial
+    );
+    let _ = std::io::stderr().flush();
+    let result = confirm_typed(serial, read_stdin_line, cancel);
+    if let Some(Xds110Error::Cancelled) = result.as_ref().err().and_then(|e| e.downcast_ref()) {
+        eprintln!();
     }
+    result
+}
+
+fn read_stdin_line() -> std::io::Result<String> {
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).map(|_| line)
+}
 
-    if !epout_found || !epin_found {
-        return Err(std::io::ErrorKind::NotFound.into());
+/// Check that the line `read_line` returns is `serial`, giving up once
+/// `cancel` is cancelled
+fn confirm_typed(
+    serial: &str,
+    read_line: impl FnOnce() -> std::io::Result<String> + Send + 'static,
+    cancel: &CancelToken,
+) -> Result<(), Box<dyn core::error::Error>> {
+    // A blocked read cannot be interrupted, so it happens on a thread that
+    // is left behind if the wait is cancelled
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(read_line());
+    });
+    let line = loop {
+        if cancel.is_cancelled() {
+            return Err(Xds110Error::Cancelled.into());
+        }
+        match rx.recv_timeout(CONFIRM_POLL_INTERVAL) {
+            Ok(line) => break line.map_err(|e| format!("reading confirmation: {}", e))?,
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                return Err("reading confirmation failed".into());
+            }
+        }
+    };
+    if !confirms(&line, serial) {
+        return Err("serial number did not match, nothing was erased".into());
     }
+    Ok(())
+}
 
-    let device_handle = device_handle.claim_interface(iface)?;
+/// Whether `line`, as typed in, is `serial`
+fn confirms(line: &str, serial: &str) -> bool {
+    line.trim() == serial
+}
 
-    Ok(Xds110UsbDevice {
-        device_handle,
-        epout,
-        epin,
-    })
+fn open_probe(args: &Args) -> Result<Xds110Probe, Xds110Error> {
+    match &args.port_path {
+        Some((bus, ports)) => Xds110Probe::open_by_path(*bus, ports),
+        None => Xds110Probe::open(),
+    }
 }
 
-fn open_dfu() -> Result<Xds110DfuDevice, std::io::Error> {
-    let devices = nusb::list_devices()?;
-    let mut device_info = None;
-    'next_device: for candidate_device in devices {
-        for candidate_match in XDS110_DFU_DEVICES {
-            if candidate_device.vendor_id() == candidate_match.vid
-                && candidate_device.product_id() == candidate_match.pid
-            {
-                if device_info.is_some() {
-                    return Err(std::io::ErrorKind::TooManyLinks.into());
-                }
-                device_info = Some(candidate_device);
-                break 'next_device;
+/// Switch to `args.mode` and back to the mode the probe started in, `count`
+/// times over. Failures are reported as they happen rather than ending the
+/// run, as the point is to see how often they happen.
+fn repeat_switch(args: &Args, count: u32, options: &SwitchOptions) -> Outcome {
+    // Not known until the first switch succeeds
+    let mut original = None;
+    let mut failed = 0;
+    for iteration in 1..=count {
+        if args.ndjson {
+            println!(
+                r#"{{"event":"iteration","iteration":{},"count":{}}}"#,
+                iteration, count
+            );
+        } else if !args.quiet {
+            println!("Iteration {}/{}", iteration, count);
+        }
+        let result = open_probe(args)
+            .and_then(|probe| switch_mode(probe, args.mode, options))
+            .and_then(|outcome| {
+                let from = match outcome {
+                    SwitchOutcome::AlreadyInMode => args.mode,
+                    SwitchOutcome::Switched { from } => from,
+                };
+                let original = *original.get_or_insert(from);
+                switch_mode(open_probe(args)?, original, options).map(drop)
+            });
+        if let Err(e) = result {
+            failed += 1;
+            if args.ndjson {
+                println!(
+                    r#"{{"event":"iteration_failed","iteration":{},"message":{}}}"#,
+                    iteration,
+                    json_string(&e.to_string())
+                );
+            } else {
+                eprintln!("Iteration {} failed: {}", iteration, e);
+            }
+            if matches!(e, Xds110Error::Cancelled) {
+                return Outcome::Repeated {
+                    iterations: iteration,
+                    failed,
+                };
             }
         }
     }
+    Outcome::Repeated {
+        iterations: count,
+        failed,
+    }
+}
 
-    let Some(device) = device_info else {
-        eprintln!("Unable to find device");
-        return Err(std::io::ErrorKind::NotFound.into());
-    };
+/// Cancelled by SIGINT and SIGTERM
+static INTERRUPT: OnceLock<CancelToken> = OnceLock::new();
 
-    let device_handle = device.open()?;
+/// Turn the first SIGINT or SIGTERM into cancelling `token`, so the
+/// operation in progress stops between transfers and leaves the bootloader
+/// idle instead of dying half way through a flash write. A second signal
+/// ends the process as usual.
+#[cfg(unix)]
+fn install_interrupt_handler(token: CancelToken) {
+    extern "C" fn handle(_signal: libc::c_int) {
+        // Only an atomic store, which is safe in a signal handler
+        if let Some(token) = INTERRUPT.get() {
+            token.cancel();
+        }
+    }
 
-    // TODO: We may need to claim interface 0 on Windows, in which case this
-    // struct will need to grow an `enum`.
+    if INTERRUPT.set(token).is_err() {
+        return;
+    }
+    // SAFETY: the action is fully initialized and the handler only touches
+    // an already initialized OnceLock and an atomic
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESETHAND;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+        libc::sigaction(libc::SIGTERM, &action, std::ptr::null_mut());
+    }
+}
 
-    Ok(Xds110DfuDevice {
-        device_handle,
-        packet_count: 0,
-    })
+#[cfg(not(unix))]
+fn install_interrupt_handler(_token: CancelToken) {}
+
+/// Record every transfer to `path`, as JSON lines if `json`
+#[cfg(feature = "transcript")]
+fn start_transcript(path: &str, json: bool) -> Result<(), String> {
+    use turbo_110::TranscriptFormat;
+    let file = std::fs::File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    let format = if json {
+        TranscriptFormat::JsonLines
+    } else {
+        TranscriptFormat::Text
+    };
+    turbo_110::record_transcript_as(file, format);
+    Ok(())
 }
 
-fn main() -> Result<(), Box<dyn core::error::Error>> {
-    let mut dfu = match open_dfu() {
-        Ok(dfu) => dfu,
-        Err(_) => {
-            let xds110 = open_xds110()?;
-            let version = xds110.firmware_version()?;
-
-            if version < CMSIS_DAP_2_MINIMUM {
-                let found = version.to_be_bytes();
-                let minimum = CMSIS_DAP_2_MINIMUM.to_be_bytes();
-                return Err(format!("CMSIS-DAP 2.0 is only supported on firmware versions >= {:02x}.{:02x}.{:02x}.{:02x} -- Your firmware is {:02x}.{:02x}.{:02x}.{:02x}",
-            minimum[0], minimum[1], minimum[2], minimum[3],
-            found[0], found[1], found[2], found[3]
-        ).into());
-            }
+#[cfg(not(feature = "transcript"))]
+fn start_transcript(_path: &str, json: bool) -> Result<(), String> {
+    let option = if json { "--log-file" } else { "--transcript" };
+    Err(format!(
+        "{} needs turbo-110 built with the transcript feature",
+        option
+    ))
+}
 
-            xds110.reboot_to_dfu()?;
-            // Wait for it to re-enumerate (TODO: Longer polling time?)
-            std::thread::sleep(Duration::from_secs(1));
-            open_dfu()?
+fn main() {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(EXIT_FAILURE);
         }
     };
 
-    println!("Ensuring Tiva protocol...");
-    dfu.ensure_binary_protocol()?;
-    println!("Reading current configuration...");
-    let mut configuration = dfu.read_configuration()?;
+    let transcript = match (&args.transcript, &args.log_file) {
+        (Some(path), _) => Some((path, false)),
+        (None, Some(path)) => Some((path, true)),
+        (None, None) => None,
+    };
+    if let Some((path, json)) = transcript
+        && let Err(e) = start_transcript(path, json)
+    {
+        eprintln!("{}", e);
+        std::process::exit(EXIT_FAILURE);
+    }
 
-    if configuration[18..20] != [0x55, 0xaa] {
-        println!(
-            "Warning: Magic value not found! Expected [0x55, 0xaa], found {:02x?}",
-            &configuration[18..20]
+    let trace = args.trace_dfu.then(StatusTrace::new);
+
+    let result = run(&args, trace.clone());
+    let unchanged = matches!(
+        result,
+        Ok(Outcome::Switched(SwitchOutcome::AlreadyInMode) | Outcome::Patched(0))
+    );
+    let repeat_failed = matches!(result, Ok(Outcome::Repeated { failed, .. }) if failed > 0);
+    match result {
+        Ok(Outcome::Switched(SwitchOutcome::AlreadyInMode)) if args.ndjson => println!(
+            r#"{{"event":"result","outcome":"already_in_mode","mode":{}}}"#,
+            args.mode.number()
+        ),
+        Ok(Outcome::Switched(SwitchOutcome::AlreadyInMode)) => {
+            println!("Device was already in mode {}", args.mode)
+        }
+        Ok(Outcome::Switched(SwitchOutcome::Switched { from })) if args.ndjson => println!(
+            r#"{{"event":"result","outcome":"switched","from":{},"to":{}}}"#,
+            from.number(),
+            args.mode.number()
+        ),
+        Ok(Outcome::Switched(SwitchOutcome::Switched { from })) => {
+            println!("Updated device from mode {} to mode {}", from, args.mode)
+        }
+        Ok(Outcome::Patched(changed)) if args.ndjson => println!(
+            r#"{{"event":"result","outcome":"patched","changed":{}}}"#,
+            changed
+        ),
+        Ok(Outcome::Erased { start, end }) if args.ndjson => println!(
+            r#"{{"event":"result","outcome":"erased","start":{},"end":{}}}"#,
+            start, end
+        ),
+        Ok(Outcome::Erased { start, end }) => println!("Erased {:#010x}..{:#010x}", start, end),
+        Ok(Outcome::Rewritten(mode)) if args.ndjson => println!(
+            r#"{{"event":"result","outcome":"rewritten","mode":{}}}"#,
+            mode.number()
+        ),
+        Ok(Outcome::Rewritten(mode)) => println!(
+            "Device is in mode {}, rewrote its configuration unchanged and it verified",
+            mode
+        ),
+        Ok(Outcome::Flashed(size)) if args.ndjson => println!(
+            r#"{{"event":"result","outcome":"flashed","size":{}}}"#,
+            size
+        ),
+        Ok(Outcome::Flashed(size)) => println!("Flashed and verified {} bytes", size),
+        Ok(Outcome::Recovered(size)) if args.ndjson => match size {
+            Some(size) => println!(
+                r#"{{"event":"result","outcome":"recovered","size":{}}}"#,
+                size
+            ),
+            None => println!(r#"{{"event":"result","outcome":"recovered"}}"#),
+        },
+        Ok(Outcome::Recovered(Some(size))) => {
+            println!("Erased the firmware, flashed and verified {} bytes", size)
+        }
+        Ok(Outcome::Dumped) => {}
+        Ok(Outcome::Powered(on)) if args.ndjson => {
+            println!(r#"{{"event":"result","outcome":"powered","on":{}}}"#, on)
+        }
+        Ok(Outcome::Powered(on)) => {
+            println!("Target power is {}", if on { "on" } else { "off" })
+        }
+        Ok(Outcome::Recovered(None)) => {
+            println!("Erased the firmware, the probe is waiting in its DFU bootloader")
+        }
+        Ok(Outcome::Rebooted(location)) if args.ndjson => match location {
+            Some((bus, address)) => println!(
+                r#"{{"event":"result","outcome":"rebooted","bus":{},"address":{}}}"#,
+                bus, address
+            ),
+            None => println!(r#"{{"event":"result","outcome":"rebooted"}}"#),
+        },
+        Ok(Outcome::Rebooted(Some((bus, address)))) => println!("{}:{}", bus, address),
+        Ok(Outcome::Rebooted(None)) => println!("Device is in DFU mode"),
+        Ok(Outcome::Repeated { iterations, failed }) if args.ndjson => println!(
+            r#"{{"event":"result","outcome":"repeated","iterations":{},"failed":{}}}"#,
+            iterations, failed
+        ),
+        Ok(Outcome::Repeated { iterations, failed }) => {
+            println!("{} of {} iterations failed", failed, iterations)
+        }
+        Ok(Outcome::Patched(0)) => println!("Configuration already matched, nothing written"),
+        Ok(Outcome::Patched(changed)) => {
+            println!("Patched and verified {} configuration bytes", changed)
+        }
+        Err(e) => {
+            if args.ndjson {
+                println!(
+                    r#"{{"event":"error","message":{}}}"#,
+                    json_string(&e.to_string())
+                );
+            } else {
+                eprintln!("Error: {}", e);
+            }
+            if !args.ndjson && matches!(e.downcast_ref(), Some(Xds110Error::Cancelled)) {
+                eprintln!(
+                    "Aborted safely: nothing was cut off half way, the probe is idle in its \
+                     DFU bootloader or running its firmware. Run turbo-110 again to finish \
+                     the job."
+                );
+            }
+            if let Some(trace) = trace {
+                eprintln!("DFU status trace:");
+                eprint!("{}", trace);
+            }
+            std::process::exit(exit_code(e.as_ref()));
+        }
+    }
+    if repeat_failed {
+        std::process::exit(EXIT_FAILURE);
+    }
+    if unchanged {
+        std::process::exit(EXIT_UNCHANGED);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(argv: &[&str]) -> Result<Args, String> {
+        Args::parse_from(argv.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn recover_requires_mass_erase_and_force() {
+        let error = parse(&["recover", "--force"]).err().unwrap();
+        assert!(error.starts_with("recover requires --mass-erase"));
+        let error = parse(&["recover", "--mass-erase"]).err().unwrap();
+        assert!(error.contains("requires --force"));
+
+        let args = parse(&["recover", "--mass-erase", "--force"]).unwrap();
+        assert!(args.recover && args.mass_erase && args.force);
+        assert_eq!(args.image, None);
+        let args = parse(&["recover", "--mass-erase", "--image", "fw.bin", "--force"]).unwrap();
+        assert_eq!(args.image.as_deref(), Some("fw.bin"));
+    }
+
+    #[test]
+    fn mass_erase_only_goes_with_recover() {
+        assert!(parse(&["--mass-erase", "--force"]).is_err());
+        assert!(parse(&["erase", "0", "1", "--mass-erase"]).is_err());
+    }
+
+    #[test]
+    fn confirmation_must_be_the_serial() {
+        assert!(confirms("MOCK0001\n", "MOCK0001"));
+        assert!(confirms("  MOCK0001 \r\n", "MOCK0001"));
+        assert!(!confirms("\n", "MOCK0001"));
+        assert!(!confirms("MOCK000\n", "MOCK0001"));
+        assert!(!confirms("mock0001\n", "MOCK0001"));
+        assert!(!confirms("yes\n", "MOCK0001"));
+    }
+
+    fn typed(line: &'static str) -> impl FnOnce() -> std::io::Result<String> + Send + 'static {
+        move || Ok(line.to_owned())
+    }
+
+    #[test]
+    fn typed_serial_lets_the_erase_go_ahead() {
+        let cancel = CancelToken::new();
+        confirm_typed("MOCK0001", typed("MOCK0001\n"), &cancel).unwrap();
+
+        let error = confirm_typed("MOCK0001", typed("MOCK0002\n"), &cancel).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "serial number did not match, nothing was erased"
         );
-        configuration[17] = 0;
-        configuration[18] = 0x55;
-        configuration[19] = 0xaa;
+        let error = confirm_typed("MOCK0001", typed(""), &cancel).unwrap_err();
+        assert!(error.to_string().contains("did not match"));
     }
-    let current_mode = u16::from_le_bytes(configuration[16..18].try_into().unwrap());
-    println!("Current mode: {:02x?}", current_mode);
 
-    if current_mode == 4 {
-        println!("Device was already in mode 4");
-        dfu.reset()?;
-        return Ok(());
+    #[test]
+    fn failed_read_refuses_the_erase() {
+        let cancel = CancelToken::new();
+        let broken = || Err(std::io::ErrorKind::BrokenPipe.into());
+        let error = confirm_typed("MOCK0001", broken, &cancel).unwrap_err();
+        assert!(error.to_string().starts_with("reading confirmation"));
     }
-    println!("Updating device from mode {} to mode 4", current_mode);
-    configuration[16] = 4;
 
-    dfu.write_configuration(&configuration)?;
-    println!("Resetting into normal mode");
-    dfu.reset()?;
+    #[test]
+    fn confirmation_without_a_serial_is_refused() {
+        let error = confirm_serial(None, &CancelToken::new()).unwrap_err();
+        assert!(error.to_string().contains("no serial number"));
+    }
 
-    Ok(())
+    #[test]
+    fn cancelled_confirmation_stops_waiting() {
+        let cancel = CancelToken::new();
+        // Nothing is ever typed in
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let never = move || {
+            let _ = rx.recv();
+            Ok(String::new())
+        };
+        let waiting = std::thread::spawn({
+            let cancel = cancel.clone();
+            move || confirm_typed("MOCK0001", never, &cancel).map_err(|e| e.to_string())
+        });
+        std::thread::sleep(CONFIRM_POLL_INTERVAL * 2);
+        assert!(!waiting.is_finished());
+        cancel.cancel();
+        let error = waiting.join().unwrap().unwrap_err();
+        assert_eq!(error, Xds110Error::Cancelled.to_string());
+        drop(tx);
+    }
 }
@@ -0,0 +1,182 @@
+//! The target's address space: how flash is laid out, and word-sized access.
+//!
+//! The Tiva bootloader addresses flash in 1024-byte blocks with a 16-bit
+//! block number, within the flash range it reported in
+//! [`FlashGeometry`](crate::dfu::FlashGeometry). Nothing outside flash can
+//! be reached, so word access is limited to reading flash.
+
+use crate::dfu::DFU_CMD_WRITE;
+use crate::{
+    CONFIGURATION_BLOCK, CONFIGURATION_SIZE, DFU_BLOCK_SIZE, Xds110DfuDevice, Xds110Error,
+};
+
+/// A part of flash with a single purpose, see
+/// [`Xds110DfuDevice::memory_map`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MemoryRegion {
+    /// What the region holds: `bootloader`, `application` or
+    /// `configuration`
+    pub name: &'static str,
+    pub start_address: u32,
+    pub size: u32,
+    /// Size of an erasable flash block, in bytes
+    pub page_size: u32,
+    pub readable: bool,
+    /// Whether this crate will write the region
+    pub writable: bool,
+    /// Whether this crate will erase the region
+    pub erasable: bool,
+}
+
+impl Xds110DfuDevice {
+    /// The regions of flash, lowest first, as laid out by the flash geometry
+    /// the bootloader reports. The bootloader is only writable once
+    /// [`set_unsafe_writes`](Self::set_unsafe_writes) allows it.
+    pub fn memory_map(&mut self) -> Result<Vec<MemoryRegion>, Xds110Error> {
+        let geometry = self.detect_flash_geometry()?;
+        let configuration = CONFIGURATION_BLOCK as u32 * DFU_BLOCK_SIZE;
+        let region = |name, start: u32, end: u32, writable| MemoryRegion {
+            name,
+            start_address: start,
+            size: end.saturating_sub(start),
+            page_size: geometry.block_size as u32,
+            readable: true,
+            writable,
+            erasable: writable,
+        };
+        Ok(vec![
+            region("bootloader", 0, geometry.app_start, self.unsafe_writes),
+            region("application", geometry.app_start, configuration, true),
+            region(
+                "configuration",
+                configuration,
+                configuration + CONFIGURATION_SIZE as u32,
+                true,
+            ),
+        ])
+    }
+
+    /// Read the little-endian word at `address`, which must be word aligned
+    /// and lie in flash. It is read out of the block that holds it, as the
+    /// bootloader's read command takes a block number and reaches nothing
+    /// but flash; SRAM and peripheral registers fail with
+    /// [`Xds110Error::UnreachableAddress`].
+    pub fn read_memory_word(&mut self, address: u32) -> Result<u32, Xds110Error> {
+        if !address.is_multiple_of(4) {
+            return Err(Xds110Error::UnreachableAddress(address));
+        }
+        self.check_flash_range(address, 4)?;
+        let block = (address / DFU_BLOCK_SIZE) as u16;
+        let offset = (address % DFU_BLOCK_SIZE) as usize;
+        let bytes = self.read_region(block, offset as u32 + 4)?;
+        let word = bytes
+            .get(offset..offset + 4)
+            .and_then(|word| word.try_into().ok())
+            .ok_or(Xds110Error::InvalidDfuResponse("DFU_UPLOAD"))?;
+        Ok(u32::from_le_bytes(word))
+    }
+
+    /// Write `value` little-endian to `address`. The bootloader cannot do
+    /// this anywhere, so this always fails without sending anything.
+    ///
+    /// Flash is refused with [`Xds110Error::FlashWordWrite`]. It is erased in
+    /// pages, 16 KiB on the TM4C129, and the bootloader erases a page as a
+    /// write reaches its start, so a word cannot be programmed on its own;
+    /// use [`write_region`](Self::write_region) on whole pages. Anything
+    /// outside flash, such as SRAM or a peripheral register, fails with
+    /// [`Xds110Error::Unsupported`], as the bootloader's write command takes
+    /// a block number and reaches nothing but flash.
+    pub fn write_memory_word(&mut self, address: u32, _value: u32) -> Result<(), Xds110Error> {
+        if !address.is_multiple_of(4) {
+            return Err(Xds110Error::UnreachableAddress(address));
+        }
+        if address < self.detect_flash_geometry()?.flash_top {
+            return Err(Xds110Error::FlashWordWrite(address));
+        }
+        Err(Xds110Error::Unsupported {
+            command: DFU_CMD_WRITE,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Xds110Error;
+    use crate::dfu::{DFU_CMD_WRITE, DFU_UPLOAD, DfuState};
+    use crate::mock::{self, MockBootloader};
+
+    #[test]
+    fn read_word_from_flash() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        mock.lock().flash[0x4400..0x4404].copy_from_slice(&[0x78, 0x56, 0x34, 0x12]);
+        mock.lock().flash[0x47fc..0x4800].copy_from_slice(&[1, 2, 3, 4]);
+
+        assert_eq!(dfu.read_memory_word(0x4400).unwrap(), 0x1234_5678);
+        assert_eq!(dfu.read_memory_word(0x47fc).unwrap(), 0x0403_0201);
+        // One upload for the flash geometry, then one for each read of
+        // block 17 from its start
+        let reads: Vec<_> = mock.lock().requests.iter().map(|r| r.0).collect();
+        assert_eq!(reads.iter().filter(|&&r| r == DFU_UPLOAD).count(), 3);
+        assert_eq!(mock.lock().state, DfuState::DfuIdle);
+    }
+
+    #[test]
+    fn read_word_outside_flash_is_refused() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        dfu.detect_flash_geometry().unwrap();
+        mock.lock().requests.clear();
+
+        for address in [mock::FLASH_TOP, 0x2000_0000, 0x400f_e000] {
+            let result = dfu.read_memory_word(address);
+            assert!(matches!(result, Err(Xds110Error::UnreachableAddress(a)) if a == address));
+        }
+        assert!(mock.lock().requests.is_empty());
+    }
+
+    #[test]
+    fn read_word_unaligned() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+
+        let result = dfu.read_memory_word(0x4402);
+        assert!(matches!(result, Err(Xds110Error::UnreachableAddress(_))));
+        assert!(mock.lock().requests.is_empty());
+    }
+
+    #[test]
+    fn write_word_outside_flash_is_unsupported() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        dfu.detect_flash_geometry().unwrap();
+        mock.lock().requests.clear();
+
+        let result = dfu.write_memory_word(0x2000_0200, 0xdead_beef);
+        assert!(matches!(
+            result,
+            Err(Xds110Error::Unsupported {
+                command: DFU_CMD_WRITE
+            })
+        ));
+        assert!(mock.lock().requests.is_empty());
+        assert_eq!(mock.lock().state, DfuState::DfuIdle);
+    }
+
+    #[test]
+    fn write_word_to_flash_is_refused() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        dfu.detect_flash_geometry().unwrap();
+        let flash = mock.lock().flash.clone();
+        mock.lock().requests.clear();
+
+        for address in [0, mock::APP_START + 0x404, mock::FLASH_TOP - 4] {
+            let result = dfu.write_memory_word(address, 0);
+            assert!(matches!(result, Err(Xds110Error::FlashWordWrite(a)) if a == address));
+        }
+        assert!(mock.lock().requests.is_empty());
+        assert!(mock.lock().flash == flash);
+    }
+}
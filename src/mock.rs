@@ -0,0 +1,800 @@
+//! A scripted XDS110 for the tests to talk to in place of USB.
+//!
+//! [`MockBootloader`] models the Tiva DFU bootloader with the flash behind
+//! it, strictly enough that a request it would not expect fails the test.
+//! [`MockFirmware`] answers bulk transfers the way the normal firmware does.
+//! A [`MockProbe`] has one of each, and switches between them as the real
+//! probe does when plugged into a [`MockBus`].
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use nusb::transfer::TransferError;
+
+use crate::bus::Bus;
+use crate::cmsis_dap::{CmsisDapInterface, LazyCmsisDap};
+use crate::dfu::{
+    DFU_ABORT, DFU_CLRSTATUS, DFU_CMD_BIN, DFU_CMD_ERASE, DFU_CMD_INFO, DFU_CMD_READ,
+    DFU_CMD_RESET, DFU_CMD_WRITE, DFU_DETACH, DFU_DNLOAD, DFU_GETSTATUS, DFU_UPLOAD, DfuFunctional,
+    DfuInterface, DfuState, FlashGeometry,
+};
+use crate::{
+    CONFIGURATION_BLOCK, CONFIGURATION_SIZE, CancelToken, DFU_BLOCK_SIZE, MAGIC_BYTES,
+    MAGIC_OFFSET, MODE_OFFSET, Observer, ProbeInfo, ProbeState, Transport, Warning,
+    XDS110_DFU_DEVICES, XDS110_USB_DEVICES, Xds110DfuDevice, Xds110Probe, Xds110UsbDevice,
+};
+
+/// The DFU interface number of the mock bootloader. Not 0, so a request
+/// addressed to anything else shows up.
+pub(crate) const INTERFACE: u8 = 1;
+pub(crate) const FLASH_TOP: u32 = 0x10_0000;
+pub(crate) const APP_START: u32 = 0x4000;
+/// The erase page of the TM4C129 on the XDS110
+pub(crate) const PAGE_SIZE: u32 = 0x4000;
+/// What the bootloader region and the application are filled with
+pub(crate) const BOOTLOADER_FILL: u8 = 0xb0;
+pub(crate) const APPLICATION_FILL: u8 = 0xa5;
+
+const STATUS_OK: u8 = 0x00;
+const ERR_ADDRESS: u8 = 0x08;
+const ERR_UNKNOWN: u8 = 0x0e;
+const ERR_STALLEDPKT: u8 = 0x0f;
+
+const TIVA_REQUEST: u8 = 0x42;
+
+/// What the last command set up
+#[derive(Debug)]
+enum Pending {
+    Nothing,
+    Info,
+    Read {
+        address: u32,
+        remaining: u32,
+        /// The command, which the first upload is prefixed with unless
+        /// `DFU_CMD_BIN` turned that off in the meantime
+        command: Option<Vec<u8>>,
+    },
+    Write {
+        address: u32,
+        remaining: u32,
+    },
+}
+
+/// The state of a [`MockBootloader`], open for tests to set up and inspect
+pub(crate) struct Bootloader {
+    pub flash: Vec<u8>,
+    pub state: DfuState,
+    pub status: u8,
+    pub geometry: FlashGeometry,
+    /// The most data an upload or download may carry. Commands and the
+    /// flash geometry are not held to it.
+    pub transfer_size: u16,
+    /// `bRequest` and `wValue` of every class request other than
+    /// `DFU_GETSTATUS`, in order
+    pub requests: Vec<(u8, u16)>,
+    /// The start block and block count of every erase
+    pub erases: Vec<(u16, u16)>,
+    /// The address of the last reset command, once one was sent
+    pub reset_to: Option<u32>,
+    /// How many times the reset command was sent
+    pub resets: usize,
+    /// Whether uploads are prefixed with the 8-byte header, until
+    /// `DFU_CMD_BIN` turns it off
+    pub header: bool,
+    /// Uploads and data downloads served so far
+    pub uploads: usize,
+    pub downloads: usize,
+    /// Answer the upload with this index with only this many bytes, which
+    /// ends the upload as a short frame does
+    pub short_upload: Option<(usize, usize)>,
+    /// Cancel the token once this many data downloads have arrived
+    pub cancel_after: Option<(usize, CancelToken)>,
+    /// Fail every transfer from now on as if unplugged
+    pub unplugged: bool,
+    /// Command bytes and the status to go into `dfuERROR` with instead of
+    /// carrying out the command, once for each entry
+    pub failing_commands: Vec<(u8, u8)>,
+    pending: Pending,
+    /// The `wValue` the next upload or data download must carry
+    next_block: u16,
+    /// The state `DFU_GETSTATUS` moves on to from `dfuDNLOAD-SYNC`
+    after_sync: DfuState,
+}
+
+/// The Tiva bootloader on the XDS110, with the bootloader and application
+/// regions filled and a valid configuration for mode 0. Clones share the
+/// device.
+#[derive(Clone)]
+pub(crate) struct MockBootloader(Arc<Mutex<Bootloader>>);
+
+impl MockBootloader {
+    pub(crate) fn new() -> Self {
+        Self::with_transfer_size(DFU_BLOCK_SIZE as u16)
+    }
+
+    /// A bootloader advertising `transfer_size` as its `wTransferSize`
+    pub(crate) fn with_transfer_size(transfer_size: u16) -> Self {
+        let mut flash = vec![0xff; FLASH_TOP as usize];
+        flash[..APP_START as usize].fill(BOOTLOADER_FILL);
+        flash[APP_START as usize..0x2_0000].fill(APPLICATION_FILL);
+        let config = CONFIGURATION_BLOCK as usize * DFU_BLOCK_SIZE as usize;
+        flash[config..config + MODE_OFFSET + 2].fill(0);
+        flash[config + MAGIC_OFFSET..config + MAGIC_OFFSET + 2].copy_from_slice(&MAGIC_BYTES);
+        MockBootloader(Arc::new(Mutex::new(Bootloader {
+            flash,
+            state: DfuState::DfuIdle,
+            status: STATUS_OK,
+            geometry: FlashGeometry {
+                block_size: PAGE_SIZE as u16,
+                num_blocks: (FLASH_TOP / PAGE_SIZE) as u16,
+                part_info: 0x1022_c01f,
+                class_info: 0x180c_0002,
+                flash_top: FLASH_TOP,
+                app_start: APP_START,
+            },
+            transfer_size,
+            requests: vec![],
+            erases: vec![],
+            reset_to: None,
+            resets: 0,
+            header: true,
+            uploads: 0,
+            downloads: 0,
+            short_upload: None,
+            cancel_after: None,
+            unplugged: false,
+            failing_commands: vec![],
+            pending: Pending::Nothing,
+            next_block: 0,
+            after_sync: DfuState::DnloadIdle,
+        })))
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Bootloader> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The bootloader as `from_device_info` would open it, on a bus of its
+    /// own
+    pub(crate) fn open(&self) -> Xds110DfuDevice {
+        let mut dfu = self.open_unrecovered();
+        dfu.recover().unwrap();
+        dfu
+    }
+
+    /// The bootloader of `probe`, found on `bus`
+    fn open_on(&self, probe: &MockProbe, bus: &MockBus) -> io::Result<Xds110DfuDevice> {
+        let mut dfu = self.open_unrecovered();
+        dfu.serial = probe.serial.clone();
+        dfu.port_path = probe.port_chain.clone().map(|chain| (1, chain));
+        dfu.bus = Arc::new(bus.clone());
+        dfu.recover().map_err(io::Error::other)?;
+        Ok(dfu)
+    }
+
+    /// The bootloader without anything sent to it yet
+    pub(crate) fn open_unrecovered(&self) -> Xds110DfuDevice {
+        let mut dfu = Xds110DfuDevice::with_transport(Box::new(self.clone()), self.interface());
+        dfu.serial = Some("MOCK0001".to_owned());
+        dfu.bus = Arc::new(MockBus::default());
+        dfu
+    }
+
+    /// The DFU interface the bootloader describes
+    pub(crate) fn interface(&self) -> DfuInterface {
+        DfuInterface {
+            number: INTERFACE,
+            alt_setting: 0,
+            functional: Some(DfuFunctional {
+                attributes: 0x0b,
+                detach_timeout_ms: 1000,
+                transfer_size: self.lock().transfer_size,
+                dfu_version: Some(0x0110),
+            }),
+        }
+    }
+
+    /// The configuration block as it is in flash
+    pub(crate) fn configuration(&self) -> Vec<u8> {
+        let start = CONFIGURATION_BLOCK as usize * DFU_BLOCK_SIZE as usize;
+        self.lock().flash[start..start + CONFIGURATION_SIZE as usize].to_vec()
+    }
+}
+
+impl Bootloader {
+    /// Refuse the request the way the bootloader does, with a stall that
+    /// leaves it in `dfuERROR`
+    fn stall<T>(&mut self) -> Result<T, TransferError> {
+        self.state = DfuState::Error;
+        self.status = ERR_STALLEDPKT;
+        self.pending = Pending::Nothing;
+        Err(TransferError::Stall)
+    }
+
+    fn fail(&mut self, status: u8) {
+        self.state = DfuState::Error;
+        self.status = status;
+        self.pending = Pending::Nothing;
+    }
+
+    fn in_flash(&self, address: u32, len: u32) -> bool {
+        address
+            .checked_add(len)
+            .is_some_and(|end| end <= self.geometry.flash_top)
+    }
+
+    /// Take the block number of an upload or data download
+    fn check_block(&mut self, value: u16) -> Result<(), TransferError> {
+        if value != self.next_block {
+            return self.stall();
+        }
+        self.next_block = value.wrapping_add(1);
+        Ok(())
+    }
+
+    fn status_response(&mut self) -> Vec<u8> {
+        let response = vec![self.status, 0, 0, 0, state_number(self.state), 0];
+        self.state = match self.state {
+            DfuState::DnloadSync => self.after_sync,
+            DfuState::DnBusy => DfuState::DnloadIdle,
+            DfuState::ManifestSync => DfuState::DfuIdle,
+            state => state,
+        };
+        response
+    }
+
+    fn upload(&mut self, value: u16, length: u16) -> Result<Vec<u8>, TransferError> {
+        if !matches!(self.state, DfuState::DnloadIdle | DfuState::UploadIdle) {
+            return self.stall();
+        }
+        self.check_block(value)?;
+        let index = self.uploads;
+        self.uploads += 1;
+        match &mut self.pending {
+            Pending::Info => {
+                let mut bytes = geometry_bytes(&self.geometry);
+                bytes.truncate(length as usize);
+                self.pending = Pending::Nothing;
+                self.state = DfuState::DfuIdle;
+                Ok(bytes)
+            }
+            Pending::Read { .. } if length > self.transfer_size => self.stall(),
+            Pending::Read {
+                address,
+                remaining,
+                command,
+            } => {
+                let mut bytes = match command.take() {
+                    Some(mut header) if self.header => {
+                        header.truncate(length as usize);
+                        header
+                    }
+                    _ => vec![],
+                };
+                let mut n = (length as usize - bytes.len()).min(*remaining as usize);
+                let short = match self.short_upload {
+                    Some((at, max)) if at == index => {
+                        n = n.min(max.saturating_sub(bytes.len()));
+                        true
+                    }
+                    _ => false,
+                };
+                let start = *address;
+                *address += n as u32;
+                *remaining -= n as u32;
+                if short || *remaining == 0 {
+                    self.pending = Pending::Nothing;
+                    self.state = DfuState::DfuIdle;
+                } else {
+                    self.state = DfuState::UploadIdle;
+                }
+                bytes.extend_from_slice(&self.flash[start as usize..start as usize + n]);
+                Ok(bytes)
+            }
+            _ => self.stall(),
+        }
+    }
+
+    fn download(&mut self, value: u16, data: &[u8]) -> Result<(), TransferError> {
+        if !matches!(self.state, DfuState::DfuIdle | DfuState::DnloadIdle) {
+            return self.stall();
+        }
+        if data.is_empty() {
+            if self.state != DfuState::DnloadIdle {
+                return self.stall();
+            }
+            self.pending = Pending::Nothing;
+            self.state = DfuState::ManifestSync;
+            return Ok(());
+        }
+        if let Pending::Write { .. } = self.pending {
+            return self.program(value, data);
+        }
+        if value != 0 {
+            return self.stall();
+        }
+        self.next_block = 1;
+        self.after_sync = DfuState::DnloadIdle;
+        self.state = DfuState::DnloadSync;
+        self.command(data);
+        Ok(())
+    }
+
+    fn command(&mut self, bytes: &[u8]) {
+        let mut header = bytes.to_vec();
+        header.resize(8, 0);
+        let block = u16::from_le_bytes([header[2], header[3]]);
+        let len = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let address = block as u32 * DFU_BLOCK_SIZE;
+        let reachable = self.in_flash(address, len);
+        if let Some(i) = self.failing_commands.iter().position(|f| f.0 == bytes[0]) {
+            let (_, status) = self.failing_commands.remove(i);
+            return self.fail(status);
+        }
+        match bytes[0] {
+            DFU_CMD_WRITE | DFU_CMD_READ if !reachable => self.fail(ERR_ADDRESS),
+            DFU_CMD_WRITE => {
+                self.pending = Pending::Write {
+                    address,
+                    remaining: len,
+                };
+            }
+            DFU_CMD_READ => {
+                self.pending = Pending::Read {
+                    address,
+                    remaining: len,
+                    command: Some(header),
+                };
+            }
+            DFU_CMD_ERASE => {
+                let num_blocks = u16::from_le_bytes([header[4], header[5]]);
+                let len = num_blocks as u32 * DFU_BLOCK_SIZE;
+                if !self.in_flash(address, len) {
+                    return self.fail(ERR_ADDRESS);
+                }
+                self.flash[address as usize..(address + len) as usize].fill(0xff);
+                self.erases.push((block, num_blocks));
+                self.state = DfuState::DnBusy;
+            }
+            DFU_CMD_INFO => self.pending = Pending::Info,
+            DFU_CMD_BIN => self.header = bytes.get(1) == Some(&0),
+            DFU_CMD_RESET => {
+                self.reset_to = Some(u32::from_le_bytes(header[1..5].try_into().unwrap()));
+                self.resets += 1;
+                self.after_sync = DfuState::DfuIdle;
+            }
+            _ => self.fail(ERR_UNKNOWN),
+        }
+    }
+
+    /// Program a data download into flash. Like the real bootloader, this
+    /// erases each flash page as the write reaches its start; anything else
+    /// in flash can only clear bits.
+    fn program(&mut self, value: u16, data: &[u8]) -> Result<(), TransferError> {
+        self.check_block(value)?;
+        let Pending::Write { address, remaining } = &mut self.pending else {
+            unreachable!()
+        };
+        if data.len() as u32 > *remaining || data.len() > self.transfer_size as usize {
+            return self.stall();
+        }
+        let start = *address;
+        *address += data.len() as u32;
+        *remaining -= data.len() as u32;
+        for (offset, byte) in data.iter().enumerate() {
+            let at = start as usize + offset;
+            if at.is_multiple_of(PAGE_SIZE as usize) {
+                self.flash[at..at + PAGE_SIZE as usize].fill(0xff);
+            }
+            self.flash[at] &= byte;
+        }
+        self.downloads += 1;
+        if let Some((after, token)) = &self.cancel_after
+            && self.downloads == *after
+        {
+            token.cancel();
+        }
+        self.state = DfuState::DnloadSync;
+        Ok(())
+    }
+}
+
+impl Transport for MockBootloader {
+    fn class_in(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, TransferError> {
+        let mut device = self.lock();
+        if device.unplugged {
+            return Err(TransferError::Disconnected);
+        }
+        if index != INTERFACE as u16 {
+            return device.stall();
+        }
+        if request != DFU_GETSTATUS {
+            device.requests.push((request, value));
+        }
+        match request {
+            DFU_GETSTATUS if length == 6 => Ok(device.status_response()),
+            DFU_UPLOAD => device.upload(value, length),
+            TIVA_REQUEST if length == 4 => Ok(vec![0x4d, 0x4c, 0x01, 0x00]),
+            _ => device.stall(),
+        }
+    }
+
+    fn class_out(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<(), TransferError> {
+        let mut device = self.lock();
+        if device.unplugged {
+            return Err(TransferError::Disconnected);
+        }
+        if index != INTERFACE as u16 {
+            return device.stall();
+        }
+        device.requests.push((request, value));
+        match request {
+            DFU_DNLOAD => device.download(value, data),
+            DFU_ABORT => match device.state {
+                DfuState::DfuIdle
+                | DfuState::DnloadSync
+                | DfuState::DnloadIdle
+                | DfuState::ManifestSync
+                | DfuState::UploadIdle => {
+                    device.pending = Pending::Nothing;
+                    device.state = DfuState::DfuIdle;
+                    Ok(())
+                }
+                _ => device.stall(),
+            },
+            DFU_CLRSTATUS if device.state == DfuState::Error => {
+                device.pending = Pending::Nothing;
+                device.status = STATUS_OK;
+                device.state = DfuState::DfuIdle;
+                Ok(())
+            }
+            DFU_DETACH => Ok(()),
+            _ => device.stall(),
+        }
+    }
+
+    fn read_bulk(&self, _endpoint: u8, _buf: &mut [u8], _timeout: Duration) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn write_bulk(&self, _endpoint: u8, _buf: &[u8], _timeout: Duration) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+fn state_number(state: DfuState) -> u8 {
+    match state {
+        DfuState::AppIdle => 0,
+        DfuState::AppDetach => 1,
+        DfuState::DfuIdle => 2,
+        DfuState::DnloadSync => 3,
+        DfuState::DnBusy => 4,
+        DfuState::DnloadIdle => 5,
+        DfuState::ManifestSync => 6,
+        DfuState::Manifest => 7,
+        DfuState::ManifestWaitReset => 8,
+        DfuState::UploadIdle => 9,
+        DfuState::Error => 10,
+        DfuState::Unknown(other) => other,
+    }
+}
+
+fn geometry_bytes(geometry: &FlashGeometry) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.extend_from_slice(&geometry.block_size.to_le_bytes());
+    bytes.extend_from_slice(&geometry.num_blocks.to_le_bytes());
+    bytes.extend_from_slice(&geometry.part_info.to_le_bytes());
+    bytes.extend_from_slice(&geometry.class_info.to_le_bytes());
+    bytes.extend_from_slice(&geometry.flash_top.to_le_bytes());
+    bytes.extend_from_slice(&geometry.app_start.to_le_bytes());
+    bytes
+}
+
+type Responder = Box<dyn FnMut(u8, &[u8]) -> Option<Vec<u8>> + Send>;
+
+/// The normal firmware, behind bulk endpoints
+#[derive(Clone)]
+pub(crate) struct MockFirmware(Arc<Mutex<Firmware>>);
+
+struct Firmware {
+    responder: Responder,
+    responses: VecDeque<Vec<u8>>,
+    /// Every bulk write, with its endpoint
+    written: Vec<(u8, Vec<u8>)>,
+}
+
+impl MockFirmware {
+    /// A device answering each write on endpoint `epout` with what
+    /// `responder` returns for it
+    pub(crate) fn new(
+        responder: impl FnMut(u8, &[u8]) -> Option<Vec<u8>> + Send + 'static,
+    ) -> Self {
+        MockFirmware(Arc::new(Mutex::new(Firmware {
+            responder: Box::new(responder),
+            responses: VecDeque::new(),
+            written: vec![],
+        })))
+    }
+
+    /// Every bulk write so far, with its endpoint
+    pub(crate) fn written(&self) -> Vec<(u8, Vec<u8>)> {
+        self.lock().written.clone()
+    }
+
+    /// How many times the firmware was asked to reboot into the bootloader
+    fn reboots(&self) -> usize {
+        let written = &self.lock().written;
+        written
+            .iter()
+            .filter(|(_, w)| w[..] == REBOOT_TO_DFU)
+            .count()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Firmware> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The XDS110 firmware at `version` on hardware `hardware_id`, answering
+    /// every vendor command with success
+    pub(crate) fn xds110(version: u32, hardware_id: u16) -> Self {
+        Self::new(move |_, request| {
+            let &[0x2a, _, _, command, ..] = request else {
+                return None;
+            };
+            let mut response = vec![0x2a, 0, 0, 0, 0, 0, 0];
+            if command == 0x03 {
+                response.extend_from_slice(&version.to_le_bytes());
+                response.extend_from_slice(&hardware_id.to_le_bytes());
+            }
+            // The reboot into the bootloader goes unanswered
+            (command != 0x26).then_some(response)
+        })
+    }
+
+    /// The probe running this firmware, with bulk endpoints 0x02 and 0x83,
+    /// on a bus of its own
+    pub(crate) fn open(&self) -> Xds110UsbDevice {
+        Xds110UsbDevice {
+            device_handle: Box::new(self.clone()),
+            epout: 0x02,
+            epin: 0x83,
+            serial: Some("MOCK0001".to_owned()),
+            port_path: None,
+            bus: Arc::new(MockBus::default()),
+            cmsis_dap: LazyCmsisDap::none(),
+        }
+    }
+
+    /// [`open`](Self::open), with `dap` behind the CMSIS-DAP interface on
+    /// endpoints [`DAP_EPOUT`] and [`DAP_EPIN`]
+    pub(crate) fn open_with_cmsis_dap(&self, dap: &MockFirmware) -> Xds110UsbDevice {
+        let dap = dap.clone();
+        Xds110UsbDevice {
+            cmsis_dap: LazyCmsisDap::new(move || {
+                let handle = Box::new(dap.clone());
+                Ok(Some(CmsisDapInterface::new(handle, DAP_EPIN, DAP_EPOUT)))
+            }),
+            ..self.open()
+        }
+    }
+
+    /// The firmware of `probe`, found on `bus`
+    fn open_on(&self, probe: &MockProbe, bus: &MockBus) -> Xds110UsbDevice {
+        Xds110UsbDevice {
+            serial: probe.serial.clone(),
+            port_path: probe.port_chain.clone().map(|chain| (1, chain)),
+            bus: Arc::new(bus.clone()),
+            ..self.open()
+        }
+    }
+}
+
+impl Transport for MockFirmware {
+    fn class_in(&self, _: u8, _: u16, _: u16, _: u16) -> Result<Vec<u8>, TransferError> {
+        Err(TransferError::Stall)
+    }
+
+    fn class_out(&self, _: u8, _: u16, _: u16, _: &[u8]) -> Result<(), TransferError> {
+        Err(TransferError::Stall)
+    }
+
+    fn read_bulk(&self, _endpoint: u8, buf: &mut [u8], _timeout: Duration) -> io::Result<usize> {
+        let response = self
+            .lock()
+            .responses
+            .pop_front()
+            .ok_or(io::ErrorKind::TimedOut)?;
+        let n = response.len().min(buf.len());
+        buf[..n].copy_from_slice(&response[..n]);
+        Ok(n)
+    }
+
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], _timeout: Duration) -> io::Result<usize> {
+        let mut device = self.lock();
+        device.written.push((endpoint, buf.to_vec()));
+        if let Some(response) = (device.responder)(endpoint, buf) {
+            device.responses.push_back(response);
+        }
+        Ok(buf.len())
+    }
+}
+
+/// The bulk endpoints of the CMSIS-DAP interface of
+/// [`MockFirmware::open_with_cmsis_dap`]
+pub(crate) const DAP_EPOUT: u8 = 0x04;
+pub(crate) const DAP_EPIN: u8 = 0x85;
+
+/// The vendor command that reboots the normal firmware into the bootloader
+pub(crate) const REBOOT_TO_DFU: [u8; 4] = [0x2a, 0x01, 0x00, 0x26];
+
+/// An XDS110 with both its firmware and its bootloader. Which one it runs
+/// follows the reboots the firmware was asked for and the resets sent to the
+/// bootloader.
+#[derive(Clone)]
+pub(crate) struct MockProbe {
+    pub serial: Option<String>,
+    /// Hub ports on bus 1
+    pub port_chain: Option<Vec<u8>>,
+    pub firmware: MockFirmware,
+    pub bootloader: MockBootloader,
+    /// What it was running when plugged in
+    pub plugged_in: ProbeState,
+}
+
+impl MockProbe {
+    /// A probe with serial number `serial` and no known port path, running
+    /// firmware at `CMSIS_DAP_2_MINIMUM`
+    pub(crate) fn new(serial: &str, plugged_in: ProbeState) -> Self {
+        MockProbe {
+            serial: Some(serial.to_owned()),
+            port_chain: None,
+            firmware: MockFirmware::xds110(crate::CMSIS_DAP_2_MINIMUM, 0),
+            bootloader: MockBootloader::new(),
+            plugged_in,
+        }
+    }
+
+    /// The same probe, plugged into hub port `port` on bus 1
+    pub(crate) fn at_port(self, port: u8) -> Self {
+        MockProbe {
+            port_chain: Some(vec![port]),
+            ..self
+        }
+    }
+
+    pub(crate) fn state(&self) -> ProbeState {
+        let reboots = self.firmware.reboots();
+        let resets = self.bootloader.lock().resets;
+        match self.plugged_in {
+            ProbeState::Runtime if reboots > resets => ProbeState::Dfu,
+            ProbeState::Dfu if resets > reboots => ProbeState::Runtime,
+            state => state,
+        }
+    }
+
+    /// The probe as listed at `address` on bus 1
+    fn info(&self, address: u8) -> ProbeInfo {
+        let state = self.state();
+        let (vid, pid) = match state {
+            ProbeState::Runtime => (XDS110_USB_DEVICES[0].vid, XDS110_USB_DEVICES[0].pid),
+            ProbeState::Dfu => (XDS110_DFU_DEVICES[0].vid, XDS110_DFU_DEVICES[0].pid),
+        };
+        ProbeInfo {
+            vid,
+            pid,
+            serial: self.serial.clone(),
+            bus: 1,
+            address,
+            port_chain: self.port_chain.clone(),
+            state,
+        }
+    }
+}
+
+/// The probes plugged in, in the order they are listed. Clones share them.
+#[derive(Clone, Default)]
+pub(crate) struct MockBus(Arc<Mutex<Vec<MockProbe>>>);
+
+impl MockBus {
+    pub(crate) fn new(probes: Vec<MockProbe>) -> Self {
+        MockBus(Arc::new(Mutex::new(probes)))
+    }
+
+    /// Plug `probe` in after the others
+    #[cfg(feature = "ffi-mock")]
+    pub(crate) fn plug_in(&self, probe: MockProbe) {
+        self.lock().push(probe);
+    }
+
+    /// The probe plugged in with serial number `serial`
+    #[cfg(feature = "ffi-mock")]
+    pub(crate) fn probe(&self, serial: &str) -> Option<MockProbe> {
+        let probes = self.lock();
+        probes
+            .iter()
+            .find(|probe| probe.serial.as_deref() == Some(serial))
+            .cloned()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<MockProbe>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// `probe`, which must be plugged in, opened in whichever state it is in
+    pub(crate) fn open(&self, probe: &MockProbe) -> Xds110Probe {
+        match probe.state() {
+            ProbeState::Dfu => Xds110Probe::Dfu(probe.bootloader.open_on(probe, self).unwrap()),
+            ProbeState::Runtime => Xds110Probe::Runtime(probe.firmware.open_on(probe, self)),
+        }
+    }
+
+    /// The first probe in `state` that `matches`. Like the real bus, this
+    /// does not check whether any other one does too.
+    fn find(
+        &self,
+        state: ProbeState,
+        matches: &dyn Fn(&ProbeInfo) -> bool,
+    ) -> io::Result<MockProbe> {
+        let probes = self.lock();
+        (1..)
+            .zip(probes.iter())
+            .find(|&(address, probe)| probe.state() == state && matches(&probe.info(address)))
+            .map(|(_, probe)| probe.clone())
+            .ok_or(io::ErrorKind::NotFound.into())
+    }
+}
+
+impl Bus for MockBus {
+    fn list(&self) -> io::Result<Vec<ProbeInfo>> {
+        let probes = self.lock();
+        let listed = (1..).zip(probes.iter());
+        Ok(listed.map(|(address, probe)| probe.info(address)).collect())
+    }
+
+    fn open_dfu(&self, matches: &dyn Fn(&ProbeInfo) -> bool) -> io::Result<Xds110DfuDevice> {
+        let probe = self.find(ProbeState::Dfu, matches)?;
+        probe.bootloader.open_on(&probe, self)
+    }
+
+    fn open_xds110(&self, matches: &dyn Fn(&ProbeInfo) -> bool) -> io::Result<Xds110UsbDevice> {
+        let probe = self.find(ProbeState::Runtime, matches)?;
+        Ok(probe.firmware.open_on(&probe, self))
+    }
+}
+
+/// An observer that keeps every warning
+#[derive(Default)]
+pub(crate) struct Warnings(Mutex<Vec<Warning>>);
+
+impl Warnings {
+    /// The warnings so far, in order
+    pub(crate) fn seen(&self) -> Vec<Warning> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl Observer for Warnings {
+    fn warning(&self, warning: &Warning) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(warning.clone());
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// The mode number that selects CMSIS-DAP 2.0
+pub const MODE_CMSIS_DAP2: u16 = 4;
+
+/// The mode stored in the probe configuration, which selects the set of USB
+/// interfaces the firmware exposes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeMode {
+    /// Mode 0, the factory default
+    Default,
+    /// Alternate mode 4
+    CmsisDap2,
+    /// A mode number this tool has no description for
+    Other(u16),
+}
+
+impl ProbeMode {
+    /// Every mode with a known meaning, for presenting a choice to users
+    pub const KNOWN: &'static [ProbeMode] = &[ProbeMode::Default, ProbeMode::CmsisDap2];
+
+    pub fn number(self) -> u16 {
+        match self {
+            ProbeMode::Default => 0,
+            ProbeMode::CmsisDap2 => MODE_CMSIS_DAP2,
+            ProbeMode::Other(number) => number,
+        }
+    }
+
+    /// Short name accepted by [`FromStr`]
+    pub fn name(self) -> &'static str {
+        match self {
+            ProbeMode::Default => "default",
+            ProbeMode::CmsisDap2 => "cmsis-dap2",
+            ProbeMode::Other(_) => "unknown",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            ProbeMode::Default => "TI debug interface with CMSIS-DAP 1.0 over HID",
+            ProbeMode::CmsisDap2 => "TI debug interface with CMSIS-DAP 2.0 over bulk endpoints",
+            ProbeMode::Other(_) => "undocumented mode",
+        }
+    }
+}
+
+impl From<u16> for ProbeMode {
+    fn from(value: u16) -> Self {
+        ProbeMode::KNOWN
+            .iter()
+            .copied()
+            .find(|mode| mode.number() == value)
+            .unwrap_or(ProbeMode::Other(value))
+    }
+}
+
+impl fmt::Display for ProbeMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeMode::Other(number) => write!(f, "{}", number),
+            mode => write!(f, "{} ({})", mode.number(), mode.name()),
+        }
+    }
+}
+
+/// Serialized as `{"number": 4, "name": "cmsis-dap2"}`
+#[cfg(feature = "serde")]
+impl serde::Serialize for ProbeMode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("ProbeMode", 2)?;
+        state.serialize_field("number", &self.number())?;
+        state.serialize_field("name", self.name())?;
+        state.end()
+    }
+}
+
+/// Error returned when parsing a [`ProbeMode`] fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseModeError(String);
+
+impl fmt::Display for ParseModeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown mode `{}`", self.0)
+    }
+}
+
+impl core::error::Error for ParseModeError {}
+
+impl FromStr for ProbeMode {
+    type Err = ParseModeError;
+
+    /// Accepts a mode number, in decimal or `0x`-prefixed hex, or a mode name
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = match s.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => s.parse(),
+        };
+        if let Ok(number) = number {
+            return Ok(ProbeMode::from(number));
+        }
+        ProbeMode::KNOWN
+            .iter()
+            .copied()
+            .find(|mode| mode.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| ParseModeError(s.to_owned()))
+    }
+}
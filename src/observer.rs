@@ -0,0 +1,78 @@
+//! Hooks for watching a mode switch as it happens.
+
+use crate::dfu::DfuState;
+use crate::{FirmwareVersion, TransferStats};
+
+/// A step of the switch flow. Progress is reported in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    EnteringDfu,
+    EnsuringBinaryProtocol,
+    /// A read has been set up; the upload follows
+    ReadCommandSent,
+    ReadingConfig {
+        done: usize,
+        total: usize,
+    },
+    /// Progress of [`read_flash_to`](crate::Xds110DfuDevice::read_flash_to)
+    ReadingFlash {
+        done: usize,
+        total: usize,
+    },
+    WritingConfig {
+        done: usize,
+        total: usize,
+    },
+    WritingFirmware {
+        done: usize,
+        total: usize,
+    },
+    /// The bootloader has accepted the end of a download
+    WriteComplete,
+    /// A flash read is over, as returned by
+    /// [`last_transfer_stats`](crate::Xds110DfuDevice::last_transfer_stats)
+    ReadStats(TransferStats),
+    /// A flash write is over, including the end of the download
+    WriteStats(TransferStats),
+    Resetting,
+}
+
+/// Something unexpected that the library worked around
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// The configuration block did not carry the `55 aa` magic, so it was
+    /// rewritten. `found` holds the bytes that were there instead.
+    MagicRepaired { found: [u8; 2] },
+
+    /// The firmware is older than the requested mode supports, but the
+    /// switch was forced anyway
+    FirmwareTooOld {
+        found: FirmwareVersion,
+        minimum: FirmwareVersion,
+    },
+
+    /// A firmware file names a different start address in its
+    /// `DFU_CMD_PROG` prefix than the one it is written to
+    PrefixAddressMismatch { prefix: u32, used: u32 },
+
+    /// [`ping_dfu`](crate::Xds110DfuDevice::ping_dfu) found the bootloader
+    /// in `state` with `bStatus` `status`, rather than idle and OK
+    UnexpectedStatus { state: DfuState, status: u8 },
+
+    /// The bootloader was found in `state` with `bStatus` `status`, left
+    /// there by an interrupted session, and was brought back to idle
+    InterruptedSession { state: DfuState, status: u8 },
+
+    /// `operation` failed and is being attempted again
+    Retry {
+        operation: &'static str,
+        attempt: u32,
+    },
+}
+
+/// Receives progress from [`Xds110DfuDevice`](crate::Xds110DfuDevice) and
+/// [`switch_mode`](crate::switch_mode). Every method defaults to doing nothing.
+pub trait Observer: Send + Sync {
+    fn phase(&self, _phase: Phase) {}
+    fn warning(&self, _warning: &Warning) {}
+}
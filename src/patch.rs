@@ -0,0 +1,103 @@
+//! Editing individual bytes of the configuration block.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::config::{MAGIC_BYTES, MAGIC_OFFSET};
+use crate::{CONFIGURATION_SIZE, Xds110DfuDevice, Xds110Error};
+
+/// Set the configuration byte at `offset` to `value`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigPatch {
+    pub offset: u16,
+    pub value: u8,
+}
+
+impl ConfigPatch {
+    /// Whether the tool maintains this byte itself
+    pub fn is_reserved(&self) -> bool {
+        (MAGIC_OFFSET..MAGIC_OFFSET + MAGIC_BYTES.len()).contains(&(self.offset as usize))
+    }
+}
+
+/// Error returned when parsing a [`ConfigPatch`] fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsePatchError(String);
+
+impl fmt::Display for ParsePatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid patch `{}`, expected <offset>=<value>", self.0)
+    }
+}
+
+impl core::error::Error for ParsePatchError {}
+
+fn parse_number<T: FromStr + TryFrom<u32>>(s: &str) -> Option<T> {
+    let s = s.trim();
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?.try_into().ok(),
+        None => s.parse().ok(),
+    }
+}
+
+impl FromStr for ConfigPatch {
+    type Err = ParsePatchError;
+
+    /// Accepts `offset=value`, each in decimal or `0x`-prefixed hex
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParsePatchError(s.to_owned());
+        let (offset, value) = s.split_once('=').ok_or_else(error)?;
+        Ok(ConfigPatch {
+            offset: parse_number(offset).ok_or_else(error)?,
+            value: parse_number(value).ok_or_else(error)?,
+        })
+    }
+}
+
+impl Xds110DfuDevice {
+    /// Apply `patches` to the configuration and write it back if anything
+    /// changed. Unless a patch sets the magic itself, the magic is restored.
+    /// Returns the number of bytes that changed.
+    ///
+    /// Patches to [reserved](ConfigPatch::is_reserved) bytes are refused
+    /// unless `allow_reserved` is set.
+    pub fn patch_configuration(
+        &mut self,
+        patches: &[ConfigPatch],
+        allow_reserved: bool,
+    ) -> Result<usize, Xds110Error> {
+        for patch in patches {
+            if patch.offset >= CONFIGURATION_SIZE {
+                return Err(Xds110Error::PatchOutOfRange {
+                    offset: patch.offset,
+                });
+            }
+            if patch.is_reserved() && !allow_reserved {
+                return Err(Xds110Error::PatchReserved {
+                    offset: patch.offset,
+                });
+            }
+        }
+
+        let mut original = self.read_configuration()?;
+        self.check_layout(&mut original)?;
+        let mut configuration = original.clone();
+        configuration.set_magic();
+        for patch in patches {
+            configuration.bytes_mut()[patch.offset as usize] = patch.value;
+        }
+
+        let changed = original
+            .to_bytes()
+            .iter()
+            .zip(configuration.to_bytes())
+            .filter(|(a, b)| a != b)
+            .count();
+        if changed == 0 {
+            return Ok(0);
+        }
+
+        self.write_configuration(&configuration)?;
+        Ok(changed)
+    }
+}
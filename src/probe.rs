@@ -0,0 +1,322 @@
+use std::time::{Duration, Instant};
+
+use crate::bus::Bus;
+use crate::{
+    CancelToken, ProbeInfo, XDS110_DFU_DEVICES, Xds110DfuDevice, Xds110Error, Xds110UsbDevice,
+    open_dfu, open_dfu_by_path, open_xds110, open_xds110_by_path,
+};
+
+/// How long to wait for the probe to come back after switching states
+const REENUMERATION_TIMEOUT: Duration = Duration::from_secs(5);
+const REENUMERATION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Which firmware the probe is currently running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ProbeState {
+    /// The normal XDS110 application firmware
+    Runtime,
+    /// The Tiva DFU bootloader
+    Dfu,
+}
+
+/// An XDS110 in either of its two USB personalities
+pub enum Xds110Probe {
+    Runtime(Xds110UsbDevice),
+    Dfu(Xds110DfuDevice),
+}
+
+impl Xds110Probe {
+    /// Find the attached probe, preferring one that is already in DFU mode.
+    pub fn open() -> Result<Self, Xds110Error> {
+        match open_dfu() {
+            Ok(dfu) => Ok(Xds110Probe::Dfu(dfu)),
+            Err(_) => Ok(Xds110Probe::Runtime(open_xds110()?)),
+        }
+    }
+
+    /// Find the probe plugged into `port_chain` on `bus`, in either state.
+    /// The probe is tracked by this path when it re-enumerates, so other
+    /// attached probes are left alone.
+    pub fn open_by_path(bus: u8, port_chain: &[u8]) -> Result<Self, Xds110Error> {
+        match open_dfu_by_path(bus, port_chain) {
+            Ok(dfu) => Ok(Xds110Probe::Dfu(dfu)),
+            Err(_) => Ok(Xds110Probe::Runtime(open_xds110_by_path(bus, port_chain)?)),
+        }
+    }
+
+    /// Open a probe the caller has already found, in whichever state its
+    /// vendor and product ID say it is in
+    pub fn from_device_info(device: nusb::DeviceInfo) -> Result<Self, Xds110Error> {
+        let ids = (device.vendor_id(), device.product_id());
+        if XDS110_DFU_DEVICES.iter().any(|x| (x.vid, x.pid) == ids) {
+            Ok(Xds110Probe::Dfu(Xds110DfuDevice::from_device_info(device)?))
+        } else {
+            Ok(Xds110Probe::Runtime(Xds110UsbDevice::from_device_info(
+                device,
+            )?))
+        }
+    }
+
+    pub fn mode(&self) -> ProbeState {
+        match self {
+            Xds110Probe::Runtime(_) => ProbeState::Runtime,
+            Xds110Probe::Dfu(_) => ProbeState::Dfu,
+        }
+    }
+
+    pub fn serial(&self) -> Option<&str> {
+        match self {
+            Xds110Probe::Runtime(xds110) => xds110.serial(),
+            Xds110Probe::Dfu(dfu) => dfu.serial(),
+        }
+    }
+
+    /// Reboot the probe into the DFU bootloader and wait for it to
+    /// re-enumerate. A probe that is already in DFU mode is returned as-is.
+    pub fn enter_dfu(self) -> Result<Xds110DfuDevice, Xds110Error> {
+        self.enter_dfu_cancellable(None)
+    }
+
+    /// Like [`enter_dfu`](Self::enter_dfu), giving up with
+    /// [`Xds110Error::Cancelled`] if `cancel` is cancelled while waiting for
+    /// the bootloader to show up
+    pub fn enter_dfu_with_cancel(
+        self,
+        cancel: &CancelToken,
+    ) -> Result<Xds110DfuDevice, Xds110Error> {
+        self.enter_dfu_cancellable(Some(cancel))
+    }
+
+    pub(crate) fn enter_dfu_cancellable(
+        self,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Xds110DfuDevice, Xds110Error> {
+        match self {
+            Xds110Probe::Dfu(dfu) => Ok(dfu),
+            Xds110Probe::Runtime(xds110) => {
+                let port_path = xds110.port_path.clone();
+                let serial = xds110.serial.clone();
+                let bus = xds110.bus.clone();
+                xds110.reboot_to_dfu()?;
+                let same_probe = |device: &ProbeInfo| {
+                    is_same_probe(device, port_path.as_ref(), serial.as_deref())
+                };
+                wait_for_reenumeration(|| bus.open_dfu(&same_probe), cancel)
+            }
+        }
+    }
+
+    /// Reset the bootloader into the normal firmware and wait for it to
+    /// re-enumerate. A probe that is already running normally is returned as-is.
+    pub fn leave_dfu(self) -> Result<Xds110UsbDevice, Xds110Error> {
+        match self {
+            Xds110Probe::Runtime(xds110) => Ok(xds110),
+            Xds110Probe::Dfu(dfu) => dfu.reset(),
+        }
+    }
+}
+
+/// Whether `device` is plugged in where `port_path` says or, without a
+/// path, has `serial`. With neither known, any device matches.
+pub(crate) fn is_same_probe(
+    device: &ProbeInfo,
+    port_path: Option<&(u8, Vec<u8>)>,
+    serial: Option<&str>,
+) -> bool {
+    match (port_path, serial) {
+        (Some((bus, ports)), _) => device.bus == *bus && device.port_chain.as_ref() == Some(ports),
+        (None, Some(serial)) => device.serial.as_deref() == Some(serial),
+        (None, None) => true,
+    }
+}
+
+fn check_cancelled(cancel: Option<&CancelToken>) -> Result<(), Xds110Error> {
+    if cancel.is_some_and(CancelToken::is_cancelled) {
+        return Err(Xds110Error::Cancelled);
+    }
+    Ok(())
+}
+
+/// Wait until no device attached to `bus` `matches`
+pub(crate) fn wait_for_removal(
+    bus: &dyn Bus,
+    matches: impl Fn(&ProbeInfo) -> bool,
+    cancel: Option<&CancelToken>,
+) -> Result<(), Xds110Error> {
+    let deadline = Instant::now() + REENUMERATION_TIMEOUT;
+    loop {
+        check_cancelled(cancel)?;
+        if !bus.list()?.iter().any(&matches) {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(Xds110Error::ReenumerationTimeout);
+        }
+        std::thread::sleep(REENUMERATION_POLL_INTERVAL);
+    }
+}
+
+/// Call `open` until the device it looks for shows up. Only
+/// [`NotFound`](std::io::ErrorKind::NotFound) means it has not yet; any
+/// other error is returned straight away.
+pub(crate) fn wait_for_reenumeration<T>(
+    open: impl Fn() -> Result<T, std::io::Error>,
+    cancel: Option<&CancelToken>,
+) -> Result<T, Xds110Error> {
+    let deadline = Instant::now() + REENUMERATION_TIMEOUT;
+    loop {
+        check_cancelled(cancel)?;
+        std::thread::sleep(REENUMERATION_POLL_INTERVAL);
+        match open() {
+            Ok(device) => return Ok(device),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        if Instant::now() >= deadline {
+            return Err(Xds110Error::ReenumerationTimeout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockBus, MockProbe, REBOOT_TO_DFU};
+
+    #[test]
+    fn enter_dfu_from_runtime() {
+        let ours = MockProbe::new("MOCK0001", ProbeState::Runtime);
+        let bus = MockBus::new(vec![ours.clone()]);
+        let probe = bus.open(&ours);
+        assert_eq!(probe.mode(), ProbeState::Runtime);
+        assert_eq!(probe.serial(), Some("MOCK0001"));
+
+        let dfu = probe.enter_dfu().unwrap();
+        assert_eq!(dfu.serial(), Some("MOCK0001"));
+        assert_eq!(ours.firmware.written(), [(0x02, REBOOT_TO_DFU.to_vec())]);
+    }
+
+    #[test]
+    fn enter_dfu_opens_the_same_probe() {
+        // Another probe's bootloader is attached, and listed first
+        let other = MockProbe::new("MOCK0002", ProbeState::Dfu);
+        let ours = MockProbe::new("MOCK0001", ProbeState::Runtime);
+        let bus = MockBus::new(vec![other.clone(), ours.clone()]);
+
+        let dfu = bus.open(&ours).enter_dfu().unwrap();
+        assert_eq!(dfu.serial(), Some("MOCK0001"));
+        assert!(other.bootloader.lock().requests.is_empty());
+
+        // By port path, which wins over the serial number
+        let other = MockProbe::new("MOCK0001", ProbeState::Dfu).at_port(2);
+        let ours = MockProbe::new("MOCK0001", ProbeState::Runtime).at_port(1);
+        let bus = MockBus::new(vec![other.clone(), ours.clone()]);
+
+        let dfu = bus.open(&ours).enter_dfu().unwrap();
+        assert_eq!(dfu.port_path, Some((1, vec![1])));
+        assert!(other.bootloader.lock().requests.is_empty());
+    }
+
+    #[test]
+    fn enter_dfu_from_dfu() {
+        let ours = MockProbe::new("MOCK0001", ProbeState::Dfu);
+        let bus = MockBus::new(vec![ours.clone()]);
+        let probe = bus.open(&ours);
+        assert_eq!(probe.mode(), ProbeState::Dfu);
+        let requests = ours.bootloader.lock().requests.len();
+
+        let dfu = probe.enter_dfu().unwrap();
+        assert_eq!(dfu.serial(), Some("MOCK0001"));
+        assert_eq!(ours.bootloader.lock().requests.len(), requests);
+        assert!(ours.firmware.written().is_empty());
+    }
+
+    #[test]
+    fn leave_dfu_from_dfu() {
+        let ours = MockProbe::new("MOCK0001", ProbeState::Dfu);
+        let bus = MockBus::new(vec![ours.clone()]);
+
+        let xds110 = bus.open(&ours).leave_dfu().unwrap();
+        assert_eq!(xds110.serial(), Some("MOCK0001"));
+        assert!(ours.bootloader.lock().reset_to.is_some());
+        assert!(ours.firmware.written().is_empty());
+    }
+
+    #[test]
+    fn leave_dfu_waits_only_for_the_same_probe() {
+        // Another probe stays in its bootloader the whole time, and another
+        // one is running its firmware, both listed first
+        let in_dfu = MockProbe::new("MOCK0002", ProbeState::Dfu);
+        let running = MockProbe::new("MOCK0003", ProbeState::Runtime);
+        let ours = MockProbe::new("MOCK0001", ProbeState::Dfu);
+        let bus = MockBus::new(vec![in_dfu.clone(), running.clone(), ours.clone()]);
+
+        let xds110 = bus.open(&ours).leave_dfu().unwrap();
+        assert_eq!(xds110.serial(), Some("MOCK0001"));
+        assert_eq!(in_dfu.state(), ProbeState::Dfu);
+        assert!(in_dfu.bootloader.lock().reset_to.is_none());
+    }
+
+    #[test]
+    fn removal_is_waited_for() {
+        let ours = MockProbe::new("MOCK0001", ProbeState::Dfu);
+        let bus = MockBus::new(vec![ours.clone()]);
+        let cancel = CancelToken::new();
+        let matches = |device: &ProbeInfo| {
+            device.state == ProbeState::Dfu && is_same_probe(device, None, Some("MOCK0001"))
+        };
+
+        // Still attached, so it waits until told to stop
+        let waiting = std::thread::spawn({
+            let (bus, cancel) = (bus.clone(), cancel.clone());
+            move || wait_for_removal(&bus, matches, Some(&cancel))
+        });
+        std::thread::sleep(REENUMERATION_POLL_INTERVAL * 2);
+        assert!(!waiting.is_finished());
+        cancel.cancel();
+        assert!(matches!(
+            waiting.join().unwrap(),
+            Err(Xds110Error::Cancelled)
+        ));
+
+        ours.bootloader.lock().resets += 1;
+        wait_for_removal(&bus, matches, None).unwrap();
+    }
+
+    #[test]
+    fn leave_dfu_from_runtime() {
+        let ours = MockProbe::new("MOCK0001", ProbeState::Runtime);
+        let bus = MockBus::new(vec![ours.clone()]);
+
+        let xds110 = bus.open(&ours).leave_dfu().unwrap();
+        assert_eq!(xds110.serial(), Some("MOCK0001"));
+        assert!(ours.firmware.written().is_empty());
+    }
+
+    #[test]
+    fn cancelled_waits_stop() {
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let bus = MockBus::new(vec![MockProbe::new("MOCK0001", ProbeState::Dfu)]);
+        let removal = wait_for_removal(&bus, |_| true, Some(&cancel));
+        assert!(matches!(removal, Err(Xds110Error::Cancelled)));
+        let reenumeration = wait_for_reenumeration(
+            || Err::<(), _>(std::io::ErrorKind::NotFound.into()),
+            Some(&cancel),
+        );
+        assert!(matches!(reenumeration, Err(Xds110Error::Cancelled)));
+    }
+
+    #[test]
+    fn reenumeration_returns_other_errors() {
+        let result = wait_for_reenumeration(
+            || Err::<(), _>(std::io::ErrorKind::PermissionDenied.into()),
+            None,
+        );
+        assert!(
+            matches!(result, Err(Xds110Error::Io(e)) if e.kind() == std::io::ErrorKind::PermissionDenied)
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Retrying bootloader operations that failed part way.
+
+use nusb::transfer::TransferError;
+
+use crate::{Warning, Xds110DfuDevice, Xds110Error};
+
+/// How [`Xds110DfuDevice`] deals with operations that fail part way. The
+/// default is to fail straight away, except after `dfuERROR`, which is
+/// cleared and retried once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuOptions {
+    /// Retry a configuration read or write that failed part way from its
+    /// first block, with the packet count back at 0 and the bootloader back
+    /// in `dfuIDLE`. On by default. Off, nothing is retried, whatever
+    /// `max_retries` says, and the bootloader is left as the failure left it.
+    pub auto_reset_packet_count_on_error: bool,
+    /// How many more times to try a configuration read or write after a
+    /// failed transfer, a bad response or a wait that timed out
+    pub max_retries: u8,
+}
+
+impl Default for DfuOptions {
+    fn default() -> Self {
+        DfuOptions {
+            auto_reset_packet_count_on_error: true,
+            max_retries: 0,
+        }
+    }
+}
+
+impl Xds110Error {
+    /// Whether trying again from `dfuIDLE` has a chance of succeeding. A
+    /// probe that was unplugged never comes back on its own.
+    fn is_recoverable(&self) -> bool {
+        match self {
+            Xds110Error::Transfer { source, .. } => *source != TransferError::Disconnected,
+            Xds110Error::InvalidDfuResponse(_)
+            | Xds110Error::WaitTimeout { .. }
+            | Xds110Error::BootloaderError { .. }
+            | Xds110Error::Stalled { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl Xds110DfuDevice {
+    /// Handle failures of subsequent operations according to `options`
+    pub fn set_options(&mut self, options: DfuOptions) {
+        self.options = options;
+    }
+
+    /// Run `f`, bringing the bootloader back to `dfuIDLE` and running it
+    /// again after a recoverable error, as often as the options allow
+    pub(crate) fn with_retries<T>(
+        &mut self,
+        operation: &'static str,
+        mut f: impl FnMut(&mut Self) -> Result<T, Xds110Error>,
+    ) -> Result<T, Xds110Error> {
+        if !self.options.auto_reset_packet_count_on_error {
+            return f(self);
+        }
+        let mut attempt = 1;
+        // A bootloader that went into dfuERROR gets one more go even when
+        // retries are off, as clearing the error usually fixes it
+        let mut error_retry = true;
+        loop {
+            match f(self) {
+                Err(e)
+                    if e.is_recoverable()
+                        && (attempt <= self.options.max_retries as u32
+                            || matches!(e, Xds110Error::BootloaderError { .. })
+                                && std::mem::take(&mut error_retry)) =>
+                {
+                    attempt += 1;
+                    self.warn(&Warning::Retry { operation, attempt });
+                    self.return_to_idle()?;
+                    self.packet_count = 0;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dfu::{DFU_CLRSTATUS, DFU_CMD_WRITE, DfuState};
+    use std::sync::Arc;
+
+    use nusb::transfer::TransferError;
+
+    use crate::mock::{MockBootloader, Warnings};
+    use crate::{DfuOptions, MODE_OFFSET, ProbeMode, Xds110Configuration, Xds110Error};
+
+    /// `errWRITE`
+    const ERR_WRITE: u8 = 0x03;
+
+    #[test]
+    fn error_on_open_is_cleared() {
+        let mock = MockBootloader::new();
+        mock.lock().state = DfuState::Error;
+        mock.lock().status = ERR_WRITE;
+
+        let mut dfu = mock.open();
+        assert_eq!(dfu.interrupted_session().unwrap().status, ERR_WRITE);
+        assert_eq!(mock.lock().requests, [(DFU_CLRSTATUS, 0)]);
+        let configuration = dfu.read_configuration().unwrap();
+        assert_eq!(configuration.to_bytes()[..], mock.configuration()[..]);
+    }
+
+    #[test]
+    fn error_during_write_is_retried_once() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        let mut configuration = Xds110Configuration::try_from(&mock.configuration()[..]).unwrap();
+        configuration.set_mode(ProbeMode::CmsisDap2);
+        mock.lock().failing_commands = vec![(DFU_CMD_WRITE, ERR_WRITE)];
+
+        dfu.write_configuration(&configuration).unwrap();
+        assert_eq!(mock.configuration()[MODE_OFFSET], 4);
+        assert!(mock.lock().requests.contains(&(DFU_CLRSTATUS, 0)));
+    }
+
+    #[test]
+    fn error_on_the_retry_fails() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        let configuration = Xds110Configuration::try_from(&mock.configuration()[..]).unwrap();
+        mock.lock().failing_commands = vec![(DFU_CMD_WRITE, ERR_WRITE); 2];
+
+        let result = dfu.write_configuration(&configuration);
+        assert!(matches!(
+            result,
+            Err(Xds110Error::BootloaderError {
+                status: ERR_WRITE,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn unplugged_is_not_retried() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        dfu.set_options(DfuOptions {
+            max_retries: 3,
+            ..DfuOptions::default()
+        });
+        let warnings = Arc::new(Warnings::default());
+        dfu.set_observer(warnings.clone());
+        let configuration = Xds110Configuration::try_from(&mock.configuration()[..]).unwrap();
+        mock.lock().unplugged = true;
+
+        let result = dfu.write_configuration(&configuration);
+        assert!(matches!(
+            result,
+            Err(Xds110Error::Transfer {
+                source: TransferError::Disconnected,
+                ..
+            })
+        ));
+        assert_eq!(warnings.seen(), []);
+    }
+
+    #[test]
+    fn nothing_is_retried_without_the_reset() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        dfu.set_options(DfuOptions {
+            auto_reset_packet_count_on_error: false,
+            max_retries: 3,
+        });
+        let configuration = Xds110Configuration::try_from(&mock.configuration()[..]).unwrap();
+        mock.lock().failing_commands = vec![(DFU_CMD_WRITE, ERR_WRITE)];
+
+        let result = dfu.write_configuration(&configuration);
+        assert!(matches!(
+            result,
+            Err(Xds110Error::BootloaderError {
+                status: ERR_WRITE,
+                ..
+            })
+        ));
+        assert!(!mock.lock().requests.contains(&(DFU_CLRSTATUS, 0)));
+        assert_eq!(mock.lock().state, DfuState::Error);
+    }
+}
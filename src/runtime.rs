@@ -0,0 +1,144 @@
+//! Waiting on nusb's transfer futures from blocking code.
+//!
+//! With the default `async-io` feature this uses async-io's reactor and
+//! timers. Without it, the calling thread parks until nusb's own event
+//! handling wakes it, so no extra threads or timers are involved.
+
+use std::time::Duration;
+
+#[cfg(feature = "async-io")]
+pub(crate) fn block_on<F: Future>(fut: F) -> F::Output {
+    async_io::block_on(fut)
+}
+
+/// Wait for `fut`, giving up with `None` after `timeout`
+#[cfg(feature = "async-io")]
+pub(crate) fn block_on_timeout<F: Future>(fut: F, timeout: Duration) -> Option<F::Output> {
+    use futures_lite::FutureExt;
+
+    async_io::block_on(async { Some(fut.await) }.or(async {
+        async_io::Timer::after(timeout).await;
+        None
+    }))
+}
+
+#[cfg(not(feature = "async-io"))]
+pub(crate) fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = std::pin::pin!(fut);
+    let waker = parker::waker();
+    let mut cx = std::task::Context::from_waker(&waker);
+    loop {
+        if let std::task::Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::park();
+    }
+}
+
+/// Wait for `fut`, giving up with `None` after `timeout`
+#[cfg(not(feature = "async-io"))]
+pub(crate) fn block_on_timeout<F: Future>(fut: F, timeout: Duration) -> Option<F::Output> {
+    let deadline = std::time::Instant::now() + timeout;
+    let mut fut = std::pin::pin!(fut);
+    let waker = parker::waker();
+    let mut cx = std::task::Context::from_waker(&waker);
+    loop {
+        if let std::task::Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+            return Some(output);
+        }
+        let remaining = deadline.checked_duration_since(std::time::Instant::now())?;
+        std::thread::park_timeout(remaining);
+    }
+}
+
+#[cfg(not(feature = "async-io"))]
+mod parker {
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+    use std::thread::Thread;
+
+    /// Unparks the thread that created it
+    struct Unparker(Thread);
+
+    impl Wake for Unparker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    pub(super) fn waker() -> Waker {
+        Arc::new(Unparker(std::thread::current())).into()
+    }
+}
+
+/// Run these with and without `async-io`: `cargo test` and
+/// `cargo test --no-default-features`
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll, Waker};
+    use std::time::Instant;
+
+    use super::*;
+
+    /// Ready once another thread has set it, which wakes the waiting task
+    #[derive(Clone, Default)]
+    struct Flag(Arc<Mutex<(bool, Option<Waker>)>>);
+
+    impl Flag {
+        fn set(&self) {
+            let mut flag = self.0.lock().unwrap();
+            flag.0 = true;
+            if let Some(waker) = flag.1.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    impl Future for Flag {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            let mut flag = self.0.lock().unwrap();
+            if flag.0 {
+                return Poll::Ready(());
+            }
+            flag.1 = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn set_later(flag: &Flag) {
+        let flag = flag.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            flag.set();
+        });
+    }
+
+    #[test]
+    fn ready_future() {
+        assert_eq!(block_on(async { 7 }), 7);
+        assert_eq!(block_on_timeout(async { 7 }, Duration::ZERO), Some(7));
+    }
+
+    #[test]
+    fn woken_from_another_thread() {
+        let flag = Flag::default();
+        set_later(&flag);
+        block_on(flag.clone());
+
+        let flag = Flag::default();
+        set_later(&flag);
+        assert_eq!(block_on_timeout(flag, Duration::from_secs(5)), Some(()));
+    }
+
+    #[test]
+    fn timeout_expires() {
+        let start = Instant::now();
+        let result = block_on_timeout(std::future::pending::<()>(), Duration::from_millis(30));
+        assert_eq!(result, None);
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+}
@@ -0,0 +1,107 @@
+//! Field formats shared by the `Serialize` impls.
+
+use serde::Serializer;
+
+pub fn hex_u16<S: Serializer>(value: &u16, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:04x}", value))
+}
+
+pub fn hex_bytes<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    let hex: String = value.iter().map(|b| format!("{:02x}", b)).collect();
+    serializer.serialize_str(&hex)
+}
+
+pub fn millis<S: Serializer>(
+    value: &std::time::Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(value.as_millis() as u64)
+}
+
+/// The JSON that the `--json` output and other tools rely on. A change here
+/// breaks them.
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        ConfigurationSummary, DfuState, DfuStatus, FirmwareVersion, ProbeInfo, ProbeMode,
+        ProbeState,
+    };
+
+    fn json(value: &impl serde::Serialize) -> String {
+        serde_json::to_string(value).unwrap()
+    }
+
+    #[test]
+    fn firmware_version() {
+        assert_eq!(json(&FirmwareVersion(0x0300_0008)), r#""03.00.00.08""#);
+    }
+
+    #[test]
+    fn probe_mode() {
+        assert_eq!(
+            json(&ProbeMode::CmsisDap2),
+            r#"{"number":4,"name":"cmsis-dap2"}"#
+        );
+        assert_eq!(
+            json(&ProbeMode::Other(7)),
+            r#"{"number":7,"name":"unknown"}"#
+        );
+    }
+
+    #[test]
+    fn probe_info() {
+        let info = ProbeInfo {
+            vid: 0x0451,
+            pid: 0xbef3,
+            serial: Some("L1234567".to_owned()),
+            bus: 1,
+            address: 9,
+            port_chain: Some(vec![2, 1]),
+            state: ProbeState::Runtime,
+        };
+        assert_eq!(
+            json(&info),
+            r#"{"vid":"0451","pid":"bef3","serial":"L1234567","bus":1,"address":9,"port_chain":[2,1],"state":"runtime"}"#
+        );
+        let info = ProbeInfo {
+            serial: None,
+            port_chain: None,
+            state: ProbeState::Dfu,
+            ..info
+        };
+        assert_eq!(
+            json(&info),
+            r#"{"vid":"0451","pid":"bef3","serial":null,"bus":1,"address":9,"port_chain":null,"state":"dfu"}"#
+        );
+    }
+
+    #[test]
+    fn dfu_status() {
+        let status = DfuStatus {
+            status: 0,
+            poll_timeout: Duration::from_millis(250),
+            state: DfuState::DnloadIdle,
+            string_index: 0,
+        };
+        assert_eq!(
+            json(&status),
+            r#"{"status":0,"poll_timeout_ms":250,"state":"dnload_idle","string_index":0}"#
+        );
+        assert_eq!(json(&DfuState::Unknown(12)), r#"{"unknown":12}"#);
+    }
+
+    #[test]
+    fn configuration_summary() {
+        let summary = ConfigurationSummary {
+            mode: ProbeMode::Default,
+            magic: [0x55, 0xaa],
+            magic_valid: true,
+        };
+        assert_eq!(
+            json(&summary),
+            r#"{"mode":{"number":0,"name":"default"},"magic":"55aa","magic_valid":true}"#
+        );
+    }
+}
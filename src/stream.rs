@@ -0,0 +1,374 @@
+//! Streaming access to a range of flash blocks.
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use crate::dfu::{self, DfuState, TivaCommand};
+use crate::{DFU_BLOCK_SIZE, Phase, Xds110DfuDevice, Xds110Error};
+
+/// Builds the phase reported after each chunk from bytes done and total
+pub(crate) type Progress = fn(usize, usize) -> Phase;
+
+/// The unit the bootloader programs flash in, in bytes
+const FLASH_WORD_SIZE: usize = 4;
+
+/// How a flash read or write went, see
+/// [`Xds110DfuDevice::last_transfer_stats`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferStats {
+    pub bytes: usize,
+    /// From sending the command to the last status
+    pub duration: Duration,
+    /// The number of uploads or downloads the data took
+    pub chunks: usize,
+}
+
+impl TransferStats {
+    /// Bytes per second, 0 if no time passed at all
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.duration.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        self.bytes as f64 / seconds
+    }
+}
+
+/// For example `16384 bytes in 412 ms (38.8 KB/s)`
+impl fmt::Display for TransferStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes in {} ms ({:.1} KB/s)",
+            self.bytes,
+            self.duration.as_millis(),
+            self.throughput() / 1024.0
+        )
+    }
+}
+
+/// Reads `len` bytes of flash, uploading a chunk of up to the bootloader's
+/// transfer size at a time as the caller asks for more
+pub struct DfuReader<'a> {
+    device: &'a mut Xds110DfuDevice,
+    /// Flash address of the start of the range
+    start: u32,
+    len: u32,
+    /// Bytes of the range uploaded so far
+    offset: u32,
+    /// Bytes the next uploads repeat from before `offset`, which are
+    /// dropped
+    skip: u32,
+    chunk: Vec<u8>,
+    /// Bytes of `chunk` already handed out
+    pos: usize,
+    progress: Option<Progress>,
+}
+
+impl<'a> DfuReader<'a> {
+    /// Start reading `len` bytes from `block`, which must all lie in flash.
+    /// Nothing is uploaded until the first read.
+    pub fn new(device: &'a mut Xds110DfuDevice, block: u16, len: u32) -> Result<Self, Xds110Error> {
+        device.check_flash_range(block as u32 * DFU_BLOCK_SIZE, len)?;
+        Self::with_progress(device, block, len, None)
+    }
+
+    pub(crate) fn with_progress(
+        device: &'a mut Xds110DfuDevice,
+        block: u16,
+        len: u32,
+        progress: Option<Progress>,
+    ) -> Result<Self, Xds110Error> {
+        device.command(TivaCommand::Read { block, len })?;
+        // Disable the DFU header when reading back
+        device.command(TivaCommand::Bin { no_header: true })?;
+        device.notify(Phase::ReadCommandSent);
+        if let Some(progress) = progress {
+            device.notify(progress(0, len as usize));
+        }
+        Ok(DfuReader {
+            device,
+            start: block as u32 * DFU_BLOCK_SIZE,
+            len,
+            offset: 0,
+            skip: 0,
+            chunk: vec![],
+            pos: 0,
+            progress,
+        })
+    }
+
+    /// The unread part of the current chunk, uploading the next one if it
+    /// has all been read. Empty once the whole range has been read.
+    pub(crate) fn fill(&mut self) -> Result<&[u8], Xds110Error> {
+        while self.pos == self.chunk.len() && self.offset < self.len {
+            self.device.check_cancelled()?;
+            let length =
+                (self.len - self.offset + self.skip).min(self.device.transfer_size() as u32) as u16;
+            let packet = self.device.next_packet();
+            let bytes = dfu::upload(
+                &*self.device.device_handle,
+                self.device.interface.number,
+                packet,
+                length,
+            );
+            let mut bytes = self.device.checked(bytes, "block upload")?;
+            self.device.chunks += 1;
+            // Nothing at all will not get any better
+            if bytes.is_empty() {
+                return Err(Xds110Error::UploadEnded {
+                    received: self.offset,
+                    expected: self.len,
+                });
+            }
+            let short = bytes.len() < length as usize;
+            let skipped = bytes.len().min(self.skip as usize);
+            bytes.drain(..skipped);
+            self.skip -= skipped as u32;
+            self.offset += bytes.len() as u32;
+            self.chunk = bytes;
+            self.pos = 0;
+            if let Some(progress) = self.progress {
+                self.device
+                    .notify(progress(self.offset as usize, self.len as usize));
+            }
+            if self.offset >= self.len {
+                self.device.get_status()?;
+            } else if short {
+                self.restart()?;
+            }
+        }
+        Ok(&self.chunk[self.pos..])
+    }
+
+    /// Set up an upload of the rest of the range after a short chunk, which
+    /// ends an upload in DFU. Reads start on a block boundary, so the part
+    /// of the block already read comes again and is skipped.
+    fn restart(&mut self) -> Result<(), Xds110Error> {
+        let address = self.start + self.offset;
+        self.skip = address % DFU_BLOCK_SIZE;
+        self.device.return_to_idle()?;
+        self.device.command(TivaCommand::Read {
+            block: (address / DFU_BLOCK_SIZE) as u16,
+            len: self.len - self.offset + self.skip,
+        })?;
+        self.device.command(TivaCommand::Bin { no_header: true })?;
+        Ok(())
+    }
+
+    /// Read the rest of the range into memory
+    pub fn read_all(mut self) -> Result<Vec<u8>, Xds110Error> {
+        let mut data = Vec::with_capacity(self.len as usize);
+        loop {
+            let chunk = self.fill()?;
+            if chunk.is_empty() {
+                return Ok(data);
+            }
+            data.extend_from_slice(chunk);
+            self.pos = self.chunk.len();
+        }
+    }
+}
+
+impl io::Read for DfuReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let chunk = self.fill()?;
+        let n = chunk.len().min(buf.len());
+        buf[..n].copy_from_slice(&chunk[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl io::BufRead for DfuReader<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(self.fill()?)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.chunk.len());
+    }
+}
+
+/// Writes `len` bytes to flash, downloading one chunk of the bootloader's
+/// transfer size at a time as the caller supplies them. The bootloader
+/// programs whole 32-bit words, so a length that is not a multiple of 4 is
+/// padded with erased (`0xff`) bytes.
+///
+/// [`finish`](Self::finish) must be called once everything is written.
+/// Dropping the writer leaves the bootloader waiting for the rest of the
+/// download.
+pub struct DfuWriter<'a> {
+    device: &'a mut Xds110DfuDevice,
+    len: u32,
+    /// Bytes downloaded so far
+    written: u32,
+    chunk: Vec<u8>,
+    /// The size of a full chunk
+    chunk_size: usize,
+    progress: Option<Progress>,
+}
+
+impl<'a> DfuWriter<'a> {
+    /// Start writing `len` bytes at `block`, which must all lie in flash
+    /// and, unless the device allows unsafe writes, above the bootloader
+    pub fn new(device: &'a mut Xds110DfuDevice, block: u16, len: u32) -> Result<Self, Xds110Error> {
+        device.check_writable(block as u32 * DFU_BLOCK_SIZE, len)?;
+        Self::with_progress(device, block, len, None)
+    }
+
+    pub(crate) fn with_progress(
+        device: &'a mut Xds110DfuDevice,
+        block: u16,
+        len: u32,
+        progress: Option<Progress>,
+    ) -> Result<Self, Xds110Error> {
+        device.command(TivaCommand::Write {
+            block,
+            len: len.next_multiple_of(FLASH_WORD_SIZE as u32),
+        })?;
+        if let Some(progress) = progress {
+            device.notify(progress(0, len as usize));
+        }
+        let chunk_size = device.transfer_size();
+        Ok(DfuWriter {
+            device,
+            len,
+            written: 0,
+            chunk: Vec::with_capacity(chunk_size),
+            chunk_size,
+            progress,
+        })
+    }
+
+    /// Buffer as much of `data` as fits in the current chunk, downloading
+    /// the chunk once it is full. Returns 0 once all `len` bytes are taken.
+    pub(crate) fn push(&mut self, data: &[u8]) -> Result<usize, Xds110Error> {
+        let left = (self.len - self.written) as usize - self.chunk.len();
+        let room = self.chunk_size - self.chunk.len();
+        let n = data.len().min(left).min(room);
+        self.chunk.extend_from_slice(&data[..n]);
+        if self.chunk.len() == self.chunk_size {
+            self.send_chunk()?;
+        }
+        Ok(n)
+    }
+
+    fn send_chunk(&mut self) -> Result<(), Xds110Error> {
+        self.device.check_cancelled()?;
+        // Wait for the device to be ready to receive bytes
+        self.device.wait_for_state(DfuState::DnloadIdle)?;
+        let packet = self.device.next_packet();
+        let result = dfu::download(
+            &*self.device.device_handle,
+            self.device.interface.number,
+            packet,
+            &self.chunk,
+        );
+        self.device.checked(result, "block download")?;
+        self.device.chunks += 1;
+        self.written += self.chunk.len() as u32;
+        self.chunk.clear();
+        if let Some(progress) = self.progress {
+            // Padding can take the count past `len`
+            let done = self.written.min(self.len);
+            self.device
+                .notify(progress(done as usize, self.len as usize));
+        }
+        Ok(())
+    }
+
+    /// Download whatever is still buffered and end the download. If fewer
+    /// than `len` bytes were written, the rest of the range is left erased.
+    pub fn finish(mut self) -> Result<(), Xds110Error> {
+        if !self.chunk.is_empty() {
+            // A short final chunk is fine, but must still end on a word
+            let padded = self.chunk.len().next_multiple_of(FLASH_WORD_SIZE);
+            self.chunk.resize(padded, 0xff);
+            self.send_chunk()?;
+        }
+
+        self.device.wait_for_state(DfuState::DnloadIdle)?;
+        let packet = self.device.next_packet();
+        let result = dfu::download(
+            &*self.device.device_handle,
+            self.device.interface.number,
+            packet,
+            &[],
+        );
+        self.device.checked(result, "manifest")?;
+
+        self.device.wait_for_state(DfuState::DfuIdle)?;
+        self.device.notify(Phase::WriteComplete);
+        Ok(())
+    }
+}
+
+impl io::Write for DfuWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(self.push(buf)?)
+    }
+
+    /// Does nothing, chunks are only downloaded once full or on
+    /// [`finish`](DfuWriter::finish)
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Xds110DfuDevice {
+    /// How many bytes go into one upload or download: the `wTransferSize` of
+    /// the functional descriptor, in whole flash words, or 1024 without one
+    pub(crate) fn transfer_size(&self) -> usize {
+        match self.interface.functional {
+            Some(functional) if functional.transfer_size as usize >= FLASH_WORD_SIZE => {
+                functional.transfer_size as usize / FLASH_WORD_SIZE * FLASH_WORD_SIZE
+            }
+            _ => DFU_BLOCK_SIZE as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::MockBootloader;
+    use crate::{CONFIGURATION_BLOCK, CONFIGURATION_SIZE, Xds110Error};
+
+    #[test]
+    fn short_chunk_mid_stream() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        dfu.detect_flash_geometry().unwrap();
+        let expected = mock.configuration();
+        let before = mock.lock().uploads;
+        // The third chunk of the read comes up short and ends the upload
+        mock.lock().short_upload = Some((before + 2, 700));
+
+        let read = dfu
+            .read_region(CONFIGURATION_BLOCK, CONFIGURATION_SIZE as u32)
+            .unwrap();
+        assert_eq!(read, expected);
+        // The rest of the third block is read again from its start, and the
+        // 700 bytes already read dropped
+        assert_eq!(mock.lock().uploads - before, 17);
+    }
+
+    #[test]
+    fn empty_chunk_ends_the_read() {
+        let mock = MockBootloader::new();
+        let mut dfu = mock.open();
+        dfu.detect_flash_geometry().unwrap();
+        let before = mock.lock().uploads;
+        mock.lock().short_upload = Some((before + 2, 0));
+
+        let result = dfu.read_region(CONFIGURATION_BLOCK, CONFIGURATION_SIZE as u32);
+        assert!(matches!(
+            result,
+            Err(Xds110Error::UploadEnded {
+                received: 2048,
+                expected: 16384,
+            })
+        ));
+    }
+}
@@ -0,0 +1,541 @@
+//! SWD and DAP register accesses over the CMSIS-DAP interface.
+
+use std::fmt;
+
+use crate::{CmsisDapPort, Xds110Error, Xds110UsbDevice};
+
+const DAP_TRANSFER: u8 = 0x05;
+const DAP_TRANSFER_BLOCK: u8 = 0x06;
+
+/// The debug port register holding the ID of the debug port
+const DPIDR: u8 = 0x00;
+
+/// How many transfers go into one `DAP_Transfer` command, few enough for
+/// the command and its response to fit a 64-byte packet
+const TRANSFERS_PER_COMMAND: usize = 8;
+
+/// How many words go into one `DAP_TransferBlock` command, for the same
+/// reason
+const WORDS_PER_BLOCK: usize = 14;
+
+const ACK_OK: u8 = 0x01;
+const ACK_WAIT: u8 = 0x02;
+const ACK_FAULT: u8 = 0x04;
+const PROTOCOL_ERROR: u8 = 0x08;
+
+/// Line reset, the 16-bit JTAG-to-SWD select sequence, another line reset
+/// and two idle cycles, sent LSB first on SWDIO
+const JTAG_TO_SWD: [u8; 17] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x9e, 0xe7, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x00,
+];
+
+/// How the target answered an SWD transfer that did not succeed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwdAck {
+    /// The target kept asking for the transfer to be retried
+    Wait,
+    /// The target refused the transfer, usually because of a sticky error
+    Fault,
+    /// The probe saw a parity or protocol error
+    ProtocolError,
+    /// No valid acknowledge at all, with the three bits the probe read
+    Invalid(u8),
+}
+
+impl SwdAck {
+    /// `None` for OK
+    fn from_response(ack: u8) -> Option<Self> {
+        match ack {
+            ACK_OK => None,
+            ack if ack & PROTOCOL_ERROR != 0 => Some(SwdAck::ProtocolError),
+            ACK_WAIT => Some(SwdAck::Wait),
+            ACK_FAULT => Some(SwdAck::Fault),
+            ack => Some(SwdAck::Invalid(ack & 0x07)),
+        }
+    }
+}
+
+impl fmt::Display for SwdAck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwdAck::Wait => write!(f, "the target kept answering WAIT"),
+            SwdAck::Fault => write!(f, "the target answered FAULT"),
+            SwdAck::ProtocolError => write!(f, "protocol error"),
+            // 7 is what an undriven SWDIO reads as
+            SwdAck::Invalid(ack) => write!(
+                f,
+                "no valid acknowledge ({:#x}), is the target connected and powered?",
+                ack
+            ),
+        }
+    }
+}
+
+/// One access in a [`dap_transfer`](Xds110UsbDevice::dap_transfer) batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DapTransfer {
+    /// Access a register of the access port selected by the debug port's
+    /// SELECT, rather than one of the debug port itself
+    pub is_ap: bool,
+    pub is_write: bool,
+    /// The register address, 0x0, 0x4, 0x8 or 0xc
+    pub register: u8,
+    /// The value to write. Ignored for reads; a write without one writes 0.
+    pub value: Option<u32>,
+}
+
+impl DapTransfer {
+    pub fn read_dp(register: u8) -> Self {
+        DapTransfer {
+            is_ap: false,
+            is_write: false,
+            register,
+            value: None,
+        }
+    }
+
+    pub fn write_dp(register: u8, value: u32) -> Self {
+        DapTransfer {
+            is_ap: false,
+            is_write: true,
+            register,
+            value: Some(value),
+        }
+    }
+
+    pub fn read_ap(register: u8) -> Self {
+        DapTransfer {
+            is_ap: true,
+            ..DapTransfer::read_dp(register)
+        }
+    }
+
+    pub fn write_ap(register: u8, value: u32) -> Self {
+        DapTransfer {
+            is_ap: true,
+            ..DapTransfer::write_dp(register, value)
+        }
+    }
+
+    /// The transfer request byte: APnDP, RnW and the address bits
+    fn request(&self) -> u8 {
+        request(self.is_ap, self.is_write, self.register)
+    }
+}
+
+impl Xds110UsbDevice {
+    /// Read the SWD debug port register at `register`, which is 0x0, 0x4,
+    /// 0x8 or 0xc. Register 0 is DPIDR, which any ARM debug port answers
+    /// with its designer and version.
+    ///
+    /// This needs the probe in CMSIS-DAP mode. It connects the SWD port,
+    /// switches the target from JTAG to SWD, performs the read and
+    /// disconnects again.
+    pub fn swd_read_dp(&self, register: u8) -> Result<u32, Xds110Error> {
+        check_register(register)?;
+        self.swd_session(|dpidr| match register {
+            DPIDR => Ok(dpidr),
+            _ => Ok(self.dap_transfer(&[DapTransfer::read_dp(register)])?[0]),
+        })
+    }
+
+    /// Write `value` to the SWD debug port register at `register`, for
+    /// example SELECT at 0x8 to pick the access port and bank for the
+    /// accesses that follow. Connects and disconnects like
+    /// [`swd_read_dp`](Self::swd_read_dp).
+    pub fn swd_write_dp(&self, register: u8, value: u32) -> Result<(), Xds110Error> {
+        check_register(register)?;
+        self.swd_session(|_| {
+            self.dap_transfer(&[DapTransfer::write_dp(register, value)])
+                .map(drop)
+        })
+    }
+
+    /// Perform `transfers` in order with `DAP_Transfer` and return the values
+    /// read, one for each read. The debug port must have been connected
+    /// with [`cmsis_dap_connect`](Self::cmsis_dap_connect) beforehand.
+    ///
+    /// The transfers are sent in batches. The first one that is not
+    /// acknowledged fails the whole call with
+    /// [`Xds110Error::SwdTransferFailed`]; the ones before it have happened.
+    pub fn dap_transfer(&self, transfers: &[DapTransfer]) -> Result<Vec<u32>, Xds110Error> {
+        for transfer in transfers {
+            check_register(transfer.register)?;
+        }
+        let dap = self.cmsis_dap()?;
+        let mut values = Vec::new();
+        for batch in transfers.chunks(TRANSFERS_PER_COMMAND) {
+            // The DAP index is ignored for SWD
+            let mut command = vec![DAP_TRANSFER, 0, batch.len() as u8];
+            for transfer in batch {
+                command.push(transfer.request());
+                if transfer.is_write {
+                    command.extend(transfer.value.unwrap_or(0).to_le_bytes());
+                }
+            }
+
+            let mut response = [0u8; 3 + 4 * TRANSFERS_PER_COMMAND];
+            let len = dap.command(&command, &mut response)?;
+            let invalid = || Xds110Error::CmsisDapInvalidResponse {
+                command: DAP_TRANSFER,
+            };
+            if len < 3 {
+                return Err(invalid());
+            }
+            if let Some(ack) = SwdAck::from_response(response[2]) {
+                return Err(Xds110Error::SwdTransferFailed(ack));
+            }
+            let reads = batch.iter().filter(|transfer| !transfer.is_write).count();
+            if response[1] as usize != batch.len() || len < 3 + 4 * reads {
+                return Err(invalid());
+            }
+            values.extend(
+                response[3..3 + 4 * reads]
+                    .chunks(4)
+                    .map(|value| u32::from_le_bytes(value.try_into().unwrap())),
+            );
+        }
+        Ok(values)
+    }
+
+    /// Read or write `data.len()` words at the one register `register` with
+    /// `DAP_TransferBlock`, such as the data register of a MEM-AP with
+    /// auto-increment on. Writes send the words of `data` and return nothing;
+    /// reads ignore the contents of `data` and return the words read. The
+    /// debug port must be connected, as for [`dap_transfer`](Self::dap_transfer).
+    ///
+    /// The words are sent in batches and the first one that is not
+    /// acknowledged fails the call, with the words before it transferred.
+    pub fn dap_transfer_block(
+        &self,
+        is_ap: bool,
+        is_write: bool,
+        register: u8,
+        data: &[u32],
+    ) -> Result<Vec<u32>, Xds110Error> {
+        check_register(register)?;
+        let dap = self.cmsis_dap()?;
+        let mut values = Vec::new();
+        for batch in data.chunks(WORDS_PER_BLOCK) {
+            // The DAP index is ignored for SWD
+            let mut command = vec![DAP_TRANSFER_BLOCK, 0];
+            command.extend((batch.len() as u16).to_le_bytes());
+            command.push(request(is_ap, is_write, register));
+            if is_write {
+                command.extend(batch.iter().flat_map(|word| word.to_le_bytes()));
+            }
+
+            let mut response = [0u8; 4 + 4 * WORDS_PER_BLOCK];
+            let len = dap.command(&command, &mut response)?;
+            let invalid = || Xds110Error::CmsisDapInvalidResponse {
+                command: DAP_TRANSFER_BLOCK,
+            };
+            if len < 4 {
+                return Err(invalid());
+            }
+            if let Some(ack) = SwdAck::from_response(response[3]) {
+                return Err(Xds110Error::SwdTransferFailed(ack));
+            }
+            let count = u16::from_le_bytes([response[1], response[2]]) as usize;
+            let read = if is_write { 0 } else { batch.len() };
+            if count != batch.len() || len < 4 + 4 * read {
+                return Err(invalid());
+            }
+            values.extend(
+                response[4..4 + 4 * read]
+                    .chunks(4)
+                    .map(|value| u32::from_le_bytes(value.try_into().unwrap())),
+            );
+        }
+        Ok(values)
+    }
+
+    /// Connect the SWD port, switch the target to SWD and run `f` with its
+    /// DPIDR, then disconnect again
+    fn swd_session<T>(
+        &self,
+        f: impl FnOnce(u32) -> Result<T, Xds110Error>,
+    ) -> Result<T, Xds110Error> {
+        self.cmsis_dap_connect(CmsisDapPort::Swd)?;
+        let result = self.swd_connect().and_then(|()| {
+            // After a line reset the debug port only accepts a DPIDR read
+            let dpidr = self.dap_transfer(&[DapTransfer::read_dp(DPIDR)])?[0];
+            f(dpidr)
+        });
+        // A failed access is the more useful error to report
+        let disconnected = self.cmsis_dap_disconnect();
+        let result = result?;
+        disconnected?;
+        Ok(result)
+    }
+
+    /// Switch the target's debug port to SWD and leave it just after a line
+    /// reset
+    fn swd_connect(&self) -> Result<(), Xds110Error> {
+        self.swj_sequence((JTAG_TO_SWD.len() * 8) as u8, &JTAG_TO_SWD)
+    }
+}
+
+/// The transfer request byte: APnDP, RnW and the address bits
+fn request(is_ap: bool, is_write: bool, register: u8) -> u8 {
+    is_ap as u8 | (!is_write as u8) << 1 | register
+}
+
+/// Fail unless `register` is one of the four register addresses
+fn check_register(register: u8) -> Result<(), Xds110Error> {
+    if register & !0x0c != 0 {
+        return Err(Xds110Error::InvalidDapRegister(register));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{DAP_EPOUT, MockFirmware};
+
+    const DAP_CONNECT: u8 = 0x02;
+    const DAP_DISCONNECT: u8 = 0x03;
+    const DAP_SWJ_SEQUENCE: u8 = 0x12;
+
+    const IDR: u32 = 0x2ba0_1477;
+
+    /// A probe whose CMSIS-DAP interface connects SWD and takes any
+    /// sequence, answering transfers with what `transfer` returns for the
+    /// command
+    fn probe(
+        mut transfer: impl FnMut(&[u8]) -> Vec<u8> + Send + 'static,
+    ) -> (Xds110UsbDevice, MockFirmware) {
+        let dap = MockFirmware::new(move |endpoint, request| {
+            assert_eq!(endpoint, DAP_EPOUT);
+            Some(match request[0] {
+                DAP_CONNECT => vec![DAP_CONNECT, request[1]],
+                DAP_DISCONNECT | DAP_SWJ_SEQUENCE => vec![request[0], 0],
+                _ => transfer(request),
+            })
+        });
+        let device = MockFirmware::xds110(0, 0).open_with_cmsis_dap(&dap);
+        (device, dap)
+    }
+
+    /// A debug port that acknowledges every transfer, reading the DPIDR from
+    /// register 0 and `register << 24 | index` from any other register,
+    /// where `index` is the position of the transfer in its command
+    fn target(request: &[u8]) -> Vec<u8> {
+        answer(request, |register, index| match register {
+            DPIDR => IDR,
+            register => (register as u32) << 24 | index,
+        })
+    }
+
+    /// Answer the `DAP_Transfer` `request` with every transfer acknowledged,
+    /// reads giving what `read` returns for their register and position
+    fn answer(request: &[u8], read: impl Fn(u8, u32) -> u32) -> Vec<u8> {
+        assert_eq!(request[0], DAP_TRANSFER);
+        let count = request[2];
+        let mut response = vec![DAP_TRANSFER, count, ACK_OK];
+        let mut at = 3;
+        for index in 0..count as u32 {
+            let transfer = request[at];
+            at += 1;
+            if transfer & 0x02 == 0 {
+                at += 4;
+            } else {
+                response.extend(read(transfer & 0x0c, index).to_le_bytes());
+            }
+        }
+        assert_eq!(at, request.len());
+        response
+    }
+
+    /// The commands sent to the CMSIS-DAP interface
+    fn sent(dap: &MockFirmware) -> Vec<Vec<u8>> {
+        dap.written().into_iter().map(|(_, data)| data).collect()
+    }
+
+    fn jtag_to_swd() -> Vec<u8> {
+        let mut command = vec![DAP_SWJ_SEQUENCE, 136];
+        command.extend(JTAG_TO_SWD);
+        command
+    }
+
+    #[test]
+    fn dpidr_read_connects_switches_and_disconnects() {
+        let (device, dap) = probe(target);
+        assert_eq!(device.swd_read_dp(DPIDR).unwrap(), IDR);
+        assert_eq!(
+            sent(&dap),
+            [
+                vec![DAP_CONNECT, CmsisDapPort::Swd as u8],
+                jtag_to_swd(),
+                // Index 0, one transfer, a DP read of address 0
+                vec![DAP_TRANSFER, 0, 1, 0x02],
+                vec![DAP_DISCONNECT],
+            ]
+        );
+    }
+
+    #[test]
+    fn other_registers_are_read_after_the_dpidr() {
+        let (device, dap) = probe(target);
+        assert_eq!(device.swd_read_dp(0x4).unwrap(), 0x0400_0000);
+        assert_eq!(sent(&dap)[3], [DAP_TRANSFER, 0, 1, 0x06]);
+        assert_eq!(sent(&dap).len(), 5);
+    }
+
+    #[test]
+    fn dp_write_sends_the_value() {
+        let (device, dap) = probe(target);
+        device.swd_write_dp(0x8, 0x0100_00f0).unwrap();
+        assert_eq!(
+            sent(&dap)[3],
+            [DAP_TRANSFER, 0, 1, 0x08, 0xf0, 0x00, 0x00, 0x01]
+        );
+        assert_eq!(*sent(&dap).last().unwrap(), [DAP_DISCONNECT]);
+    }
+
+    #[test]
+    fn acks_are_decoded() {
+        for (ack, expected) in [
+            (ACK_WAIT, SwdAck::Wait),
+            (ACK_FAULT, SwdAck::Fault),
+            (PROTOCOL_ERROR | ACK_OK, SwdAck::ProtocolError),
+            (0x07, SwdAck::Invalid(0x07)),
+        ] {
+            let (device, dap) = probe(move |_| vec![DAP_TRANSFER, 0, ack]);
+            assert!(matches!(
+                device.swd_read_dp(DPIDR),
+                Err(Xds110Error::SwdTransferFailed(found)) if found == expected
+            ));
+            // Disconnected all the same
+            assert_eq!(*sent(&dap).last().unwrap(), [DAP_DISCONNECT]);
+        }
+    }
+
+    #[test]
+    fn bad_registers_send_nothing() {
+        let (device, dap) = probe(target);
+        assert!(matches!(
+            device.swd_read_dp(0x3),
+            Err(Xds110Error::InvalidDapRegister(0x3))
+        ));
+        assert!(matches!(
+            device.dap_transfer(&[DapTransfer::read_ap(0x10)]),
+            Err(Xds110Error::InvalidDapRegister(0x10))
+        ));
+        assert_eq!(sent(&dap), Vec::<Vec<u8>>::new());
+    }
+
+    #[test]
+    fn transfers_go_out_in_batches() {
+        let (device, dap) = probe(target);
+        let mut transfers = vec![DapTransfer::write_ap(0x4, 0x2000_0000)];
+        transfers.extend((0..9).map(|_| DapTransfer::read_ap(0xc)));
+        let values = device.dap_transfer(&transfers).unwrap();
+
+        // Seven reads in the first command after the write, two in the second
+        let expected: Vec<u32> = (1..8).chain(0..2).map(|i| 0x0c00_0000 | i).collect();
+        assert_eq!(values, expected);
+        let sent = sent(&dap);
+        assert_eq!(sent.len(), 2);
+        let mut first = vec![DAP_TRANSFER, 0, 8, 0x05, 0x00, 0x00, 0x00, 0x20];
+        first.extend([0x0f; 7]);
+        assert_eq!(sent[0], first);
+        assert_eq!(sent[1], [DAP_TRANSFER, 0, 2, 0x0f, 0x0f]);
+    }
+
+    #[test]
+    fn transfers_stop_at_the_first_failure() {
+        // The second batch faults on its third transfer
+        let mut commands = 0;
+        let (device, dap) = probe(move |request| {
+            commands += 1;
+            match commands {
+                1 => answer(request, |_, index| index),
+                _ => vec![DAP_TRANSFER, 2, ACK_FAULT],
+            }
+        });
+        let transfers = vec![DapTransfer::read_dp(0x4); 20];
+        assert!(matches!(
+            device.dap_transfer(&transfers),
+            Err(Xds110Error::SwdTransferFailed(SwdAck::Fault))
+        ));
+        assert_eq!(sent(&dap).len(), 2);
+    }
+
+    #[test]
+    fn short_counts_are_invalid() {
+        // Acknowledged, but only one of the two transfers was done
+        let (device, _) = probe(|_| vec![DAP_TRANSFER, 1, ACK_OK, 0, 0, 0, 0]);
+        let transfers = [DapTransfer::read_dp(0x4), DapTransfer::read_dp(0x4)];
+        assert!(matches!(
+            device.dap_transfer(&transfers),
+            Err(Xds110Error::CmsisDapInvalidResponse {
+                command: DAP_TRANSFER
+            })
+        ));
+    }
+
+    /// Answer `DAP_TransferBlock` with every word acknowledged, reads giving
+    /// their position in the block
+    fn block(request: &[u8]) -> Vec<u8> {
+        assert_eq!(request[0], DAP_TRANSFER_BLOCK);
+        let count = u16::from_le_bytes([request[2], request[3]]);
+        let mut response = vec![DAP_TRANSFER_BLOCK, request[2], request[3], ACK_OK];
+        if request[4] & 0x02 != 0 {
+            assert_eq!(request.len(), 5);
+            response.extend((0..count as u32).flat_map(u32::to_le_bytes));
+        } else {
+            assert_eq!(request.len(), 5 + 4 * count as usize);
+        }
+        response
+    }
+
+    #[test]
+    fn block_writes_go_out_in_batches() {
+        let (device, dap) = probe(block);
+        let data: Vec<u32> = (0..20).collect();
+        assert_eq!(
+            device.dap_transfer_block(true, true, 0xc, &data).unwrap(),
+            Vec::<u32>::new()
+        );
+        let sent = sent(&dap);
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0][..5], [DAP_TRANSFER_BLOCK, 0, 14, 0, 0x0d]);
+        assert_eq!(sent[0][5..9], 0u32.to_le_bytes());
+        assert_eq!(sent[1][..5], [DAP_TRANSFER_BLOCK, 0, 6, 0, 0x0d]);
+        assert_eq!(sent[1][5..9], 14u32.to_le_bytes());
+    }
+
+    #[test]
+    fn block_reads_return_every_word() {
+        let (device, dap) = probe(block);
+        let words = device
+            .dap_transfer_block(true, false, 0xc, &[0; 20])
+            .unwrap();
+        let expected: Vec<u32> = (0..14).chain(0..6).collect();
+        assert_eq!(words, expected);
+        assert_eq!(sent(&dap)[1], [DAP_TRANSFER_BLOCK, 0, 6, 0, 0x0f]);
+    }
+
+    #[test]
+    fn short_blocks_are_invalid_or_failed() {
+        // Nine of fourteen words, acknowledged
+        let (device, _) = probe(|_| vec![DAP_TRANSFER_BLOCK, 9, 0, ACK_OK]);
+        assert!(matches!(
+            device.dap_transfer_block(true, true, 0xc, &[0; 14]),
+            Err(Xds110Error::CmsisDapInvalidResponse {
+                command: DAP_TRANSFER_BLOCK
+            })
+        ));
+
+        // Nine of fourteen words, then WAIT: the next batch is not sent
+        let (device, dap) = probe(|_| vec![DAP_TRANSFER_BLOCK, 9, 0, ACK_WAIT]);
+        assert!(matches!(
+            device.dap_transfer_block(true, true, 0xc, &[0; 20]),
+            Err(Xds110Error::SwdTransferFailed(SwdAck::Wait))
+        ));
+        assert_eq!(sent(&dap).len(), 1);
+    }
+}
@@ -0,0 +1,485 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::bus::UsbBus;
+use crate::cmsis_dap::LazyCmsisDap;
+use crate::firmware;
+use crate::{
+    CMSIS_DAP_2_MINIMUM, CancelToken, ConfigPatch, FIRMWARE_VERSION_TIMEOUT, FirmwareVersion,
+    FirmwareVersionError, MODE_OFFSET, Observer, Phase, ProbeMode, StatusTrace, Warning,
+    Xds110Configuration, Xds110DfuDevice, Xds110Error, Xds110Probe, Xds110UsbDevice,
+};
+
+/// What [`switch_mode`] ended up doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwitchOutcome {
+    /// The probe was already configured for the requested mode
+    AlreadyInMode,
+    /// The configuration was rewritten, `from` is the previous mode
+    Switched { from: ProbeMode },
+}
+
+/// Knobs for [`switch_mode`]
+#[derive(Clone, Default)]
+pub struct SwitchOptions {
+    /// Receives progress and warnings
+    pub observer: Option<Arc<dyn Observer>>,
+    /// Proceed even if the firmware is older than the mode requires. This is
+    /// unsupported and may leave the probe in a state it cannot run. Also
+    /// lets [`flash_firmware_file`] program a file meant for another device,
+    /// and a configuration in an unrecognized layout be rewritten.
+    pub force: bool,
+    /// Records the bootloader's status responses. Keep a clone to inspect
+    /// the trace after the switch, in particular when it fails.
+    pub status_trace: Option<StatusTrace>,
+    /// Stops the configuration read or write in progress. The probe is left
+    /// in the bootloader when this happens.
+    pub cancel: Option<CancelToken>,
+    /// Timeout for each transfer of the firmware version query, which is the
+    /// first thing sent to the probe. Defaults to
+    /// [`FIRMWARE_VERSION_TIMEOUT`].
+    pub version_timeout: Option<Duration>,
+    /// How long each wait for the bootloader may take. Defaults to
+    /// [`DFU_WAIT_TIMEOUT`](crate::DFU_WAIT_TIMEOUT).
+    pub wait_timeout: Option<Duration>,
+    /// Skip reading the configuration back after writing it
+    pub no_verify: bool,
+    /// Where the bootloader starts execution when it is done. Defaults to
+    /// [`RESET_ADDRESS`](crate::RESET_ADDRESS).
+    pub reset_address: Option<u32>,
+}
+
+/// Configure `probe` to boot into `mode`, going through the DFU bootloader
+/// and resetting back into the normal firmware afterwards. Returns once the
+/// probe has re-enumerated.
+pub fn switch_mode(
+    probe: Xds110Probe,
+    mode: ProbeMode,
+    options: &SwitchOptions,
+) -> Result<SwitchOutcome, Xds110Error> {
+    let mut firmware = None;
+    if let Xds110Probe::Runtime(xds110) = &probe {
+        firmware = Some(check_firmware(xds110, mode, options)?);
+        if let Some(observer) = &options.observer {
+            observer.phase(Phase::EnteringDfu);
+        }
+    }
+
+    let outcome = with_dfu(probe, options, |dfu| {
+        // The bootloader cannot tell which firmware the configuration is for
+        dfu.firmware = firmware;
+        dfu.set_mode(mode)
+    })?;
+    Ok(match outcome {
+        SetModeOutcome::Unchanged => SwitchOutcome::AlreadyInMode,
+        SetModeOutcome::Changed { from } => SwitchOutcome::Switched { from },
+    })
+}
+
+/// The first half of [`switch_mode`] for callers that manage the USB handles
+/// themselves: check the firmware supports `target` and reboot the probe
+/// behind `interface` into the DFU bootloader.
+///
+/// This returns as soon as the reboot has been requested. Waiting for the
+/// bootloader to enumerate, opening it and passing it to
+/// [`convert_dfu_device`] is up to the caller.
+pub fn switch_mode_on(
+    interface: &nusb::Interface,
+    epin: u8,
+    epout: u8,
+    target: ProbeMode,
+) -> Result<(), Xds110Error> {
+    let xds110 = Xds110UsbDevice {
+        device_handle: Box::new(interface.clone()),
+        epout,
+        epin,
+        serial: None,
+        port_path: None,
+        bus: Arc::new(UsbBus),
+        cmsis_dap: LazyCmsisDap::none(),
+    };
+    check_firmware(&xds110, target, &SwitchOptions::default())?;
+    xds110.reboot_to_dfu()?;
+    Ok(())
+}
+
+/// The second half of [`switch_mode`] for callers that manage the USB handles
+/// themselves: configure the bootloader behind `device` for `target` and
+/// reset it into the normal firmware.
+///
+/// This returns as soon as the reset has been sent. The probe then drops off
+/// the bus and comes back running its normal firmware; waiting for that is
+/// up to the caller.
+pub fn convert_dfu_device(
+    device: &nusb::Device,
+    target: ProbeMode,
+) -> Result<SetModeOutcome, Xds110Error> {
+    let mut dfu = Xds110DfuDevice::new(device.clone())?;
+    dfu.recover()?;
+    dfu.ensure_binary_protocol()?;
+    let outcome = dfu.set_mode(target)?;
+    dfu.leave_bootloader()?;
+    Ok(outcome)
+}
+
+/// Fail if the running firmware is too old for `mode`, unless forced.
+/// Returns the version found.
+fn check_firmware(
+    xds110: &Xds110UsbDevice,
+    mode: ProbeMode,
+    options: &SwitchOptions,
+) -> Result<FirmwareVersion, Xds110Error> {
+    let observer = &options.observer;
+    let timeout = options.version_timeout.unwrap_or(FIRMWARE_VERSION_TIMEOUT);
+    let minimum = match mode {
+        ProbeMode::CmsisDap2 => FirmwareVersion(CMSIS_DAP_2_MINIMUM),
+        _ => FirmwareVersion(0),
+    };
+    let result = match xds110.check_minimum_firmware_with_timeout(minimum, timeout) {
+        // A freshly enumerated probe can be slow to answer, give it a
+        // second chance
+        Err(FirmwareVersionError::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut => {
+            if let Some(observer) = observer {
+                observer.warning(&Warning::Retry {
+                    operation: "firmware version query",
+                    attempt: 2,
+                });
+            }
+            xds110.check_minimum_firmware_with_timeout(minimum, timeout)
+        }
+        result => result,
+    };
+    match result {
+        Err(FirmwareVersionError::TooOld { found, minimum }) if options.force => {
+            if let Some(observer) = observer {
+                observer.warning(&Warning::FirmwareTooOld { found, minimum });
+            }
+            Ok(found)
+        }
+        result => result.map_err(Xds110Error::from),
+    }
+}
+
+/// Apply `patches` to the configuration of `probe`, going through the DFU
+/// bootloader like [`switch_mode`]. See
+/// [`Xds110DfuDevice::patch_configuration`] for the details. Returns the
+/// number of bytes that changed.
+pub fn patch_config(
+    probe: Xds110Probe,
+    patches: &[ConfigPatch],
+    allow_reserved: bool,
+    options: &SwitchOptions,
+) -> Result<usize, Xds110Error> {
+    if let (Xds110Probe::Runtime(_), Some(observer)) = (&probe, &options.observer) {
+        observer.phase(Phase::EnteringDfu);
+    }
+    with_dfu(probe, options, |dfu| {
+        dfu.patch_configuration(patches, allow_reserved)
+    })
+}
+
+/// Read the configuration of `probe` and write it back unchanged, going
+/// through the DFU bootloader like [`switch_mode`]. This exercises the whole
+/// write, verify and reset cycle without changing anything, as a check
+/// before a real reconfiguration. The write is always verified, whatever
+/// `options.no_verify` says. Returns the mode the configuration selects.
+pub fn rewrite_config(
+    probe: Xds110Probe,
+    options: &SwitchOptions,
+) -> Result<ProbeMode, Xds110Error> {
+    if let (Xds110Probe::Runtime(_), Some(observer)) = (&probe, &options.observer) {
+        observer.phase(Phase::EnteringDfu);
+    }
+    with_dfu(probe, options, |dfu| {
+        let mut configuration = dfu.read_configuration()?;
+        dfu.check_layout(&mut configuration)?;
+        dfu.set_verify_writes(true);
+        dfu.write_configuration(&configuration)?;
+        Ok(configuration.summary().mode)
+    })
+}
+
+/// Read the configuration of `probe` without changing anything, going
+/// through the DFU bootloader like [`switch_mode`], which resets the probe
+/// back into its normal firmware afterwards
+pub fn read_config(
+    probe: Xds110Probe,
+    options: &SwitchOptions,
+) -> Result<Xds110Configuration, Xds110Error> {
+    if let (Xds110Probe::Runtime(_), Some(observer)) = (&probe, &options.observer) {
+        observer.phase(Phase::EnteringDfu);
+    }
+    with_dfu(probe, options, Xds110DfuDevice::read_configuration)
+}
+
+/// Erase `num_blocks` flash blocks of `probe` from `start_block` on, going
+/// through the DFU bootloader like [`switch_mode`]. See
+/// [`Xds110DfuDevice::erase_region`].
+pub fn erase_flash(
+    probe: Xds110Probe,
+    start_block: u16,
+    num_blocks: u16,
+    options: &SwitchOptions,
+) -> Result<(), Xds110Error> {
+    if let (Xds110Probe::Runtime(_), Some(observer)) = (&probe, &options.observer) {
+        observer.phase(Phase::EnteringDfu);
+    }
+    with_dfu(probe, options, |dfu| {
+        dfu.erase_region(start_block, num_blocks)
+    })
+}
+
+/// Program the firmware file `file` into `probe`, going through the DFU
+/// bootloader like [`switch_mode`]. See
+/// [`Xds110DfuDevice::program_firmware_file`]; `options.force` allows a file
+/// whose DFU suffix names another device. Returns the size of the image that
+/// was written.
+pub fn flash_firmware_file(
+    probe: Xds110Probe,
+    file: &[u8],
+    options: &SwitchOptions,
+) -> Result<usize, Xds110Error> {
+    if let (Xds110Probe::Runtime(_), Some(observer)) = (&probe, &options.observer) {
+        observer.phase(Phase::EnteringDfu);
+    }
+    with_dfu(probe, options, |dfu| {
+        dfu.program_firmware_file(file, options.force)
+    })
+}
+
+/// Recover `probe` from firmware that is beyond repair, going through the DFU
+/// bootloader like [`switch_mode`]: erase the whole application region with
+/// [`Xds110DfuDevice::mass_erase`], then program `image` if there is one and
+/// reset into it. Returns the size of the image that was written.
+///
+/// The DFU suffix of `image` is checked before anything is erased, like
+/// [`flash_firmware_file`] does with `options.force`. Without an image
+/// there is no firmware to start, and the probe is left in its bootloader.
+pub fn recover_probe(
+    probe: Xds110Probe,
+    image: Option<&[u8]>,
+    options: &SwitchOptions,
+) -> Result<Option<usize>, Xds110Error> {
+    if let Some(file) = image {
+        firmware::strip_dfu_suffix(file, options.force)?;
+    }
+    if let (Xds110Probe::Runtime(_), Some(observer)) = (&probe, &options.observer) {
+        observer.phase(Phase::EnteringDfu);
+    }
+    let mut dfu = prepare_dfu(probe, options)?;
+    dfu.mass_erase()?;
+    let Some(file) = image else {
+        dfu.close_gracefully()?;
+        return Ok(None);
+    };
+    let written = dfu.program_firmware_file(file, options.force)?;
+    dfu.reset()?;
+    Ok(Some(written))
+}
+
+/// Enter the bootloader, run `f` on it and reset back into the normal
+/// firmware
+fn with_dfu<T>(
+    probe: Xds110Probe,
+    options: &SwitchOptions,
+    f: impl FnOnce(&mut Xds110DfuDevice) -> Result<T, Xds110Error>,
+) -> Result<T, Xds110Error> {
+    let mut dfu = prepare_dfu(probe, options)?;
+    let result = f(&mut dfu)?;
+    dfu.reset()?;
+    Ok(result)
+}
+
+/// Enter the bootloader and set it up as `options` ask
+fn prepare_dfu(
+    probe: Xds110Probe,
+    options: &SwitchOptions,
+) -> Result<Xds110DfuDevice, Xds110Error> {
+    let mut dfu = probe.enter_dfu_cancellable(options.cancel.as_ref())?;
+    if let Some(observer) = &options.observer {
+        dfu.set_observer(observer.clone());
+        // Found when the bootloader was opened, before anyone could hear
+        if let Some(status) = dfu.interrupted_session() {
+            observer.warning(&Warning::InterruptedSession {
+                state: status.state,
+                status: status.status,
+            });
+        }
+    }
+    if let Some(trace) = &options.status_trace {
+        dfu.set_status_trace(trace.clone());
+    }
+    if let Some(token) = &options.cancel {
+        dfu.set_cancel_token(token.clone());
+    }
+    if let Some(timeout) = options.wait_timeout {
+        dfu.set_wait_timeout(timeout);
+    }
+    dfu.set_verify_writes(!options.no_verify);
+    dfu.set_allow_unrecognized_layout(options.force);
+
+    dfu.ensure_binary_protocol()?;
+    if let Some(address) = options.reset_address {
+        dfu.set_reset_address(address)?;
+    }
+    Ok(dfu)
+}
+
+/// What [`Xds110DfuDevice::set_mode`] ended up doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetModeOutcome {
+    /// The configuration already selected the mode and was left untouched
+    Unchanged,
+    /// The configuration was rewritten, `from` is the previous mode
+    Changed { from: ProbeMode },
+}
+
+impl Xds110DfuDevice {
+    /// Rewrite the configuration to select `mode`. The configuration is only
+    /// written if it selects a different mode, so this is safe to repeat.
+    pub fn set_mode(&mut self, mode: ProbeMode) -> Result<SetModeOutcome, Xds110Error> {
+        let mut configuration = self.read_configuration()?;
+        self.check_layout(&mut configuration)?;
+
+        let summary = configuration.summary();
+        if !summary.magic_valid {
+            self.warn(&Warning::MagicRepaired {
+                found: summary.magic,
+            });
+            configuration.bytes_mut()[MODE_OFFSET + 1] = 0;
+            configuration.set_magic();
+        }
+        let current_mode = configuration.summary().mode;
+
+        if current_mode == mode {
+            return Ok(SetModeOutcome::Unchanged);
+        }
+        configuration.set_mode(mode);
+
+        self.write_configuration(&configuration)?;
+        Ok(SetModeOutcome::Changed { from: current_mode })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProbeState;
+    use crate::mock::{self, MockBootloader, MockBus, MockProbe};
+    use crate::{CONFIGURATION_BLOCK, DFU_BLOCK_SIZE, DfuSuffix};
+
+    const APP_BLOCK: u16 = (mock::APP_START / DFU_BLOCK_SIZE) as u16;
+    const CONFIG_START: usize = CONFIGURATION_BLOCK as usize * DFU_BLOCK_SIZE as usize;
+
+    /// A firmware file for a device other than the XDS110
+    fn foreign_file() -> Vec<u8> {
+        let mut file = vec![0x5a; 3000];
+        DfuSuffix {
+            vendor: 0x1234,
+            product: 0x5678,
+            ..DfuSuffix::xds110()
+        }
+        .append_to(&mut file);
+        file
+    }
+
+    fn assert_bootloader_and_configuration_kept(mock: &MockBootloader, configuration: &[u8]) {
+        let device = mock.lock();
+        assert!(
+            device.flash[..mock::APP_START as usize]
+                .iter()
+                .all(|&b| b == mock::BOOTLOADER_FILL)
+        );
+        drop(device);
+        assert_eq!(mock.configuration(), configuration);
+    }
+
+    #[test]
+    fn recover_erases_only_the_application() {
+        let mock = MockBootloader::new();
+        let configuration = mock.configuration();
+        let recovered = recover_probe(
+            Xds110Probe::Dfu(mock.open()),
+            None,
+            &SwitchOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(recovered, None);
+
+        assert_bootloader_and_configuration_kept(&mock, &configuration);
+        let device = mock.lock();
+        assert_eq!(
+            device.erases,
+            [(APP_BLOCK, CONFIGURATION_BLOCK - APP_BLOCK)]
+        );
+        assert!(
+            device.flash[mock::APP_START as usize..CONFIG_START]
+                .iter()
+                .all(|&b| b == 0xff)
+        );
+        assert_eq!(device.reset_to, None);
+    }
+
+    #[test]
+    fn recover_erases_everything_before_flashing() {
+        let ours = MockProbe::new("MOCK0001", ProbeState::Dfu);
+        let bus = MockBus::new(vec![ours.clone()]);
+        let mock = ours.bootloader.clone();
+        let configuration = mock.configuration();
+        let image = vec![0x5a; 3000];
+        let recovered =
+            recover_probe(bus.open(&ours), Some(&image), &SwitchOptions::default()).unwrap();
+        assert_eq!(recovered, Some(image.len()));
+
+        assert_bootloader_and_configuration_kept(&mock, &configuration);
+        let device = mock.lock();
+        assert_eq!(
+            device.erases[0],
+            (APP_BLOCK, CONFIGURATION_BLOCK - APP_BLOCK)
+        );
+        let app = mock::APP_START as usize;
+        assert_eq!(device.flash[app..app + image.len()], image);
+        // Nothing of the old, longer image is left behind the new one
+        assert!(
+            device.flash[app + image.len()..CONFIG_START]
+                .iter()
+                .all(|&b| b == 0xff)
+        );
+        assert!(device.reset_to.is_some());
+    }
+
+    #[test]
+    fn recover_refuses_a_foreign_image_before_erasing() {
+        let mock = MockBootloader::new();
+        let result = recover_probe(
+            Xds110Probe::Dfu(mock.open()),
+            Some(&foreign_file()),
+            &SwitchOptions::default(),
+        );
+        assert!(matches!(
+            result,
+            Err(Xds110Error::DfuSuffixMismatch {
+                vendor: 0x1234,
+                product: 0x5678
+            })
+        ));
+        let device = mock.lock();
+        assert!(device.erases.is_empty());
+        assert_eq!(
+            device.flash[mock::APP_START as usize],
+            mock::APPLICATION_FILL
+        );
+    }
+
+    #[test]
+    fn recover_flashes_a_foreign_image_with_force() {
+        let ours = MockProbe::new("MOCK0001", ProbeState::Dfu);
+        let bus = MockBus::new(vec![ours.clone()]);
+        let options = SwitchOptions {
+            force: true,
+            ..Default::default()
+        };
+        let recovered = recover_probe(bus.open(&ours), Some(&foreign_file()), &options).unwrap();
+        assert_eq!(recovered, Some(3000));
+    }
+}
@@ -0,0 +1,61 @@
+//! A record of the DFU states a bootloader went through, for bug reports.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::dfu::DfuStatus;
+
+/// One status response and when it arrived
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub at: Instant,
+    pub status: DfuStatus,
+}
+
+/// Every [`DfuStatus`] a device returned, in order. Clones share the same
+/// buffer, so a caller can keep one while the device records into another.
+#[derive(Debug, Clone, Default)]
+pub struct StatusTrace(Arc<Mutex<Vec<TraceEntry>>>);
+
+impl StatusTrace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record(&self, status: DfuStatus) {
+        let entry = TraceEntry {
+            at: Instant::now(),
+            status,
+        };
+        self.0.lock().unwrap().push(entry);
+    }
+
+    /// A copy of the entries recorded so far
+    pub fn entries(&self) -> Vec<TraceEntry> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// One line per entry, timestamped relative to the first
+impl fmt::Display for StatusTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries = self.0.lock().unwrap();
+        let Some(first) = entries.first() else {
+            return Ok(());
+        };
+        for entry in entries.iter() {
+            let status = &entry.status;
+            writeln!(
+                f,
+                "{:>8.3}ms  {:?} status={} poll={}ms string={}",
+                (entry.at - first.at).as_secs_f64() * 1000.0,
+                status.state,
+                status.status,
+                status.poll_timeout.as_millis(),
+                status.string_index
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,597 @@
+//! Recording every USB transfer, for attaching to bug reports.
+//!
+//! With the `transcript` feature, [`record_transcript`] sends one line per
+//! transfer to a writer, in the order the transfers finished:
+//!
+//! ```text
+//! control_in request=0x03 value=0x0000 index=0x0000 length=6 ok 000000020000
+//! control_out request=0x01 value=0x0002 index=0x0000 data=0300 ok
+//! bulk_out endpoint=0x02 data=2a010003 ok 4
+//! bulk_in endpoint=0x83 length=64 error TimedOut
+//! ```
+//!
+//! Everything except the result is what was asked of the device; the
+//! result is `ok`, followed by the data received or the number of bytes
+//! sent, or `error` and the kind of failure. Numbers and data are hex.
+//!
+//! [`record_transcript_as`] with [`TranscriptFormat::JsonLines`] writes the
+//! same fields as one JSON object per line instead, as numbers and hex
+//! strings, along with the time in seconds since the Unix epoch:
+//!
+//! ```text
+//! {"time":1760400000.123456,"transfer":"bulk_out","direction":"out","endpoint":2,"data":"2a010003","ok":true,"sent":4}
+//! {"time":1760400000.123801,"transfer":"bulk_in","direction":"in","endpoint":131,"length":64,"ok":false,"error":"TimedOut"}
+//! ```
+//!
+//! Without the feature, the hooks compile to nothing.
+//!
+//! The tests play a text transcript back in place of the device with
+//! `Replay`, so a session from a bug report can be stepped through without
+//! the probe that recorded it.
+
+use std::io;
+
+use nusb::transfer::TransferError;
+
+use crate::error::hex;
+
+/// How [`record_transcript_as`] writes each transfer
+#[cfg(feature = "transcript")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TranscriptFormat {
+    /// The space-separated lines shown in the module documentation
+    #[default]
+    Text,
+    /// One JSON object per line, with a timestamp
+    JsonLines,
+}
+
+#[cfg(feature = "transcript")]
+struct Sink {
+    writer: Box<dyn io::Write + Send>,
+    format: TranscriptFormat,
+}
+
+#[cfg(feature = "transcript")]
+static SINK: std::sync::Mutex<Option<Sink>> = std::sync::Mutex::new(None);
+
+/// Record every transfer from now on to `writer`, in place of any previous
+/// one. Each line is flushed as it is written, so the transcript survives a
+/// crash.
+#[cfg(feature = "transcript")]
+pub fn record_transcript(writer: impl io::Write + Send + 'static) {
+    record_transcript_as(writer, TranscriptFormat::Text);
+}
+
+/// [`record_transcript`] in `format`
+#[cfg(feature = "transcript")]
+pub fn record_transcript_as(writer: impl io::Write + Send + 'static, format: TranscriptFormat) {
+    *lock() = Some(Sink {
+        writer: Box::new(writer),
+        format,
+    });
+}
+
+/// Stop recording transfers and drop the writer
+#[cfg(feature = "transcript")]
+pub fn stop_transcript() {
+    *lock() = None;
+}
+
+#[cfg(feature = "transcript")]
+fn lock() -> std::sync::MutexGuard<'static, Option<Sink>> {
+    SINK.lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Write the transfer built by `entry` if a transcript is being recorded
+fn record(entry: impl FnOnce() -> Entry) {
+    #[cfg(feature = "transcript")]
+    if let Some(sink) = lock().as_mut() {
+        let entry = entry();
+        let line = match sink.format {
+            TranscriptFormat::Text => entry.text(),
+            TranscriptFormat::JsonLines => entry.json(),
+        };
+        // A transcript that cannot be written must not fail the transfer
+        let _ = writeln!(sink.writer, "{}", line).and_then(|()| sink.writer.flush());
+    }
+    #[cfg(not(feature = "transcript"))]
+    let _ = entry;
+}
+
+/// One finished transfer
+#[cfg_attr(not(feature = "transcript"), allow(dead_code))]
+struct Entry {
+    transfer: &'static str,
+    /// The setup or endpoint, and the length asked for
+    fields: Vec<(&'static str, Field)>,
+    /// The data sent, for transfers out
+    data: Option<String>,
+    result: Result<Received, String>,
+}
+
+#[cfg_attr(not(feature = "transcript"), allow(dead_code))]
+enum Field {
+    Byte(u8),
+    Word(u16),
+    Length(usize),
+}
+
+/// What a successful transfer gave back
+#[cfg_attr(not(feature = "transcript"), allow(dead_code))]
+enum Received {
+    Nothing,
+    Data(String),
+    Sent(usize),
+}
+
+#[cfg(feature = "transcript")]
+impl Entry {
+    fn text(&self) -> String {
+        let mut line = self.request();
+        match &self.result {
+            Ok(Received::Nothing) => line.push_str(" ok"),
+            Ok(Received::Data(data)) => line.push_str(&format!(" ok {}", data)),
+            Ok(Received::Sent(n)) => line.push_str(&format!(" ok {}", n)),
+            Err(e) => line.push_str(&format!(" error {}", e)),
+        }
+        line
+    }
+
+    /// The line up to the result: what was asked of the device
+    fn request(&self) -> String {
+        let mut line = self.transfer.to_owned();
+        for (name, field) in &self.fields {
+            let value = match field {
+                Field::Byte(value) => format!("{:#04x}", value),
+                Field::Word(value) => format!("{:#06x}", value),
+                Field::Length(value) => value.to_string(),
+            };
+            line.push_str(&format!(" {}={}", name, value));
+        }
+        if let Some(data) = &self.data {
+            line.push_str(&format!(" data={}", data));
+        }
+        line
+    }
+
+    fn json(&self) -> String {
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let direction = if self.transfer.ends_with("_in") {
+            "in"
+        } else {
+            "out"
+        };
+        let mut line = format!(
+            r#"{{"time":{}.{:06},"transfer":"{}","direction":"{}""#,
+            time.as_secs(),
+            time.subsec_micros(),
+            self.transfer,
+            direction
+        );
+        for (name, field) in &self.fields {
+            let value = match *field {
+                Field::Byte(value) => value as usize,
+                Field::Word(value) => value as usize,
+                Field::Length(value) => value,
+            };
+            line.push_str(&format!(r#","{}":{}"#, name, value));
+        }
+        if let Some(data) = &self.data {
+            line.push_str(&format!(r#","data":"{}""#, data));
+        }
+        // The error names are Debug output of enum variants, which need no
+        // escaping
+        match &self.result {
+            Ok(Received::Nothing) => line.push_str(r#","ok":true"#),
+            Ok(Received::Data(data)) => {
+                line.push_str(&format!(r#","ok":true,"received":"{}""#, data))
+            }
+            Ok(Received::Sent(n)) => line.push_str(&format!(r#","ok":true,"sent":{}"#, n)),
+            Err(e) => line.push_str(&format!(r#","ok":false,"error":"{}""#, e)),
+        }
+        line.push('}');
+        line
+    }
+}
+
+fn setup(request: u8, value: u16, index: u16) -> Vec<(&'static str, Field)> {
+    vec![
+        ("request", Field::Byte(request)),
+        ("value", Field::Word(value)),
+        ("index", Field::Word(index)),
+    ]
+}
+
+pub(crate) fn control_in(
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    result: &Result<Vec<u8>, TransferError>,
+) {
+    record(|| {
+        let mut fields = setup(request, value, index);
+        fields.push(("length", Field::Length(length as usize)));
+        Entry {
+            transfer: "control_in",
+            fields,
+            data: None,
+            result: match result {
+                Ok(data) => Ok(Received::Data(hex(data))),
+                Err(e) => Err(format!("{:?}", e)),
+            },
+        }
+    });
+}
+
+pub(crate) fn control_out(
+    request: u8,
+    value: u16,
+    index: u16,
+    data: &[u8],
+    result: &Result<(), TransferError>,
+) {
+    record(|| Entry {
+        transfer: "control_out",
+        fields: setup(request, value, index),
+        data: Some(hex(data)),
+        result: match result {
+            Ok(()) => Ok(Received::Nothing),
+            Err(e) => Err(format!("{:?}", e)),
+        },
+    });
+}
+
+pub(crate) fn bulk_in(endpoint: u8, buf: &[u8], result: &io::Result<usize>) {
+    record(|| Entry {
+        transfer: "bulk_in",
+        fields: vec![
+            ("endpoint", Field::Byte(endpoint)),
+            ("length", Field::Length(buf.len())),
+        ],
+        data: None,
+        result: match result {
+            Ok(n) => Ok(Received::Data(hex(&buf[..*n]))),
+            Err(e) => Err(format!("{:?}", e.kind())),
+        },
+    });
+}
+
+pub(crate) fn bulk_out(endpoint: u8, data: &[u8], result: &io::Result<usize>) {
+    record(|| Entry {
+        transfer: "bulk_out",
+        fields: vec![("endpoint", Field::Byte(endpoint))],
+        data: Some(hex(data)),
+        result: match result {
+            Ok(n) => Ok(Received::Sent(*n)),
+            Err(e) => Err(format!("{:?}", e.kind())),
+        },
+    });
+}
+
+/// A text transcript played back as the device that recorded it. Each
+/// transfer has to ask for exactly what the next line does, and gets back
+/// what the device gave then; asking for anything else panics, naming both.
+/// Clones share the lines left.
+#[cfg(all(test, feature = "transcript"))]
+#[derive(Clone)]
+pub(crate) struct Replay(std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<Line>>>);
+
+/// One line of a transcript, split at the result
+#[cfg(all(test, feature = "transcript"))]
+struct Line {
+    request: String,
+    /// The data or count after `ok`, or the kind of error after `error`
+    result: Result<Option<String>, String>,
+}
+
+#[cfg(all(test, feature = "transcript"))]
+impl Replay {
+    /// Play back `transcript`, in the text format. Blank lines are skipped.
+    pub(crate) fn parse(transcript: &str) -> Self {
+        let lines = transcript
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let words: Vec<&str> = line.split_whitespace().collect();
+                let at = words
+                    .iter()
+                    .position(|word| *word == "ok" || *word == "error")
+                    .unwrap_or_else(|| panic!("no result in transcript line {:?}", line));
+                let value = words.get(at + 1).map(|word| word.to_string());
+                Line {
+                    request: words[..at].join(" "),
+                    result: match words[at] {
+                        "ok" => Ok(value),
+                        _ => Err(value.unwrap_or_default()),
+                    },
+                }
+            })
+            .collect();
+        Replay(std::sync::Arc::new(std::sync::Mutex::new(lines)))
+    }
+
+    /// How many lines have not been played back yet
+    pub(crate) fn remaining(&self) -> usize {
+        self.0
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .len()
+    }
+
+    /// The result of the next line, which has to be `asked`
+    fn next(&self, asked: Entry) -> Result<Option<String>, String> {
+        let asked = asked.request();
+        let mut lines = self
+            .0
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let Some(line) = lines.pop_front() else {
+            drop(lines);
+            panic!("transcript ended, but the session went on with {:?}", asked);
+        };
+        if line.request != asked {
+            drop(lines);
+            panic!(
+                "the session asked for {:?} where the transcript has {:?}",
+                asked, line.request
+            );
+        }
+        line.result
+    }
+}
+
+#[cfg(all(test, feature = "transcript"))]
+impl crate::transport::Transport for Replay {
+    fn class_in(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, TransferError> {
+        let mut fields = setup(request, value, index);
+        fields.push(("length", Field::Length(length as usize)));
+        match self.next(asked("control_in", fields, None)) {
+            Ok(data) => Ok(unhex(data.as_deref().unwrap_or(""))),
+            Err(kind) => Err(transfer_error(&kind)),
+        }
+    }
+
+    fn class_out(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<(), TransferError> {
+        let fields = setup(request, value, index);
+        match self.next(asked("control_out", fields, Some(data))) {
+            Ok(_) => Ok(()),
+            Err(kind) => Err(transfer_error(&kind)),
+        }
+    }
+
+    fn read_bulk(
+        &self,
+        endpoint: u8,
+        buf: &mut [u8],
+        _timeout: std::time::Duration,
+    ) -> io::Result<usize> {
+        let fields = vec![
+            ("endpoint", Field::Byte(endpoint)),
+            ("length", Field::Length(buf.len())),
+        ];
+        match self.next(asked("bulk_in", fields, None)) {
+            Ok(data) => {
+                let data = unhex(data.as_deref().unwrap_or(""));
+                buf[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            }
+            Err(kind) => Err(io_error(&kind)),
+        }
+    }
+
+    fn write_bulk(
+        &self,
+        endpoint: u8,
+        buf: &[u8],
+        _timeout: std::time::Duration,
+    ) -> io::Result<usize> {
+        let fields = vec![("endpoint", Field::Byte(endpoint))];
+        match self.next(asked("bulk_out", fields, Some(buf))) {
+            Ok(sent) => Ok(sent.and_then(|n| n.parse().ok()).unwrap_or_default()),
+            Err(kind) => Err(io_error(&kind)),
+        }
+    }
+}
+
+/// A transfer as it is asked for, before it has a result
+#[cfg(all(test, feature = "transcript"))]
+fn asked(transfer: &'static str, fields: Vec<(&'static str, Field)>, data: Option<&[u8]>) -> Entry {
+    Entry {
+        transfer,
+        fields,
+        data: data.map(hex),
+        result: Ok(Received::Nothing),
+    }
+}
+
+#[cfg(all(test, feature = "transcript"))]
+fn unhex(data: &str) -> Vec<u8> {
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&data[i..i + 2], 16).expect("transcript data is hex"))
+        .collect()
+}
+
+/// The control transfer error recorded as `kind`
+#[cfg(all(test, feature = "transcript"))]
+fn transfer_error(kind: &str) -> TransferError {
+    match kind {
+        "Cancelled" => TransferError::Cancelled,
+        "Stall" => TransferError::Stall,
+        "Disconnected" => TransferError::Disconnected,
+        "Fault" => TransferError::Fault,
+        _ => TransferError::Unknown,
+    }
+}
+
+/// The bulk transfer error recorded as `kind`
+#[cfg(all(test, feature = "transcript"))]
+fn io_error(kind: &str) -> io::Error {
+    use io::ErrorKind::*;
+    [
+        TimedOut,
+        NotConnected,
+        BrokenPipe,
+        Interrupted,
+        InvalidData,
+        Unsupported,
+        UnexpectedEof,
+    ]
+    .into_iter()
+    .find(|known| format!("{:?}", known) == kind)
+    .unwrap_or(Other)
+    .into()
+}
+
+#[cfg(all(test, feature = "transcript"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::Xds110DfuDevice;
+    use crate::mock::MockBootloader;
+    use crate::transport::Transport;
+
+    /// Where the transcript goes, kept for the test to read back
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The mock, recorded through the hooks the nusb transports call
+    struct Recorded(MockBootloader);
+
+    impl Transport for Recorded {
+        fn class_in(
+            &self,
+            request: u8,
+            value: u16,
+            index: u16,
+            length: u16,
+        ) -> Result<Vec<u8>, TransferError> {
+            let result = self.0.class_in(request, value, index, length);
+            control_in(request, value, index, length, &result);
+            result
+        }
+
+        fn class_out(
+            &self,
+            request: u8,
+            value: u16,
+            index: u16,
+            data: &[u8],
+        ) -> Result<(), TransferError> {
+            let result = self.0.class_out(request, value, index, data);
+            control_out(request, value, index, data, &result);
+            result
+        }
+
+        fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+            let result = self.0.read_bulk(endpoint, buf, timeout);
+            bulk_in(endpoint, buf, &result);
+            result
+        }
+
+        fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+            let result = self.0.write_bulk(endpoint, buf, timeout);
+            bulk_out(endpoint, buf, &result);
+            result
+        }
+    }
+
+    /// Read the configuration, then again after `unplug`, and what each
+    /// read came to
+    fn session(
+        transport: Box<dyn Transport>,
+        mock: &MockBootloader,
+        unplug: impl FnOnce(),
+    ) -> Vec<String> {
+        let mut dfu = Xds110DfuDevice::with_transport(transport, mock.interface());
+        dfu.recover().unwrap();
+        let first = format!("{:?}", dfu.read_configuration());
+        unplug();
+        let second = format!("{:?}", dfu.read_configuration());
+        vec![first, second]
+    }
+
+    #[test]
+    fn recorded_session_replays() {
+        let mock = MockBootloader::new();
+        let transcript = Shared::default();
+        record_transcript(transcript.clone());
+        let recorded = session(Box::new(Recorded(mock.clone())), &mock, || {
+            mock.lock().unplugged = true
+        });
+        stop_transcript();
+        assert!(recorded[0].starts_with("Ok("), "{}", recorded[0]);
+        assert!(recorded[1].starts_with("Err("), "{}", recorded[1]);
+
+        let text = String::from_utf8(transcript.0.lock().unwrap().clone()).unwrap();
+        let lines = text.lines().count();
+        assert!(
+            text.lines()
+                .any(|line| line.ends_with("error Disconnected"))
+        );
+        let replay = Replay::parse(&text);
+        let replayed = session(Box::new(replay.clone()), &mock, || {});
+        assert_eq!(replayed, recorded);
+        assert_eq!(replay.remaining(), 0, "{} lines", lines);
+    }
+
+    #[test]
+    #[should_panic(expected = "where the transcript has")]
+    fn diverging_session_is_caught() {
+        let replay = Replay::parse(
+            "control_in request=0x03 value=0x0000 index=0x0000 length=6 ok 000000020000\n",
+        );
+        let _ = replay.class_in(0x03, 0, 1, 6);
+    }
+
+    #[test]
+    fn results_are_played_back() {
+        let replay = Replay::parse(
+            "bulk_out endpoint=0x02 data=2a010003 ok 4\n\
+             bulk_in endpoint=0x83 length=64 ok 0102\n\
+             bulk_in endpoint=0x83 length=64 error TimedOut\n",
+        );
+        let timeout = Duration::from_secs(1);
+        assert_eq!(
+            replay
+                .write_bulk(0x02, &[0x2a, 0x01, 0x00, 0x03], timeout)
+                .unwrap(),
+            4
+        );
+        let mut buf = [0; 64];
+        assert_eq!(replay.read_bulk(0x83, &mut buf, timeout).unwrap(), 2);
+        assert_eq!(buf[..2], [0x01, 0x02]);
+        let error = replay.read_bulk(0x83, &mut buf, timeout).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(replay.remaining(), 0);
+    }
+}
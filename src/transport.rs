@@ -0,0 +1,174 @@
+//! The USB transfers the probe handles are built on.
+//!
+//! [`Xds110UsbDevice`](crate::Xds110UsbDevice) and
+//! [`Xds110DfuDevice`](crate::Xds110DfuDevice) only ever issue class requests
+//! to an interface and bulk transfers, so that is all [`Transport`] asks for.
+//! It is implemented for nusb's devices and interfaces; the tests put a
+//! scripted probe in their place.
+
+use std::io;
+use std::time::Duration;
+
+use nusb::transfer::{ControlIn, ControlOut, ControlType, Recipient, TransferError};
+
+use crate::runtime::block_on;
+use crate::{transcript, usb_util};
+
+/// How long to wait for a string descriptor.
+const STRING_DESCRIPTOR_TIMEOUT: Duration = Duration::from_secs(1);
+/// The language ID to ask for when the device does not list any.
+const US_ENGLISH: u16 = 0x0409;
+
+/// The transfers a probe handle issues
+pub trait Transport: Send + Sync {
+    /// Issue a class request to an interface and return what it sent back
+    fn class_in(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, TransferError>;
+
+    /// Issue a class request to an interface that sends `data`
+    fn class_out(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<(), TransferError>;
+
+    /// Read up to `buf.len()` bytes from the bulk IN `endpoint`
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize>;
+
+    /// Send all of `buf` to the bulk OUT `endpoint`
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> io::Result<usize>;
+
+    /// The string descriptor `index` in the device's first language
+    fn string_descriptor(&self, index: u8) -> io::Result<String> {
+        let _ = index;
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Reset the port the device is plugged into, so it re-enumerates
+    fn reset_port(&self) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// A whole device, which the DFU bootloader is driven through. It has no
+/// bulk endpoints to speak of.
+impl Transport for nusb::Device {
+    fn class_in(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, TransferError> {
+        let result = block_on(self.control_in(class_in_request(request, value, index, length)))
+            .into_result();
+        transcript::control_in(request, value, index, length, &result);
+        result
+    }
+
+    fn class_out(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<(), TransferError> {
+        let result = block_on(self.control_out(class_out_request(request, value, index, data)))
+            .into_result()
+            .map(drop);
+        transcript::control_out(request, value, index, data, &result);
+        result
+    }
+
+    fn read_bulk(&self, _endpoint: u8, _buf: &mut [u8], _timeout: Duration) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn write_bulk(&self, _endpoint: u8, _buf: &[u8], _timeout: Duration) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn string_descriptor(&self, index: u8) -> io::Result<String> {
+        let language = self
+            .get_string_descriptor_supported_languages(STRING_DESCRIPTOR_TIMEOUT)?
+            .next()
+            .unwrap_or(US_ENGLISH);
+        self.get_string_descriptor(index, language, STRING_DESCRIPTOR_TIMEOUT)
+    }
+
+    fn reset_port(&self) -> io::Result<()> {
+        self.reset()
+    }
+}
+
+/// A claimed interface, which the normal firmware and CMSIS-DAP are driven
+/// through
+impl Transport for nusb::Interface {
+    fn class_in(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, TransferError> {
+        let result = block_on(self.control_in(class_in_request(request, value, index, length)))
+            .into_result();
+        transcript::control_in(request, value, index, length, &result);
+        result
+    }
+
+    fn class_out(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<(), TransferError> {
+        let result = block_on(self.control_out(class_out_request(request, value, index, data)))
+            .into_result()
+            .map(drop);
+        transcript::control_out(request, value, index, data, &result);
+        result
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let result = usb_util::read_bulk(&mut self.bulk_in_queue(endpoint), buf, timeout);
+        transcript::bulk_in(endpoint, buf, &result);
+        result
+    }
+
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        let result = usb_util::write_bulk(self, endpoint, buf, timeout);
+        transcript::bulk_out(endpoint, buf, &result);
+        result
+    }
+}
+
+fn class_in_request(request: u8, value: u16, index: u16, length: u16) -> ControlIn {
+    ControlIn {
+        control_type: ControlType::Class,
+        recipient: Recipient::Interface,
+        request,
+        value,
+        index,
+        length,
+    }
+}
+
+fn class_out_request(request: u8, value: u16, index: u16, data: &[u8]) -> ControlOut<'_> {
+    ControlOut {
+        control_type: ControlType::Class,
+        recipient: Recipient::Interface,
+        request,
+        value,
+        index,
+        data,
+    }
+}
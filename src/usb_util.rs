@@ -1,70 +1,327 @@
-use async_io::{Timer, block_on};
-use futures_lite::FutureExt;
-use nusb::{Interface, transfer::RequestBuffer};
-use std::{io, time::Duration};
+use nusb::Interface;
+use nusb::descriptors::Endpoint;
+use nusb::transfer::{Completion, Direction, EndpointType, Queue, RequestBuffer, TransferError};
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use crate::runtime::{block_on, block_on_timeout};
 
 const MAX_PACKET_LENGTH: usize = 64;
 
-pub trait InterfaceExt {
-    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize>;
-    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> io::Result<usize>;
+/// A failed completion as an `io::Error`. An unplugged device shows up as
+/// `NotConnected`, so that it can be told apart from a timeout and is not
+/// retried.
+fn transfer_error(error: TransferError) -> io::Error {
+    match error {
+        TransferError::Disconnected => io::Error::new(io::ErrorKind::NotConnected, error),
+        error => io::Error::other(error),
+    }
+}
+
+/// Cancel every pending transfer and wait for the cancellations to land, so
+/// none of them can complete with data meant for a later transfer
+macro_rules! abandon {
+    ($queue:expr) => {
+        $queue.cancel_all();
+        while $queue.pending() > 0 {
+            let _ = block_on($queue.next_complete());
+        }
+    };
+}
+
+/// What [`read_bulk`] needs of nusb's `Queue<RequestBuffer>`, so that the
+/// tests can stand in for an endpoint
+pub(crate) trait InQueue {
+    fn submit(&mut self, buffer: RequestBuffer);
+    fn next_complete(&mut self) -> impl Future<Output = Completion<Vec<u8>>> + '_;
+    fn pending(&self) -> usize;
+    fn cancel_all(&mut self);
+}
+
+impl InQueue for Queue<RequestBuffer> {
+    fn submit(&mut self, buffer: RequestBuffer) {
+        Queue::submit(self, buffer);
+    }
+
+    fn next_complete(&mut self) -> impl Future<Output = Completion<Vec<u8>>> + '_ {
+        Queue::next_complete(self)
+    }
+
+    fn pending(&self) -> usize {
+        Queue::pending(self)
+    }
+
+    fn cancel_all(&mut self) {
+        Queue::cancel_all(self);
+    }
 }
 
-impl InterfaceExt for Interface {
-    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> io::Result<usize> {
-        let fut = async {
-            let comp = self.bulk_out(endpoint, buf.to_vec()).await;
-            comp.status.map_err(io::Error::other)?;
+/// Keeps resubmitting the rest of `buf` after a short write, until all
+/// of it is sent or `timeout` runs out
+pub(crate) fn write_bulk(
+    interface: &Interface,
+    endpoint: u8,
+    buf: &[u8],
+    timeout: Duration,
+) -> io::Result<usize> {
+    let deadline = Instant::now() + timeout;
+    let mut queue = interface.bulk_out_queue(endpoint);
+    let mut sent = 0;
+    loop {
+        queue.submit(buf[sent..].to_vec());
 
-            let n = comp.data.actual_length();
-            Ok(n)
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let comp = block_on_timeout(queue.next_complete(), remaining);
+        let Some(comp) = comp else {
+            abandon!(queue);
+            return Err(io::ErrorKind::TimedOut.into());
         };
+        comp.status.map_err(transfer_error)?;
+
+        sent += comp.data.actual_length();
+        if sent >= buf.len() {
+            return Ok(sent);
+        }
+    }
+}
+
+/// Read into `buf` through `queue`, in packet-sized transfers for a large
+/// `buf`, until a short packet or `buf` is full
+pub(crate) fn read_bulk(
+    queue: &mut impl InQueue,
+    buf: &mut [u8],
+    timeout: Duration,
+) -> io::Result<usize> {
+    let (transfer_size, n_transfers) = if buf.len() > MAX_PACKET_LENGTH {
+        // Add one byte for the ZLP
+        (MAX_PACKET_LENGTH, (buf.len() + 1) / MAX_PACKET_LENGTH)
+    } else {
+        (buf.len(), 1)
+    };
+    while queue.pending() < n_transfers {
+        queue.submit(RequestBuffer::new(transfer_size));
+    }
 
-        block_on(fut.or(async {
-            Timer::after(timeout).await;
-            Err(std::io::ErrorKind::TimedOut.into())
-        }))
-    }
-
-    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
-        let fut = async {
-            if buf.len() > 64 {
-                let mut queue = self.bulk_in_queue(endpoint);
-                // Add one byte for the ZLP
-                let transfer_size = MAX_PACKET_LENGTH;
-                let n_transfers = (buf.len() + 1) / transfer_size;
-                while queue.pending() < n_transfers {
-                    queue.submit(RequestBuffer::new(transfer_size));
-                }
-
-                let mut offset = 0;
-                loop {
-                    let comp = queue.next_complete().await;
-                    comp.status.map_err(io::Error::other)?;
-
-                    let n = comp.data.len();
-                    buf[offset..offset + n].copy_from_slice(&comp.data);
-                    offset += n;
-                    // Finish on ZLP, a non-full buffer, or if we've filled the buffer
-                    if n == 0 || n != transfer_size || offset == buf.len() {
-                        break;
-                    }
-                    queue.submit(RequestBuffer::reuse(comp.data, transfer_size));
-                }
-                Ok(offset)
-            } else {
-                let comp = self.bulk_in(endpoint, RequestBuffer::new(buf.len())).await;
-                comp.status.map_err(io::Error::other)?;
-
-                let n = comp.data.len();
-                buf[..n].copy_from_slice(&comp.data);
-                Ok(n)
+    let fut = async {
+        let mut offset = 0;
+        loop {
+            let comp = queue.next_complete().await;
+            comp.status.map_err(transfer_error)?;
+
+            let n = comp.data.len();
+            buf[offset..offset + n].copy_from_slice(&comp.data);
+            offset += n;
+            // Finish on ZLP, a non-full buffer, or if we've filled the buffer
+            if n == 0 || n != transfer_size || offset == buf.len() {
+                break;
             }
+            queue.submit(RequestBuffer::reuse(comp.data, transfer_size));
+        }
+        Ok(offset)
+    };
+
+    let result = block_on_timeout(fut, timeout);
+    // Transfers still in flight would swallow the start of the next response
+    abandon!(queue);
+    result.unwrap_or(Err(io::ErrorKind::TimedOut.into()))
+}
+
+/// The hub ports leading from the root hub to `device`, outermost first.
+/// Returns `None` on platforms where this cannot be determined.
+pub fn port_chain(device: &nusb::DeviceInfo) -> Option<Vec<u8>> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        // Sysfs names devices "<bus>-<port>.<port>..."
+        let name = device.sysfs_path().file_name()?.to_str()?;
+        let (_bus, ports) = name.split_once('-')?;
+        ports.split('.').map(|port| port.parse().ok()).collect()
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        // The location ID holds the bus in its top byte, followed by one
+        // nibble per port until a zero nibble
+        let location = device.location_id();
+        Some(
+            (0..6)
+                .map(|i| ((location >> (20 - 4 * i)) & 0xf) as u8)
+                .take_while(|&port| port != 0)
+                .collect(),
+        )
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+    {
+        let _ = device;
+        None
+    }
+}
+
+/// The bus and [`port_chain`] of `device`
+pub fn port_path(device: &nusb::DeviceInfo) -> Option<(u8, Vec<u8>)> {
+    Some((device.bus_number(), port_chain(device)?))
+}
+
+pub fn is_at_path(device: &nusb::DeviceInfo, bus: u8, ports: &[u8]) -> bool {
+    device.bus_number() == bus && port_chain(device).is_some_and(|chain| chain == ports)
+}
+
+/// Fail with a description of the mismatch unless `endpoint` is a bulk
+/// endpoint in `direction`, as a changed descriptor would otherwise only
+/// show up as transfers failing
+pub fn check_bulk_endpoint(endpoint: &Endpoint, direction: Direction) -> io::Result<()> {
+    if endpoint.transfer_type() == EndpointType::Bulk && endpoint.direction() == direction {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "endpoint {:#04x} is {:?} {:?}, expected Bulk {:?}",
+            endpoint.address(),
+            endpoint.transfer_type(),
+            endpoint.direction(),
+            direction
+        ),
+    ))
+}
+
+/// Detach whatever kernel driver is bound to `interface`, such as hid or
+/// cdc_acm on Linux, so it can be claimed without udev rules. Having no
+/// driver bound is fine. Does nothing on other platforms.
+pub fn detach_kernel_driver_if_needed(device: &nusb::Device, interface: u8) -> io::Result<()> {
+    // The kernel reports ENODATA when no driver is bound
+    #[cfg(target_os = "linux")]
+    const ENODATA: i32 = 61;
+
+    match device.detach_kernel_driver(interface) {
+        #[cfg(target_os = "linux")]
+        Err(e) if e.raw_os_error() == Some(ENODATA) => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// An IN endpoint. Packets the device has ready, or the errors to
+    /// fail transfers with, go to transfers in the order they were
+    /// submitted.
+    #[derive(Default)]
+    struct Endpoint {
+        ready: VecDeque<Result<Vec<u8>, TransferError>>,
+        /// Transfers submitted and still waiting for a packet
+        waiting: usize,
+        /// Transfers that finished but were not collected yet
+        done: VecDeque<Completion<Vec<u8>>>,
+        /// A packet that lands in the first waiting transfer just as it is
+        /// cancelled, which then completes normally
+        late: Option<Vec<u8>>,
+    }
+
+    impl Endpoint {
+        fn complete_ready(&mut self) {
+            while self.waiting > 0
+                && let Some(packet) = self.ready.pop_front()
+            {
+                self.waiting -= 1;
+                self.done.push_back(match packet {
+                    Ok(data) => Completion {
+                        data,
+                        status: Ok(()),
+                    },
+                    Err(error) => Completion {
+                        data: vec![],
+                        status: Err(error),
+                    },
+                });
+            }
+        }
+    }
+
+    impl InQueue for Endpoint {
+        fn submit(&mut self, _buffer: RequestBuffer) {
+            self.waiting += 1;
+            self.complete_ready();
+        }
+
+        async fn next_complete(&mut self) -> Completion<Vec<u8>> {
+            match self.done.pop_front() {
+                Some(completion) => completion,
+                None => std::future::pending().await,
+            }
+        }
+
+        fn pending(&self) -> usize {
+            self.waiting + self.done.len()
+        }
+
+        fn cancel_all(&mut self) {
+            if self.waiting > 0
+                && let Some(data) = self.late.take()
+            {
+                self.waiting -= 1;
+                self.done.push_back(Completion {
+                    data,
+                    status: Ok(()),
+                });
+            }
+            for _ in 0..std::mem::take(&mut self.waiting) {
+                self.done.push_back(Completion {
+                    data: vec![],
+                    status: Err(TransferError::Cancelled),
+                });
+            }
+        }
+    }
+
+    const TIMEOUT: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn read_after_a_timeout_gets_fresh_data() {
+        let mut endpoint = Endpoint {
+            late: Some(b"stale".to_vec()),
+            ..Default::default()
         };
+        let mut buf = [0; 8];
+        let timed_out = read_bulk(&mut endpoint, &mut buf, TIMEOUT).unwrap_err();
+        assert_eq!(timed_out.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(endpoint.pending(), 0);
+
+        endpoint.ready.push_back(Ok(b"fresh".to_vec()));
+        let n = read_bulk(&mut endpoint, &mut buf, TIMEOUT).unwrap();
+        assert_eq!(&buf[..n], b"fresh");
+    }
+
+    #[test]
+    fn read_spans_packets_until_a_short_one() {
+        let mut endpoint = Endpoint::default();
+        endpoint
+            .ready
+            .extend([Ok(vec![1; MAX_PACKET_LENGTH]), Ok(vec![2; 10])]);
+        let mut buf = [0; 100];
+        let n = read_bulk(&mut endpoint, &mut buf, TIMEOUT).unwrap();
+        assert_eq!(n, MAX_PACKET_LENGTH + 10);
+        assert_eq!(buf[MAX_PACKET_LENGTH], 2);
+        assert_eq!(endpoint.pending(), 0);
+    }
+
+    #[test]
+    fn disconnect_is_not_a_timeout() {
+        let mut endpoint = Endpoint::default();
+        endpoint.ready.push_back(Err(TransferError::Disconnected));
+        let mut buf = [0; 8];
+        let error = read_bulk(&mut endpoint, &mut buf, TIMEOUT).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotConnected);
 
-        block_on(fut.or(async {
-            Timer::after(timeout).await;
-            Err(std::io::ErrorKind::TimedOut.into())
-        }))
+        // Other failures stay what they were
+        endpoint.ready.push_back(Err(TransferError::Stall));
+        let error = read_bulk(&mut endpoint, &mut buf, TIMEOUT).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+        assert_eq!(endpoint.pending(), 0);
     }
 }
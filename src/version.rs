@@ -0,0 +1,74 @@
+use std::fmt;
+
+/// An XDS110 firmware version, displayed as `a.b.c.d` with each component
+/// in hex, the way TI writes them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FirmwareVersion(pub u32);
+
+impl FirmwareVersion {
+    pub fn components(self) -> [u8; 4] {
+        self.0.to_be_bytes()
+    }
+}
+
+impl From<u32> for FirmwareVersion {
+    fn from(value: u32) -> Self {
+        FirmwareVersion(value)
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.components();
+        write!(f, "{:02x}.{:02x}.{:02x}.{:02x}", a, b, c, d)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for FirmwareVersion {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Why [`check_minimum_firmware`](crate::Xds110UsbDevice::check_minimum_firmware)
+/// failed
+#[derive(Debug)]
+pub enum FirmwareVersionError {
+    /// The firmware answered but is older than `minimum`
+    TooOld {
+        found: FirmwareVersion,
+        minimum: FirmwareVersion,
+    },
+
+    /// Asking the probe for its firmware version failed
+    Io(std::io::Error),
+}
+
+impl fmt::Display for FirmwareVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FirmwareVersionError::TooOld { found, minimum } => write!(
+                f,
+                "firmware version {} is older than the required {}",
+                found, minimum
+            ),
+            FirmwareVersionError::Io(e) => write!(f, "reading the firmware version failed: {}", e),
+        }
+    }
+}
+
+impl core::error::Error for FirmwareVersionError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            FirmwareVersionError::Io(e) => Some(e),
+            FirmwareVersionError::TooOld { .. } => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for FirmwareVersionError {
+    fn from(value: std::io::Error) -> Self {
+        FirmwareVersionError::Io(value)
+    }
+}